@@ -0,0 +1,812 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{Context, anyhow};
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use rcgen::{
+    CertificateParams, CustomExtension, DistinguishedName, KeyPair, PKCS_ECDSA_P256_SHA256,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair as _},
+};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use x509_parser::extensions::GeneralName;
+
+use crate::config::{Acme, AcmeChallenge};
+
+pub const CERT_FILE: &str = "cert.pem";
+pub const KEY_FILE: &str = "key.pem";
+const ACCOUNT_KEY_FILE: &str = "acme_account.pem";
+
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+/// The key authorizations of the pending HTTP-01 challenges by token.
+pub type HttpChallenges = Arc<RwLock<HashMap<String, String>>>;
+
+/// The certificate of the https listener, replaced on renewal without a restart,
+/// and the certificates answering the TLS-ALPN-01 challenges.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    cert: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    /// Serves a PEM certificate chain and private key.
+    pub fn set_cert(&self, cert_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<()> {
+        *self.cert.write().unwrap() = Some(Arc::new(served_key(cert_pem, key_pem)?));
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let challenge = hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        if challenge {
+            let name = hello.server_name()?;
+            return self.challenges.read().unwrap().get(name).cloned();
+        }
+        self.cert.read().unwrap().clone()
+    }
+}
+
+/// The TLS settings of the https listener, the certificates are the ones of the
+/// resolver.
+pub fn server_config(resolver: Arc<CertResolver>) -> Arc<rustls::ServerConfig> {
+    let mut config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    Arc::new(config)
+}
+
+/// Answers the HTTP-01 challenges at `/.well-known/acme-challenge/{token}`.
+pub async fn http_challenge(
+    State(challenges): State<HttpChallenges>,
+    UrlPath(token): UrlPath<String>,
+) -> Response {
+    match challenges.read().unwrap().get(&token) {
+        Some(key_authorization) => key_authorization.clone().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Orders the certificate of the domains when missing, issued for other domains
+/// or about to expire, and stores it as `cert.pem` and `key.pem` in `tls_dir`.
+pub struct CertManager {
+    settings: Acme,
+    tls_dir: PathBuf,
+    resolver: Arc<CertResolver>,
+    http_challenges: HttpChallenges,
+}
+
+impl CertManager {
+    pub fn new(
+        settings: Acme,
+        tls_dir: PathBuf,
+        resolver: Arc<CertResolver>,
+        http_challenges: HttpChallenges,
+    ) -> Self {
+        CertManager {
+            settings,
+            tls_dir,
+            resolver,
+            http_challenges,
+        }
+    }
+
+    pub async fn run(self) {
+        // the stored certificate is served while the new one is ordered
+        let cert_path = self.tls_dir.join(CERT_FILE);
+        if let (Ok(cert), Ok(key)) = (fs::read(&cert_path), fs::read(self.tls_dir.join(KEY_FILE)))
+            && let Err(e) = self.resolver.set_cert(&cert, &key)
+        {
+            tracing::warn!(
+                "invalid stored certificate {}: {:#}",
+                cert_path.display(),
+                e
+            );
+        }
+        if !self.settings.accept_terms {
+            tracing::error!(
+                "the ACME certificate is not ordered without accept_terms = true in [acme], \
+                 the terms of service of {} must be accepted",
+                self.settings.directory_url
+            );
+            return;
+        }
+        loop {
+            let wait = match self.renew().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(e) => {
+                    tracing::error!(
+                        "failed to obtain the ACME certificate of {}: {:#}",
+                        self.settings.domains.join(", "),
+                        e
+                    );
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    async fn renew(&self) -> anyhow::Result<()> {
+        let stored = stored_cert(&self.tls_dir);
+        let now = chrono::Utc::now().timestamp();
+        if !needs_order(
+            stored.as_ref(),
+            &self.settings.domains,
+            now,
+            self.settings.renew_days_before_expiry,
+        ) {
+            return Ok(());
+        }
+
+        tracing::info!(
+            "ordering the ACME certificate of {} from {}",
+            self.settings.domains.join(", "),
+            self.settings.directory_url
+        );
+        fs::create_dir_all(&self.tls_dir)?;
+        let mut client = AcmeClient::connect(&self.settings, &self.tls_dir).await?;
+        let (cert_pem, key_pem) = client.order(self).await?;
+        self.resolver
+            .set_cert(cert_pem.as_bytes(), key_pem.as_bytes())?;
+        store_cert(&self.tls_dir, &cert_pem, &key_pem)?;
+
+        let (not_after, _) = cert_info(cert_pem.as_bytes())?;
+        tracing::info!(
+            "obtained the ACME certificate of {}, valid until {}",
+            self.settings.domains.join(", "),
+            chrono::DateTime::from_timestamp(not_after, 0).unwrap_or_default()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// An ACME account, its requests are signed with ES256.
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    jwk: Value,
+    kid: String,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Registers the account of the key stored in `tls_dir`, or of a new key.
+    async fn connect(settings: &Acme, tls_dir: &Path) -> anyhow::Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(concat!("dataingester/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(30));
+        if let Some(ca_path) = &settings.ca_path {
+            let pem = fs::read(ca_path)
+                .with_context(|| format!("failed to read {}", ca_path.display()))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        let http = builder.build()?;
+        let directory = http
+            .get(&settings.directory_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Directory>()
+            .await
+            .with_context(|| format!("invalid ACME directory {}", settings.directory_url))?;
+
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &account_key(&tls_dir.join(ACCOUNT_KEY_FILE))?.serialize_der(),
+            &rng,
+        )
+        .map_err(|e| anyhow!("invalid ACME account key: {}", e))?;
+        let jwk = jwk(key.public_key().as_ref());
+
+        let mut client = AcmeClient {
+            http,
+            directory,
+            key,
+            rng,
+            jwk,
+            kid: String::new(),
+            nonce: None,
+        };
+        let contact: Vec<String> = settings
+            .contacts
+            .iter()
+            .map(|c| match c.starts_with("mailto:") {
+                true => c.clone(),
+                false => format!("mailto:{}", c),
+            })
+            .collect();
+        let new_account = client.directory.new_account.clone();
+        let resp = client
+            .post(
+                &new_account,
+                Some(&json!({
+                    "termsOfServiceAgreed": settings.accept_terms,
+                    "contact": contact,
+                })),
+            )
+            .await?;
+        client.kid = location(&resp)?;
+        Ok(client)
+    }
+
+    /// Completes the challenges of a new order and downloads the certificate,
+    /// returns it with its private key.
+    async fn order(&mut self, manager: &CertManager) -> anyhow::Result<(String, String)> {
+        let identifiers: Vec<Value> = manager
+            .settings
+            .domains
+            .iter()
+            .map(|d| json!({"type": "dns", "value": d}))
+            .collect();
+        let new_order = self.directory.new_order.clone();
+        let resp = self
+            .post(&new_order, Some(&json!({"identifiers": identifiers})))
+            .await?;
+        let order_url = location(&resp)?;
+        let order: Value = resp.json().await?;
+
+        for authz_url in order["authorizations"].as_array().into_iter().flatten() {
+            let authz_url = authz_url.as_str().unwrap_or_default();
+            self.authorize(manager, authz_url).await?;
+        }
+
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+        let mut params = CertificateParams::new(manager.settings.domains.clone())?;
+        params.distinguished_name = DistinguishedName::new();
+        let csr = params.serialize_request(&key)?;
+        let finalize = order["finalize"].as_str().unwrap_or_default().to_owned();
+        self.post(&finalize, Some(&json!({"csr": b64(csr.der())})))
+            .await?;
+
+        let order = self.poll(&order_url, "order").await?;
+        let cert_url = order["certificate"]
+            .as_str()
+            .ok_or(anyhow!("the order {} has no certificate", order_url))?;
+        let cert_pem = self.post(cert_url, None).await?.text().await?;
+        Ok((cert_pem, key.serialize_pem()))
+    }
+
+    async fn authorize(&mut self, manager: &CertManager, authz_url: &str) -> anyhow::Result<()> {
+        let authz: Value = self.post(authz_url, None).await?.json().await?;
+        if authz["status"] == "valid" {
+            return Ok(());
+        }
+        let domain = authz["identifier"]["value"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        let kind = match manager.settings.challenge {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let challenge = authz["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|c| c["type"] == kind)
+            .ok_or(anyhow!("no {} challenge offered for {}", kind, domain))?;
+        let token = challenge["token"].as_str().unwrap_or_default().to_owned();
+        let challenge_url = challenge["url"].as_str().unwrap_or_default().to_owned();
+        let key_authorization = format!("{}.{}", token, thumbprint(&self.jwk));
+
+        match manager.settings.challenge {
+            AcmeChallenge::Http01 => {
+                manager
+                    .http_challenges
+                    .write()
+                    .unwrap()
+                    .insert(token.clone(), key_authorization);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let cert = alpn_challenge_cert(&domain, &key_authorization)?;
+                manager
+                    .resolver
+                    .challenges
+                    .write()
+                    .unwrap()
+                    .insert(domain.clone(), Arc::new(cert));
+            }
+        }
+
+        let result = match self.post(&challenge_url, Some(&json!({}))).await {
+            Ok(_) => self.poll(authz_url, "authorization").await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+
+        manager.http_challenges.write().unwrap().remove(&token);
+        manager.resolver.challenges.write().unwrap().remove(&domain);
+        result
+    }
+
+    /// Fetches the resource until it is valid.
+    async fn poll(&mut self, url: &str, what: &str) -> anyhow::Result<Value> {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: Value = self.post(url, None).await?.json().await?;
+            match resource["status"].as_str() {
+                Some("valid") => return Ok(resource),
+                Some("invalid") => {
+                    return Err(anyhow!("the {} {} is invalid: {}", what, url, resource));
+                }
+                _ => tokio::time::sleep(POLL_INTERVAL).await,
+            }
+        }
+        Err(anyhow!("the {} {} is still pending", what, url))
+    }
+
+    /// Posts a signed request, without payload a POST-as-GET. A rejected nonce is
+    /// retried once with a fresh one.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let resp = self
+                .http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/jose+json")
+                .body(self.jws(url, &nonce, payload)?.to_string())
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&resp);
+            if resp.status().is_success() {
+                return Ok(resp);
+            }
+            let status = resp.status();
+            let problem: Value = resp.json().await.unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(anyhow!(
+                "{} {}: {}",
+                url,
+                status,
+                problem["detail"].as_str().unwrap_or_default()
+            ));
+        }
+    }
+
+    async fn new_nonce(&self) -> anyhow::Result<String> {
+        let resp = self.http.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&resp).ok_or(anyhow!("no nonce from {}", self.directory.new_nonce))
+    }
+
+    fn jws(&self, url: &str, nonce: &str, payload: Option<&Value>) -> anyhow::Result<Value> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match self.kid.is_empty() {
+            true => protected["jwk"] = self.jwk.clone(),
+            false => protected["kid"] = json!(self.kid),
+        }
+        let protected = b64(protected.to_string());
+        let payload = payload.map(|p| b64(p.to_string())).unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow!("failed to sign the ACME request"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature),
+        }))
+    }
+}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+fn replay_nonce(resp: &reqwest::Response) -> Option<String> {
+    resp.headers()
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+}
+
+fn location(resp: &reqwest::Response) -> anyhow::Result<String> {
+    resp.headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+        .ok_or(anyhow!("no location in the response of {}", resp.url()))
+}
+
+/// Writes a file readable only by the owner, through a temporary file renamed
+/// over it so that it is never seen partially written.
+fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    let tmp = path.with_extension("pem.tmp");
+    let written = (|| {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written.with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Stores the certificate and its key, the key first. A stop between the two
+/// leaves a key not matching the certificate, which [stored_cert] takes for a
+/// missing certificate.
+fn store_cert(tls_dir: &Path, cert_pem: &str, key_pem: &str) -> anyhow::Result<()> {
+    write_private(&tls_dir.join(KEY_FILE), key_pem)?;
+    write_private(&tls_dir.join(CERT_FILE), cert_pem)
+}
+
+/// The expiry and DNS names of the stored certificate, when it is present and
+/// matches the stored key.
+fn stored_cert(tls_dir: &Path) -> Option<(i64, Vec<String>)> {
+    let cert = fs::read(tls_dir.join(CERT_FILE)).ok()?;
+    let key = fs::read(tls_dir.join(KEY_FILE)).ok()?;
+    if let Err(e) = served_key(&cert, &key) {
+        tracing::warn!(
+            "invalid stored certificate in {}: {:#}",
+            tls_dir.display(),
+            e
+        );
+        return None;
+    }
+    cert_info(&cert).ok()
+}
+
+/// The account key stored in the file, a new P-256 key when missing.
+fn account_key(path: &Path) -> anyhow::Result<KeyPair> {
+    if let Ok(pem) = fs::read_to_string(path) {
+        return KeyPair::from_pem(&pem)
+            .with_context(|| format!("invalid ACME account key {}", path.display()));
+    }
+    let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+    write_private(path, &key.serialize_pem())?;
+    tracing::info!("created the ACME account key {}", path.display());
+    Ok(key)
+}
+
+/// The JWK of a P-256 public key, an uncompressed point.
+fn jwk(public_key: &[u8]) -> Value {
+    json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": b64(&public_key[1..33]),
+        "y": b64(&public_key[33..65]),
+    })
+}
+
+/// The RFC 7638 thumbprint, the required members in lexicographic order.
+fn thumbprint(jwk: &Value) -> String {
+    let canonical = format!(
+        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+        jwk["x"].as_str().unwrap_or_default(),
+        jwk["y"].as_str().unwrap_or_default()
+    );
+    b64(Sha256::digest(canonical))
+}
+
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_slice_iter(cert_pem).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificate in the PEM chain"));
+    }
+    let key =
+        rustls::crypto::ring::sign::any_supported_type(&PrivateKeyDer::from_pem_slice(key_pem)?)?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// The certificate of the listener, its key checked against the certificate.
+/// The challenge certificates are not checked, the parser of the check rejects
+/// their critical acmeIdentifier extension.
+fn served_key(cert_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<CertifiedKey> {
+    let certified = certified_key(cert_pem, key_pem)?;
+    certified.keys_match()?;
+    Ok(certified)
+}
+
+/// The self signed certificate of a TLS-ALPN-01 challenge, RFC 8737.
+fn alpn_challenge_cert(domain: &str, key_authorization: &str) -> anyhow::Result<CertifiedKey> {
+    let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+    let mut params = CertificateParams::new(vec![domain.to_owned()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(&Sha256::digest(
+        key_authorization,
+    ))];
+    let cert = params.self_signed(&key)?;
+    certified_key(cert.pem().as_bytes(), key.serialize_pem().as_bytes())
+}
+
+/// The expiry as a unix timestamp and the DNS names of the first certificate of
+/// a PEM chain.
+fn cert_info(pem: &[u8]) -> anyhow::Result<(i64, Vec<String>)> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem)
+        .map_err(|e| anyhow!("invalid PEM certificate: {}", e))?;
+    let cert = pem
+        .parse_x509()
+        .map_err(|e| anyhow!("invalid certificate: {}", e))?;
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    };
+    Ok((cert.validity().not_after.timestamp(), names))
+}
+
+/// Whether the stored certificate is missing, for other domains or expiring
+/// within the renewal days.
+fn needs_order(
+    stored: Option<&(i64, Vec<String>)>,
+    domains: &[String],
+    now: i64,
+    renew_days: u64,
+) -> bool {
+    let Some((not_after, names)) = stored else {
+        return true;
+    };
+    let names: HashSet<&String> = names.iter().collect();
+    let domains: HashSet<&String> = domains.iter().collect();
+    names != domains || *not_after - now < renew_days as i64 * 86400
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_cert_info() {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params =
+            CertificateParams::new(vec!["a.example.org".to_owned(), "b.example.org".to_owned()])
+                .unwrap();
+        params.not_after = rcgen::date_time_ymd(2030, 1, 1);
+        let cert = params.self_signed(&key).unwrap();
+
+        let info = cert_info(cert.pem().as_bytes()).unwrap();
+        assert_eq!(info.0, 1893456000);
+        assert_eq!(info.1, vec!["a.example.org", "b.example.org"]);
+
+        let domains = vec!["b.example.org".to_owned(), "a.example.org".to_owned()];
+        let day = 86400;
+        assert!(!needs_order(Some(&info), &domains, info.0 - 31 * day, 30));
+        assert!(needs_order(Some(&info), &domains, info.0 - 29 * day, 30));
+        assert!(needs_order(Some(&info), &domains[..1], 0, 30));
+        assert!(needs_order(None, &domains, 0, 30));
+
+        assert!(certified_key(cert.pem().as_bytes(), key.serialize_pem().as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let jwk = jwk(key.public_key().as_ref());
+        // serde_json orders the members as the thumbprint does
+        assert_eq!(thumbprint(&jwk), b64(Sha256::digest(jwk.to_string())));
+        assert_eq!(
+            URL_SAFE_NO_PAD
+                .decode(jwk["x"].as_str().unwrap())
+                .unwrap()
+                .len(),
+            32
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_challenge() {
+        let challenges = HttpChallenges::default();
+        challenges
+            .write()
+            .unwrap()
+            .insert("token".to_owned(), "token.thumbprint".to_owned());
+
+        let resp = http_challenge(State(challenges.clone()), UrlPath("token".to_owned())).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"token.thumbprint");
+
+        let resp = http_challenge(State(challenges), UrlPath("other".to_owned())).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_alpn_challenge_cert() {
+        let cert = alpn_challenge_cert("a.example.org", "token.thumbprint").unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.cert[0]).unwrap();
+        let identifier = cert
+            .extensions()
+            .iter()
+            .find(|ext| ext.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        // a critical DER octet string of the SHA-256 of the key authorization
+        assert!(identifier.critical);
+        let mut expected = vec![0x04, 0x20];
+        expected.extend(Sha256::digest("token.thumbprint"));
+        assert_eq!(identifier.value, expected);
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            san.value.general_names,
+            vec![GeneralName::DNSName("a.example.org")]
+        );
+    }
+
+    #[test]
+    fn test_jws() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let public_key = key.public_key().as_ref().to_vec();
+        let mut client = AcmeClient {
+            http: reqwest::Client::new(),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            jwk: jwk(&public_key),
+            key,
+            rng,
+            kid: String::new(),
+            nonce: None,
+        };
+        let decode = |part: &Value| -> Value {
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part.as_str().unwrap()).unwrap())
+                .unwrap()
+        };
+
+        // the account is registered with its key, then referenced by its url
+        let jws = client
+            .jws("https://ca/new-account", "nonce", Some(&json!({"a": 1})))
+            .unwrap();
+        let protected = decode(&jws["protected"]);
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["nonce"], "nonce");
+        assert_eq!(protected["url"], "https://ca/new-account");
+        assert_eq!(protected["jwk"], client.jwk);
+        assert!(protected.get("kid").is_none());
+        assert_eq!(decode(&jws["payload"]), json!({"a": 1}));
+
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let signature = URL_SAFE_NO_PAD
+            .decode(jws["signature"].as_str().unwrap())
+            .unwrap();
+        ring::signature::UnparsedPublicKey::new(
+            &ring::signature::ECDSA_P256_SHA256_FIXED,
+            &public_key,
+        )
+        .verify(signed.as_bytes(), &signature)
+        .unwrap();
+
+        client.kid = "https://ca/account/1".to_owned();
+        let jws = client.jws("https://ca/order/1", "nonce", None).unwrap();
+        let protected = decode(&jws["protected"]);
+        assert_eq!(protected["kid"], "https://ca/account/1");
+        assert!(protected.get("jwk").is_none());
+        // a POST-as-GET has an empty payload
+        assert_eq!(jws["payload"], "");
+    }
+
+    #[test]
+    fn test_store_cert() {
+        let dir = TempDir::new("acme").unwrap();
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let params = CertificateParams::new(vec!["a.example.org".to_owned()]).unwrap();
+        let cert = params.self_signed(&key).unwrap();
+        assert!(stored_cert(dir.path()).is_none());
+
+        store_cert(dir.path(), &cert.pem(), &key.serialize_pem()).unwrap();
+        let (_, names) = stored_cert(dir.path()).unwrap();
+        assert_eq!(names, vec!["a.example.org"]);
+        #[cfg(unix)]
+        for file in [CERT_FILE, KEY_FILE] {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.path().join(file))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(!dir.path().join("key.pem.tmp").exists());
+
+        // a key of another certificate, as left by a stop between the writes
+        let other = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        write_private(&dir.path().join(KEY_FILE), &other.serialize_pem()).unwrap();
+        assert!(stored_cert(dir.path()).is_none());
+    }
+
+    /// Orders a certificate from a Pebble test CA started with
+    /// `PEBBLE_VA_ALWAYS_VALID=1`, its directory in `PEBBLE_DIRECTORY` and its
+    /// root certificate in `PEBBLE_CA`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_pebble() {
+        let dir = TempDir::new("acme").unwrap();
+        let settings = Acme {
+            domains: vec!["a.example.org".to_owned()],
+            accept_terms: true,
+            directory_url: std::env::var("PEBBLE_DIRECTORY")
+                .unwrap_or("https://localhost:14000/dir".to_owned()),
+            ca_path: std::env::var("PEBBLE_CA").ok().map(PathBuf::from),
+            ..Default::default()
+        };
+        let resolver = Arc::new(CertResolver::default());
+        let manager = CertManager::new(
+            settings,
+            dir.path().to_owned(),
+            resolver.clone(),
+            HttpChallenges::default(),
+        );
+        manager.renew().await.unwrap();
+
+        let (_, names) = stored_cert(dir.path()).unwrap();
+        assert_eq!(names, vec!["a.example.org"]);
+        assert!(resolver.cert.read().unwrap().is_some());
+        assert!(dir.path().join(ACCOUNT_KEY_FILE).exists());
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json, RequestPartsExt, Router,
+    extract::{Path, Request, State},
+    http::StatusCode,
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Basic},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::config::{Manifest, Secret, StoredPassword, verify_login};
+use crate::http::{AppError, ReqState};
+use crate::pending::{Approval, PendingChip};
+use crate::registry::{Registry, RegistryReport};
+use crate::sensor_data::{DataWriter, ManagedWriter, WriterStatus, as_data_writers};
+
+mod ui;
+
+#[derive(Clone)]
+pub struct AdminState {
+    pub manifest: Arc<Mutex<Manifest>>,
+    /// the state of the ingestion endpoints, used to back-fill approved chips
+    pub ingest: ReqState,
+    pub writers: Vec<Arc<ManagedWriter>>,
+    pub logins: HashMap<String, StoredPassword>,
+}
+
+impl AdminState {
+    fn dyn_writers(&self) -> Vec<Arc<dyn DataWriter>> {
+        as_data_writers(&self.writers)
+    }
+}
+
+async fn auth(State(state): State<AdminState>, req: Request, next: Next) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let creds = match parts.extract::<TypedHeader<Authorization<Basic>>>().await {
+        Ok(TypedHeader(Authorization(basic))) => basic,
+        Err(_) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(axum::http::header::WWW_AUTHENTICATE, "Basic")],
+                Json(json!({"error": "missing credentials"})),
+            )
+                .into_response();
+        }
+    };
+
+    if verify_login(&state.logins, creds.username(), creds.password())
+        .await
+        .is_err()
+    {
+        tracing::warn!("admin authentication failed for user: {}", creds.username());
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({"error": "Unauthorized"})),
+        )
+            .into_response();
+    }
+
+    next.run(Request::from_parts(parts, body)).await
+}
+
+async fn get_config(State(state): State<AdminState>) -> Result<Json<Value>, AppError> {
+    let manifest = state.manifest.lock().unwrap();
+    Ok(Json(Secret::masked(|| serde_json::to_value(&*manifest))?))
+}
+
+#[derive(Deserialize)]
+struct LogLevel {
+    level: String,
+}
+
+async fn put_log_level(
+    State(state): State<AdminState>,
+    Json(LogLevel { level }): Json<LogLevel>,
+) -> Result<Json<Value>, AppError> {
+    state.manifest.lock().unwrap().change_log_level(&level)?;
+    tracing::info!("log level changed to: {}", level);
+    Ok(Json(json!({ "level": level.to_lowercase() })))
+}
+
+/// Runs a registry operation on the blocking threads, the registry reads and
+/// rewrites its files under a lock.
+async fn blocking_registry(
+    state: &AdminState,
+    op: impl FnOnce(&Registry) -> Result<RegistryReport, Box<dyn Error>> + Send + 'static,
+) -> anyhow::Result<RegistryReport> {
+    let registry = state.ingest.registry.clone();
+    tokio::task::spawn_blocking(move || op(&registry).map_err(|e| anyhow::anyhow!("{}", e))).await?
+}
+
+/// Reloads both files, the registry is only replaced when both validate.
+async fn post_reload(State(state): State<AdminState>) -> Result<Response, AppError> {
+    let report = blocking_registry(&state, Registry::reload)
+        .await
+        .map_err(|e| anyhow::anyhow!("error reloading the registry: {}", e))?;
+
+    if !report.applied {
+        return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(report)).into_response());
+    }
+
+    crate::refresh_sensor_info_on_writers(&state.ingest.registry, &state.dyn_writers()).await;
+    Ok(Json(report).into_response())
+}
+
+fn writer_statuses(state: &AdminState) -> Json<Vec<WriterStatus>> {
+    Json(state.writers.iter().map(|w| w.status()).collect())
+}
+
+async fn get_writers(State(state): State<AdminState>) -> Json<Vec<WriterStatus>> {
+    writer_statuses(&state)
+}
+
+fn set_writer_paused(state: &AdminState, name: &str, paused: bool) -> Response {
+    match state.writers.iter().find(|w| w.name() == name) {
+        Some(w) => {
+            w.set_paused(paused);
+            Json(w.status()).into_response()
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": format!("unknown writer: {}", name)})),
+        )
+            .into_response(),
+    }
+}
+
+async fn post_pause(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    set_writer_paused(&state, &name, true)
+}
+
+async fn post_resume(State(state): State<AdminState>, Path(name): Path<String>) -> Response {
+    set_writer_paused(&state, &name, false)
+}
+
+/// The names of the paused writers, they skip the writes and the refreshes.
+fn paused_writers(state: &AdminState) -> Vec<&'static str> {
+    state
+        .writers
+        .iter()
+        .filter(|w| w.status().paused)
+        .map(|w| w.name())
+        .collect()
+}
+
+/// Refreshes the sensor info of the writers, 409 when some are paused and
+/// were not refreshed.
+async fn post_refresh_sensor_info(State(state): State<AdminState>) -> Response {
+    crate::refresh_sensor_info_on_writers(&state.ingest.registry, &state.dyn_writers()).await;
+    let paused = paused_writers(&state);
+    if paused.is_empty() {
+        return writer_statuses(&state).into_response();
+    }
+    (
+        StatusCode::CONFLICT,
+        Json(json!({
+            "error": "paused writers were not refreshed",
+            "paused": paused,
+            "writers": writer_statuses(&state).0,
+        })),
+    )
+        .into_response()
+}
+
+async fn get_pending(State(state): State<AdminState>) -> Result<Json<Vec<PendingChip>>, AppError> {
+    let ingest = &state.ingest;
+    Ok(Json(
+        ingest.pending.list(&ingest.measure_name_to_sensor_type)?,
+    ))
+}
+
+/// Registers a pending chip and writes its held payloads, 422 when the registry refuses it.
+/// Refused with 409 while a writer is paused, the held payloads would not reach it.
+async fn post_approve_pending(
+    State(state): State<AdminState>,
+    Path(chip_id): Path<String>,
+    Json(approval): Json<Approval>,
+) -> Response {
+    let paused = paused_writers(&state);
+    if !paused.is_empty() {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "error": "resume the paused writers before approving",
+                "paused": paused,
+            })),
+        )
+            .into_response();
+    }
+    match crate::pending::approve(&state.ingest, &chip_id, approval).await {
+        Ok(report) if !report.registry.applied => {
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(report)).into_response()
+        }
+        Ok(report) => {
+            crate::refresh_sensor_info_on_writers(&state.ingest.registry, &state.dyn_writers())
+                .await;
+            Json(report).into_response()
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+async fn delete_pending(
+    State(state): State<AdminState>,
+    Path(chip_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if state.ingest.pending.remove(&chip_id)? {
+        tracing::info!("dropped the payloads held for chip {}", chip_id);
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+pub fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/admin/config", get(get_config))
+        .route("/admin/log-level", put(put_log_level))
+        .route("/admin/reload", post(post_reload))
+        .route("/admin/writers", get(get_writers))
+        .route("/admin/writers/{name}/pause", post(post_pause))
+        .route("/admin/writers/{name}/resume", post(post_resume))
+        .route("/admin/refresh-sensor-info", post(post_refresh_sensor_info))
+        .route("/admin/pending", get(get_pending))
+        .route("/admin/pending/{chip_id}", delete(delete_pending))
+        .route(
+            "/admin/pending/{chip_id}/approve",
+            post(post_approve_pending),
+        )
+        .route("/admin/ui", get(ui::index))
+        .route("/admin/api/sensor-types", get(ui::get_sensor_types))
+        .route("/admin/api/chips", get(ui::get_chips))
+        .route("/admin/api/chips/{chip_id}", put(ui::put_chip))
+        .route(
+            "/admin/api/sensors",
+            get(ui::get_sensors).put(ui::put_sensor),
+        )
+        .route("/admin/api/sensors/{sensor_id}", get(ui::get_sensor))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth))
+        .with_state(state)
+}
+
+/// Serves the admin api on its own address, meant to be bound to localhost or a private network.
+pub async fn serve(addr: &str, state: AdminState) {
+    let addr: SocketAddr = match addr.trim().parse() {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::error!("invalid admin address {}: {}", addr, e);
+            return;
+        }
+    };
+
+    if state.logins.is_empty() {
+        tracing::error!("admin api enabled without logins, not starting it");
+        return;
+    }
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::error!("could not bind admin address {}: {}", addr, e);
+            return;
+        }
+    };
+    tracing::info!("serving admin api on address: {}", addr);
+
+    if let Err(e) = axum::serve(listener, router(state)).await {
+        tracing::error!("admin server error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Mqtt, QuestDB};
+
+    #[test]
+    fn test_masked_secrets() {
+        let questdb = QuestDB {
+            username: "admin".to_owned(),
+            password: Secret::new("secret"),
+            ..Default::default()
+        };
+        let mqtt = Mqtt {
+            password: Secret::resolve("plain").unwrap(),
+            ..Default::default()
+        };
+        let value = Secret::masked(|| json!({"questdb": questdb, "mqtt": mqtt}));
+        assert_eq!(value["questdb"]["password"], "********");
+        assert_eq!(value["questdb"]["username"], "admin");
+        assert_eq!(value["mqtt"]["password"], "********");
+        // the manifest is still saved with the secrets
+        assert_eq!(json!(questdb)["password"], "secret");
+    }
+}
@@ -0,0 +1,152 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{AdminState, blocking_registry};
+use crate::http::AppError;
+use crate::registry::RegistryReport;
+use crate::{ChipInfo, SensorInfo};
+
+const INDEX_HTML: &str = include_str!("ui.html");
+
+#[derive(Serialize)]
+pub struct ChipView {
+    #[serde(flatten)]
+    chip: ChipInfo,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+fn bad_request(msg: String) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": msg }))).into_response()
+}
+
+pub async fn index() -> Html<&'static str> {
+    Html(INDEX_HTML)
+}
+
+pub async fn get_sensor_types(State(state): State<AdminState>) -> Json<Vec<String>> {
+    Json(state.ingest.registry.sensor_types().to_vec())
+}
+
+#[derive(Deserialize)]
+pub struct ChipFilter {
+    city: Option<String>,
+}
+
+pub async fn get_chips(
+    State(state): State<AdminState>,
+    Query(filter): Query<ChipFilter>,
+) -> Json<Vec<ChipView>> {
+    let chips = match &filter.city {
+        Some(city) => state.ingest.registry.chips_in_city(city),
+        None => state.ingest.registry.chips(),
+    };
+    let last_seen = state.ingest.last_seen.read().unwrap().clone();
+    Json(
+        chips
+            .into_iter()
+            .map(|chip| ChipView {
+                last_seen: last_seen.get(&chip.chip_id).cloned(),
+                chip,
+            })
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct SensorFilter {
+    chip_id: Option<String>,
+    sensor_type: Option<String>,
+}
+
+pub async fn get_sensors(
+    State(state): State<AdminState>,
+    Query(filter): Query<SensorFilter>,
+) -> Json<Vec<SensorInfo>> {
+    let sensors = match (&filter.chip_id, &filter.sensor_type) {
+        (Some(chip_id), sensor_type) => state
+            .ingest
+            .registry
+            .sensors_of_chip(chip_id)
+            .into_iter()
+            .filter(|s| sensor_type.as_ref().is_none_or(|t| &s.sensor_type == t))
+            .collect(),
+        (None, Some(sensor_type)) => state.ingest.registry.sensors_of_type(sensor_type),
+        (None, None) => state.ingest.registry.sensors(),
+    };
+    Json(sensors)
+}
+
+pub async fn get_sensor(
+    State(state): State<AdminState>,
+    Path(sensor_id): Path<String>,
+) -> Response {
+    match state.ingest.registry.sensor(&sensor_id) {
+        Some(sensor) => Json(sensor).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "error": format!("unknown sensor id {}", sensor_id) })),
+        )
+            .into_response(),
+    }
+}
+
+fn saved(report: RegistryReport) -> Response {
+    if report.applied {
+        Json(report).into_response()
+    } else {
+        (StatusCode::UNPROCESSABLE_ENTITY, Json(report)).into_response()
+    }
+}
+
+/// Adds or updates a chip, writing the chips file back and reloading the registry.
+pub async fn put_chip(
+    State(state): State<AdminState>,
+    Path(chip_id): Path<String>,
+    Json(chip): Json<ChipInfo>,
+) -> Result<Response, AppError> {
+    if chip.chip_id != chip_id {
+        return Ok(bad_request(format!(
+            "chip id {} does not match the path {}",
+            chip.chip_id, chip_id
+        )));
+    }
+    if let Err(e) = chip.validate() {
+        return Ok(bad_request(e));
+    }
+
+    tracing::info!("saving chip {} from the admin ui", chip.chip_id);
+    let report = blocking_registry(&state, move |registry| registry.upsert_chip(chip))
+        .await
+        .map_err(|e| anyhow::anyhow!("error saving the chip: {}", e))?;
+    Ok(saved(report))
+}
+
+/// Adds or updates a sensor, writing the sensors file back and reloading the registry.
+pub async fn put_sensor(
+    State(state): State<AdminState>,
+    Json(sensor): Json<SensorInfo>,
+) -> Result<Response, AppError> {
+    if let Err(e) = sensor.validate(state.ingest.registry.sensor_types()) {
+        return Ok(bad_request(e));
+    }
+    if !state.ingest.registry.contains_chip(&sensor.chip_id) {
+        return Ok(bad_request(format!("unknown chip id {}", sensor.chip_id)));
+    }
+
+    tracing::info!(
+        "saving sensor {} of chip {} from the admin ui",
+        sensor.sensor_id,
+        sensor.chip_id
+    );
+    let report = blocking_registry(&state, move |registry| registry.upsert_sensor(sensor))
+        .await
+        .map_err(|e| anyhow::anyhow!("error saving the sensor: {}", e))?;
+    Ok(saved(report))
+}
@@ -13,6 +13,8 @@ use futures::{
 
 use notify_debouncer_full::{DebounceEventResult, Debouncer, RecommendedCache, new_debouncer};
 
+use super::ReloadReport;
+
 fn _async_watcher() -> notify::Result<(RecommendedWatcher, Receiver<notify::Result<Event>>)> {
     let (mut tx, rx) = channel(10);
 
@@ -55,17 +57,18 @@ fn async_debounce_watcher() -> notify::Result<(
 }
 
 pub trait CacheKey {
+    /// name of the cache, used in metrics
+    const NAME: &'static str;
     fn id(&self) -> String;
 }
 
-/// The key is the chip id
-// type ChipCache = HashMap<String, ChipInfo>;
-
-pub type Cache<T> = Arc<RwLock<HashMap<String, T>>>;
+/// Checks a single row before it is accepted in the cache, returning the reason it is refused.
+pub type Validator<T> = Arc<dyn Fn(&T) -> std::result::Result<(), String> + Send + Sync>;
 
-fn load_cache_from_file<T: CacheKey + serde::de::DeserializeOwned>(
+pub fn load_cache_from_file<T: CacheKey + serde::de::DeserializeOwned>(
     path: &str,
-) -> std::result::Result<HashMap<String, T>, Box<dyn std::error::Error>> {
+    validator: &Validator<T>,
+) -> std::result::Result<(HashMap<String, T>, ReloadReport), Box<dyn std::error::Error>> {
     let file = std::fs::File::open(path)?;
     let file_size = file.metadata()?.len();
 
@@ -75,41 +78,103 @@ fn load_cache_from_file<T: CacheKey + serde::de::DeserializeOwned>(
 
     let reader = std::io::BufReader::new(file);
 
-    let mut cache = HashMap::new();
+    parse_cache(reader, path, validator)
+}
 
+/// Parses every row, collecting the rows that can not be decoded, do not validate
+/// or repeat an id already seen, instead of stopping at the first bad one.
+pub(super) fn parse_cache<T: CacheKey + serde::de::DeserializeOwned, R: std::io::Read>(
+    reader: R,
+    path: &str,
+    validator: &Validator<T>,
+) -> std::result::Result<(HashMap<String, T>, ReloadReport), Box<dyn std::error::Error>> {
     let mut rdr = csv::Reader::from_reader(reader);
-    for result in rdr.deserialize() {
-        // Notice that we need to provide a type hint for automatic
-        // deserialization.
-        let record: T = result?;
-        cache.insert(record.id().clone(), record);
+    let headers = rdr.headers()?.clone();
+    let rows = rdr.records().map(|result| match result {
+        Ok(row) => {
+            let line = row.position().map(|p| p.line()).unwrap_or_default();
+            (
+                line,
+                row.deserialize(Some(&headers)).map_err(|e| e.to_string()),
+            )
+        }
+        Err(e) => {
+            let line = e.position().map(|p| p.line()).unwrap_or_default();
+            (line, Err(e.to_string()))
+        }
+    });
+    Ok(collect_rows(rows, path, validator))
+}
+
+/// Builds the cache from decoded rows, each with the line or position it was read from.
+pub(super) fn collect_rows<T: CacheKey>(
+    rows: impl Iterator<Item = (u64, std::result::Result<T, String>)>,
+    path: &str,
+    validator: &Validator<T>,
+) -> (HashMap<String, T>, ReloadReport) {
+    let mut report = ReloadReport::new(T::NAME, path);
+    let mut cache = HashMap::new();
+
+    for (line, record) in rows {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                report.error(line, e);
+                continue;
+            }
+        };
+        if let Err(e) = validator(&record) {
+            report.error(line, e);
+            continue;
+        }
+
+        let id = record.id();
+        if let Some(first) = report.lines.get(&id) {
+            report.error(
+                line,
+                format!("duplicate id {}, first defined at line {}", id, first),
+            );
+            continue;
+        }
+        report.lines.insert(id.clone(), line);
+        cache.insert(id, record);
     }
 
-    Ok(cache)
+    report.items = cache.len();
+    (cache, report)
 }
 
-pub fn load_cache<T: CacheKey + serde::de::DeserializeOwned + Send + Sync + 'static>(
+/// Writes the items sorted by id to the csv file. The data is written to a temporary file
+/// in the same folder, then renamed over the original so readers never see a partial file.
+pub fn save_cache_to_file<T: CacheKey + serde::Serialize>(
     path: &str,
-) -> std::result::Result<
-    (
-        Cache<T>,
-        Arc<RwLock<Debouncer<RecommendedWatcher, RecommendedCache>>>,
-        tokio::sync::watch::Receiver<u64>,
-    ),
-    Box<dyn std::error::Error>,
-> {
-    let config = load_cache_from_file(path)?;
-
-    // We wrap the data a mutex under an atomic reference counted pointer
-    // to guarantee that the config won't be read and written to at the same time.
-    // To learn about how that works,
-    // please check out the [Fearless Concurrency](https://doc.rust-lang.org/book/ch16-00-concurrency.html) chapter of the Rust book.
-    let config = Arc::new(RwLock::new(config));
-    let cloned_config = Arc::clone(&config);
+    items: &[&T],
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut items = items.to_vec();
+    items.sort_by_key(|item| item.id());
 
-    let cloned_path = path.to_owned();
+    let tmp_path = format!("{}.tmp", path);
+    {
+        let mut wtr = csv::Writer::from_path(&tmp_path)?;
+        for item in items {
+            wtr.serialize(item)?;
+        }
+        wtr.flush()?;
+        wtr.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
 
-    let (watch_tx, watch_rx) = tokio::sync::watch::channel(0u64);
+pub type FileWatcher = Arc<RwLock<Debouncer<RecommendedWatcher, RecommendedCache>>>;
+
+/// Watches a file calling `on_change` when it is modified or replaced,
+/// the watcher is reset when the file is removed.
+pub fn watch_file<F: Fn() + Send + 'static>(
+    path: &str,
+    on_change: F,
+) -> std::result::Result<FileWatcher, Box<dyn std::error::Error>> {
+    let cloned_path = path.to_owned();
 
     let (mut watcher, mut rx) = async_debounce_watcher()?;
 
@@ -143,25 +208,7 @@ pub fn load_cache<T: CacheKey + serde::de::DeserializeOwned + Send + Sync + 'sta
 
                             if reload {
                                 // std::thread::sleep(std::time::Duration::from_millis(1000));
-                                match load_cache_from_file(&cloned_path) {
-                                    Ok(new_config) => {
-                                        tracing::info!(
-                                            "Successfully reloaded cache from file: {}, items are: {}",
-                                            &cloned_path,
-                                            new_config.len(),
-                                        );
-                                        let mut w = cloned_config.write().unwrap();
-                                        *w = new_config;
-                                        watch_tx.send_modify(|v| *v += 1);
-                                    }
-                                    Err(error) => {
-                                        tracing::error!(
-                                            "Error reloading cache from file {}: {:?}",
-                                            &cloned_path,
-                                            error
-                                        )
-                                    }
-                                }
+                                on_change();
                             }
                             if removed {
                                 break 'outer;
@@ -199,5 +246,44 @@ pub fn load_cache<T: CacheKey + serde::de::DeserializeOwned + Send + Sync + 'sta
         }
     });
 
-    Ok((config, watcher_lock_clone, watch_rx))
+    Ok(watcher_lock_clone)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize)]
+    struct Item {
+        id: String,
+        value: f64,
+    }
+
+    impl CacheKey for Item {
+        const NAME: &'static str = "items";
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    #[test]
+    fn test_parse_cache_reports_lines() {
+        let csv = "id,value\na,1\nb,x\na,2\nc,-5\nd,3,4\ne,6\n";
+        let validator: Validator<Item> = Arc::new(|item: &Item| {
+            if item.value < 0.0 {
+                Err(format!("negative value {}", item.value))
+            } else {
+                Ok(())
+            }
+        });
+        let (cache, report) = parse_cache(csv.as_bytes(), "items.csv", &validator).unwrap();
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(report.items, 2);
+        assert!(!report.is_valid());
+        let lines: Vec<u64> = report.errors.iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 4, 5, 6]);
+        assert!(report.errors[1].message.contains("first defined at line 2"));
+    }
 }
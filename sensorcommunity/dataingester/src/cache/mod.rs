@@ -1,6 +1,9 @@
 mod hotreload;
-
-pub use hotreload::{Cache, CacheKey, load_cache};
-
-
-
+mod report;
+mod source;
+
+pub use hotreload::{
+    CacheKey, FileWatcher, Validator, load_cache_from_file, save_cache_to_file, watch_file,
+};
+pub use report::ReloadReport;
+pub use source::{Source, SourceWatcher, open_source};
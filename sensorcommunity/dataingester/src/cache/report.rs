@@ -0,0 +1,66 @@
+use std::{collections::HashMap, fmt};
+
+use serde::Serialize;
+
+/// A problem found in a row of a cache file, `line` is the 1-based line in the file.
+#[derive(Debug, Clone, Serialize)]
+pub struct RowError {
+    pub line: u64,
+    pub message: String,
+}
+
+/// Outcome of loading a cache file: the valid items and the rows that were refused.
+/// The cache is only replaced when there are no errors.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReloadReport {
+    pub cache: &'static str,
+    pub path: String,
+    pub items: usize,
+    pub errors: Vec<RowError>,
+    pub applied: bool,
+    /// line of each accepted item by id, for the checks made across rows
+    #[serde(skip)]
+    pub lines: HashMap<String, u64>,
+}
+
+impl ReloadReport {
+    pub fn new(cache: &'static str, path: &str) -> Self {
+        ReloadReport {
+            cache,
+            path: path.to_owned(),
+            items: 0,
+            errors: Vec::new(),
+            applied: false,
+            lines: HashMap::new(),
+        }
+    }
+
+    pub fn error(&mut self, line: u64, message: impl Into<String>) {
+        self.errors.push(RowError {
+            line,
+            message: message.into(),
+        });
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} cache from {}: {} valid items, {} errors{}",
+            self.cache,
+            self.path,
+            self.items,
+            self.errors.len(),
+            if self.applied { "" } else { ", not applied" }
+        )?;
+        for e in &self.errors {
+            write!(f, "\n  line {}: {}", e.line, e.message)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,516 @@
+//! Where the rows of a cache are read from. The location is a path or url:
+//!
+//! - `chips.csv`, `chips.json`, `chips.toml`: a local file, the format is taken from the
+//!   extension. A json file holds an array of rows, a toml file an array of tables named
+//!   after the cache, e.g. `[[chips]]`.
+//! - `sqlite://registry.db?table=chips`: a table of a SQLite database, the table defaults
+//!   to the name of the cache. Values are decoded like the fields of a csv file.
+//! - `http://...` or `https://...`: a csv document polled periodically, e.g. the csv
+//!   export of a published spreadsheet. It is read-only.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{
+    CacheKey, FileWatcher, ReloadReport, Validator,
+    hotreload::{collect_rows, parse_cache},
+    load_cache_from_file, save_cache_to_file, watch_file,
+};
+
+/// Keeps a source watched, the watching stops when it is dropped.
+pub enum SourceWatcher {
+    File(FileWatcher),
+    Poll(PollTask),
+}
+
+/// Background task polling a source, aborted on drop.
+pub struct PollTask(tokio::task::JoinHandle<()>);
+
+impl Drop for PollTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub type OnChange = Box<dyn Fn() + Send + 'static>;
+
+/// A place the rows of a cache are loaded from and, unless read-only, saved to.
+pub trait Source<T>: Send + Sync {
+    /// the location the source was opened from, used in the reports
+    fn location(&self) -> &str;
+
+    fn load(
+        &self,
+        validator: &Validator<T>,
+    ) -> Result<(HashMap<String, T>, ReloadReport), Box<dyn Error>>;
+
+    fn save(&self, items: &[&T]) -> Result<(), Box<dyn Error>>;
+
+    /// Calls `on_change` whenever the rows may have changed.
+    fn watch(&self, on_change: OnChange) -> Result<SourceWatcher, Box<dyn Error>>;
+}
+
+/// Opens the source at `location`, `poll_every` is how often remote sources are checked
+/// for changes, zero disables the polling.
+pub fn open_source<T>(
+    location: &str,
+    poll_every: Duration,
+) -> Result<Box<dyn Source<T>>, Box<dyn Error>>
+where
+    T: CacheKey + Serialize + DeserializeOwned + 'static,
+{
+    if let Some(rest) = location.strip_prefix("sqlite://") {
+        let (path, table) = match rest.split_once("?table=") {
+            Some((path, table)) => (path, table),
+            None => (rest, T::NAME),
+        };
+        if table.is_empty() || !table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("invalid table name {:?} in {}", table, location).into());
+        }
+        return Ok(Box::new(SqliteSource {
+            location: location.to_owned(),
+            path: PathBuf::from(path),
+            table: table.to_owned(),
+        }));
+    }
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Ok(Box::new(HttpSource {
+            url: location.to_owned(),
+            poll_every,
+            last_hash: Default::default(),
+            polled: Default::default(),
+        }));
+    }
+
+    let format = match Path::new(location).extension().and_then(|e| e.to_str()) {
+        Some("json") => FileFormat::Json,
+        Some("toml") => FileFormat::Toml,
+        _ => FileFormat::Csv,
+    };
+    Ok(Box::new(FileSource {
+        path: location.to_owned(),
+        format,
+    }))
+}
+
+#[derive(Clone, Copy)]
+enum FileFormat {
+    Csv,
+    Json,
+    Toml,
+}
+
+struct FileSource {
+    path: String,
+    format: FileFormat,
+}
+
+/// Writes to a temporary file in the same folder, then renames it over the original.
+fn replace_file(path: &str, contents: &[u8]) -> Result<(), Box<dyn Error>> {
+    let tmp_path = format!("{}.tmp", path);
+    {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn sorted<'a, T: CacheKey>(items: &[&'a T]) -> Vec<&'a T> {
+    let mut items = items.to_vec();
+    items.sort_by_key(|item| item.id());
+    items
+}
+
+impl<T: CacheKey + Serialize + DeserializeOwned> Source<T> for FileSource {
+    fn location(&self) -> &str {
+        &self.path
+    }
+
+    fn load(
+        &self,
+        validator: &Validator<T>,
+    ) -> Result<(HashMap<String, T>, ReloadReport), Box<dyn Error>> {
+        let rows: Vec<(u64, Result<T, String>)> = match self.format {
+            FileFormat::Csv => return load_cache_from_file(&self.path, validator),
+            FileFormat::Json => {
+                let values: Vec<serde_json::Value> = serde_json::from_reader(
+                    std::io::BufReader::new(std::fs::File::open(&self.path)?),
+                )?;
+                values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        (
+                            i as u64 + 1,
+                            serde_json::from_value(v).map_err(|e| e.to_string()),
+                        )
+                    })
+                    .collect()
+            }
+            FileFormat::Toml => {
+                let mut table: toml::Table = std::fs::read_to_string(&self.path)?.parse()?;
+                let values = match table.remove(T::NAME) {
+                    Some(toml::Value::Array(values)) => values,
+                    Some(_) => return Err(format!("{} must be an array of tables", T::NAME).into()),
+                    None => vec![],
+                };
+                values
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, v)| {
+                        (
+                            i as u64 + 1,
+                            v.try_into().map_err(|e: toml::de::Error| e.to_string()),
+                        )
+                    })
+                    .collect()
+            }
+        };
+        Ok(collect_rows(rows.into_iter(), &self.path, validator))
+    }
+
+    fn save(&self, items: &[&T]) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            FileFormat::Csv => save_cache_to_file(&self.path, items),
+            FileFormat::Json => {
+                replace_file(&self.path, &serde_json::to_vec_pretty(&sorted(items))?)
+            }
+            FileFormat::Toml => {
+                let mut table = toml::Table::new();
+                table.insert(T::NAME.to_owned(), toml::Value::try_from(sorted(items))?);
+                replace_file(&self.path, toml::to_string(&table)?.as_bytes())
+            }
+        }
+    }
+
+    fn watch(&self, on_change: OnChange) -> Result<SourceWatcher, Box<dyn Error>> {
+        Ok(SourceWatcher::File(watch_file(&self.path, on_change)?))
+    }
+}
+
+/// A SQLite table with a column per field. The database file is watched, changes
+/// made in WAL mode are only seen once they are checkpointed to the file.
+struct SqliteSource {
+    location: String,
+    path: PathBuf,
+    table: String,
+}
+
+impl SqliteSource {
+    /// Opens the database, read-only unless writing. A missing database is an
+    /// error, not an empty one created in its place.
+    fn open(&self, write: bool) -> Result<rusqlite::Connection, Box<dyn Error>> {
+        if !self.path.exists() {
+            return Err(format!("the database {} does not exist", self.path.display()).into());
+        }
+        let flags = match write {
+            true => rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE,
+            false => rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        };
+        Ok(rusqlite::Connection::open_with_flags(&self.path, flags)?)
+    }
+}
+
+fn sql_text(value: rusqlite::types::ValueRef<'_>) -> Result<String, String> {
+    use rusqlite::types::ValueRef;
+    match value {
+        ValueRef::Null => Ok(String::new()),
+        ValueRef::Integer(i) => Ok(i.to_string()),
+        ValueRef::Real(f) => Ok(f.to_string()),
+        ValueRef::Text(t) => String::from_utf8(t.to_vec()).map_err(|e| e.to_string()),
+        ValueRef::Blob(_) => Err("blob values are not supported".to_owned()),
+    }
+}
+
+impl<T: CacheKey + Serialize + DeserializeOwned> Source<T> for SqliteSource {
+    fn location(&self) -> &str {
+        &self.location
+    }
+
+    fn load(
+        &self,
+        validator: &Validator<T>,
+    ) -> Result<(HashMap<String, T>, ReloadReport), Box<dyn Error>> {
+        let conn = self.open(false)?;
+        let mut stmt = conn.prepare(&format!("SELECT * FROM \"{}\"", self.table))?;
+        let headers = csv::StringRecord::from(stmt.column_names());
+        let columns = headers.len();
+
+        let mut rows = stmt.query([])?;
+        let mut decoded = vec![];
+        let mut n = 0;
+        while let Some(row) = rows.next()? {
+            n += 1;
+            let record: Result<csv::StringRecord, String> = (0..columns)
+                .map(|i| sql_text(row.get_ref(i).map_err(|e| e.to_string())?))
+                .collect();
+            let record =
+                record.and_then(|r| r.deserialize(Some(&headers)).map_err(|e| e.to_string()));
+            decoded.push((n, record));
+        }
+        Ok(collect_rows(decoded.into_iter(), &self.location, validator))
+    }
+
+    fn save(&self, items: &[&T]) -> Result<(), Box<dyn Error>> {
+        // the rows are encoded like the csv files, so they decode the same way
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        for item in sorted(items) {
+            wtr.serialize(item)?;
+        }
+        let data = wtr.into_inner().map_err(|e| e.into_error())?;
+        let mut rdr = csv::Reader::from_reader(data.as_slice());
+        let headers = rdr.headers()?.clone();
+        let columns: Vec<String> = headers.iter().map(|h| format!("\"{}\"", h)).collect();
+
+        let mut conn = self.open(true)?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS \"{}\" ({})",
+                self.table,
+                columns
+                    .iter()
+                    .map(|c| format!("{} TEXT", c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            [],
+        )?;
+        tx.execute(&format!("DELETE FROM \"{}\"", self.table), [])?;
+        {
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO \"{}\" ({}) VALUES ({})",
+                self.table,
+                columns.join(", "),
+                vec!["?"; columns.len()].join(", ")
+            ))?;
+            for record in rdr.records() {
+                let record = record?;
+                stmt.execute(rusqlite::params_from_iter(record.iter()))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn watch(&self, on_change: OnChange) -> Result<SourceWatcher, Box<dyn Error>> {
+        Ok(SourceWatcher::File(watch_file(
+            &self.path.to_string_lossy(),
+            on_change,
+        )?))
+    }
+}
+
+/// A csv document fetched over http, polled for changes.
+struct HttpSource {
+    url: String,
+    poll_every: Duration,
+    /// hash of the document last loaded, the poller reports a change when it differs
+    last_hash: Arc<Mutex<Option<u64>>>,
+    /// the changed document fetched by the poller, loaded without fetching it again
+    polled: Arc<Mutex<Option<String>>>,
+}
+
+/// The longest a fetch of a document may take, a hanging server would stall the
+/// first load and the polling.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+async fn fetch(url: &str, timeout: Duration) -> Result<String, reqwest::Error> {
+    reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await
+}
+
+fn hash_of(body: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Fetches the document from sync code, which may run on a runtime thread,
+/// so the request is made on a thread of its own. Only the first load fetches
+/// this way, the reloads get the document fetched by the poller.
+fn fetch_blocking(url: &str, timeout: Duration) -> Result<String, Box<dyn Error>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let thread_url = url.to_owned();
+    std::thread::spawn(move || {
+        let res = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())
+            .and_then(|rt| {
+                rt.block_on(fetch(&thread_url, timeout))
+                    .map_err(|e| e.to_string())
+            });
+        let _ = tx.send(res);
+    });
+    // the client times out first, the wait is bounded should it not
+    match rx.recv_timeout(timeout + Duration::from_secs(5)) {
+        Ok(res) => res.map_err(|e| e.into()),
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            Err(format!("timed out fetching {}", url).into())
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+            Err("the http fetch thread panicked".into())
+        }
+    }
+}
+
+impl<T: CacheKey + DeserializeOwned> Source<T> for HttpSource {
+    fn location(&self) -> &str {
+        &self.url
+    }
+
+    fn load(
+        &self,
+        validator: &Validator<T>,
+    ) -> Result<(HashMap<String, T>, ReloadReport), Box<dyn Error>> {
+        let polled = self.polled.lock().unwrap().take();
+        let body = match polled {
+            Some(body) => body,
+            None => fetch_blocking(&self.url, FETCH_TIMEOUT)?,
+        };
+        if body.trim().is_empty() {
+            return Err(format!("the document at {} is empty", self.url).into());
+        }
+        let loaded = parse_cache(body.as_bytes(), &self.url, validator)?;
+        *self.last_hash.lock().unwrap() = Some(hash_of(&body));
+        Ok(loaded)
+    }
+
+    fn save(&self, _items: &[&T]) -> Result<(), Box<dyn Error>> {
+        Err(format!(
+            "{} is read-only, edit the document at {}",
+            T::NAME,
+            self.url
+        )
+        .into())
+    }
+
+    fn watch(&self, on_change: OnChange) -> Result<SourceWatcher, Box<dyn Error>> {
+        let url = self.url.clone();
+        let poll_every = self.poll_every;
+        let last_hash = self.last_hash.clone();
+        let polled = self.polled.clone();
+        let task = tokio::spawn(async move {
+            if poll_every.is_zero() {
+                return;
+            }
+            let mut interval = tokio::time::interval(poll_every);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match fetch(&url, FETCH_TIMEOUT).await {
+                    Ok(body) => {
+                        let hash = hash_of(&body);
+                        if *last_hash.lock().unwrap() != Some(hash) {
+                            tracing::info!("{} changed", url);
+                            *polled.lock().unwrap() = Some(body);
+                            on_change();
+                        }
+                    }
+                    Err(e) => tracing::error!("Error polling {}: {}", url, e),
+                }
+            }
+        });
+        Ok(SourceWatcher::Poll(PollTask(task)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+    use tempdir::TempDir;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Item {
+        id: String,
+        value: f64,
+        #[serde(default)]
+        enabled: bool,
+    }
+
+    impl CacheKey for Item {
+        const NAME: &'static str = "items";
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+    }
+
+    #[test]
+    fn test_sources_round_trip() {
+        let tmp_dir = TempDir::new("sources").unwrap();
+        let dir = tmp_dir.path();
+        let validator: Validator<Item> = Arc::new(|_: &Item| Ok(()));
+        let items = [
+            Item {
+                id: "b".to_owned(),
+                value: 2.5,
+                enabled: true,
+            },
+            Item {
+                id: "a".to_owned(),
+                value: -1.0,
+                enabled: false,
+            },
+        ];
+        let refs: Vec<&Item> = items.iter().collect();
+
+        for name in ["items.csv", "items.json", "items.toml", "items.db"] {
+            let location = match name {
+                "items.db" => format!("sqlite://{}", dir.join(name).display()),
+                _ => dir.join(name).display().to_string(),
+            };
+            let source = open_source::<Item>(&location, Duration::ZERO).unwrap();
+            if name == "items.db" {
+                // never created by the source
+                assert!(source.load(&validator).is_err());
+                assert!(!dir.join(name).exists());
+                rusqlite::Connection::open(dir.join(name)).unwrap();
+            }
+            source.save(&refs).unwrap();
+            let (cache, report) = source.load(&validator).unwrap();
+            assert!(report.is_valid(), "{}", report);
+            assert_eq!(cache.len(), 2, "{}", name);
+            assert_eq!(cache["b"].value, 2.5);
+            assert!(cache["b"].enabled);
+            assert!(!cache["a"].enabled);
+        }
+    }
+
+    #[test]
+    fn test_fetch_timeout() {
+        // accepts the connection and never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/sheet.csv", listener.local_addr().unwrap());
+        let started = std::time::Instant::now();
+        assert!(fetch_blocking(&url, Duration::from_millis(200)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn test_open_source_rejects_bad_table() {
+        assert!(open_source::<Item>("sqlite://x.db?table=a;drop", Duration::ZERO).is_err());
+        assert!(open_source::<Item>("https://example.org/sheet.csv", Duration::ZERO).is_ok());
+    }
+}
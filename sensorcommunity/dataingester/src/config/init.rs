@@ -1,5 +1,5 @@
 // #[macro_use]
-use super::manifest::Manifest;
+use super::manifest::{GeneratedLogin, Manifest};
 use std::fs;
 use std::path::PathBuf;
 use tracing::{event, Level};
@@ -23,10 +23,12 @@ impl Context {
     }
 }
 
-pub fn init_cli(manifest_name: &str) -> std::result::Result<(Manifest, tracing_appender::non_blocking::WorkerGuard, Context), anyhow::Error> {
+/// Loads the manifest, or creates the default one with the generated logins.
+pub fn init_cli(manifest_name: &str) -> std::result::Result<(Manifest, tracing_appender::non_blocking::WorkerGuard, Context, Vec<GeneratedLogin>), anyhow::Error> {
 
     let manifest;
     let guard;
+    let mut generated = vec![];
     let path = PathBuf::from(manifest_name);
     if path.exists() {
         println!("Using config file: {:?}", fs::canonicalize(&path)?);
@@ -36,7 +38,7 @@ pub fn init_cli(manifest_name: &str) -> std::result::Result<(Manifest, tracing_a
             "default manifest missing: {}, using default values",
             manifest_name
         );
-        (manifest, guard) = Manifest::from_default(&manifest_name)?;
+        (manifest, guard, generated) = Manifest::from_default(&manifest_name)?;
         manifest.save()?;
     }
 
@@ -50,5 +52,5 @@ pub fn init_cli(manifest_name: &str) -> std::result::Result<(Manifest, tracing_a
     );
 
 
-    Ok((manifest, guard, ctx))
+    Ok((manifest, guard, ctx, generated))
 }
@@ -1,10 +1,8 @@
-use crate::config::hostname;
+use crate::config::{HashAlgorithm, Secret, hash_password};
 use crate::logging;
+use crate::telemetry;
 use anyhow::Context;
-use chrono::prelude::*;
-use digest::Digest;
 use serde::{Deserialize, Serialize};
-use sha2;
 use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
@@ -17,16 +15,6 @@ static DEFAULT_PERF_ADDR: &str = "0.0.0.0:4000";
 static DEFAULT_HTTP_ADDR: &str = "0.0.0.0:7878";
 static DEFAULT_HTTPS_ADDR: &str = "0.0.0.0:3878";
 
-// Toy example, do not use it in practice!
-// Instead use crates from: https://github.com/RustCrypto/password-hashing
-fn hash_password<D: Digest>(password: &str, salt: &str, output: &mut [u8]) {
-    let mut hasher = D::new();
-    hasher.update(password.as_bytes());
-    hasher.update(b"$");
-    hasher.update(salt.as_bytes());
-    output.copy_from_slice(hasher.finalize().as_slice())
-}
-
 // ManifestName is the manifest file name used by dep.
 // pub const MANIFEST_NAME: &str = "config.toml";
 
@@ -38,9 +26,15 @@ pub struct Manifest {
     #[serde(default)]
     pub tls_dir: PathBuf,
     #[serde(default)]
+    pub acme: Acme,
+    #[serde(default)]
     pub chips_filepath: PathBuf,
     #[serde(default)]
     pub sensors_filepath: PathBuf,
+    /// how often the chips and sensors published at an http url are checked
+    /// for changes, zero disables the polling
+    #[serde(default = "default_registry_poll_secs")]
+    pub registry_poll_secs: u64,
     #[serde(default)]
     pub sensor_data_dir: PathBuf,
     pub perf: PerfConfig,
@@ -52,53 +46,64 @@ pub struct Manifest {
     pub influxdb: InfluxDB,
     pub influxdb3: InfluxDB3,
     pub questdb: QuestDB,
+    #[serde(default)]
+    pub mqtt: Mqtt,
+    #[serde(default)]
+    pub admin: Admin,
+    #[serde(default)]
+    pub pending: Pending,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub geo: Geo,
     pub logins: Vec<Login>,
     pub measure_name_to_field: HashMap<String, String>,
     pub measure_name_to_sensor_type: HashMap<String, String>,
 }
 
+fn default_registry_poll_secs() -> u64 {
+    300
+}
+
+/// The username and password of a login generated with the default manifest.
+pub type GeneratedLogin = (String, String);
+
 impl Manifest {
+    /// The default manifest with the logins it generated, only their hash is kept.
     pub fn from_default(
         path: &str,
-    ) -> std::result::Result<(Manifest, tracing_appender::non_blocking::WorkerGuard), anyhow::Error>
-    {
+    ) -> std::result::Result<
+        (
+            Manifest,
+            tracing_appender::non_blocking::WorkerGuard,
+            Vec<GeneratedLogin>,
+        ),
+        anyhow::Error,
+    > {
         let mut manifest: Manifest = Default::default();
         manifest.path = PathBuf::from(path);
         manifest.tls_dir = PathBuf::from("tls");
         manifest.chips_filepath = PathBuf::from("chips.csv");
         manifest.sensors_filepath = PathBuf::from("sensors.csv");
+        manifest.registry_poll_secs = default_registry_poll_secs();
         manifest.sensor_data_dir = PathBuf::from("./chip_data");
         // manifest.sensors_filepath = PathBuf::from("sensors.csv");
 
         manifest.http_addr = DEFAULT_HTTP_ADDR.to_string();
         manifest.https_addr = DEFAULT_HTTPS_ADDR.to_string();
 
-        let mut buf = [0u8; 32];
-        // Create a normal DateTime from the NaiveDateTime
-        let datetime = Utc::now().format("%Y-%m-%d %H:%M:%S");
-
-        // Get an RNG:
-
+        // random passwords, stored hashed and shown once
         use rand::distr::{Alphanumeric, SampleString};
-        let salt = Alphanumeric.sample_string(&mut rand::rng(), 16);
-        let salt = salt + &hostname::gethostname().to_string_lossy();
-
-        hash_password::<sha2::Sha256>(&datetime.to_string(), &salt, &mut buf);
-
-        let hex: String = buf
-            .iter()
-            .map(|b| format!("{:x}", b).to_string())
-            .collect::<Vec<String>>()
-            .join("");
-
-        manifest.logins.push(Login {
-            username: "myuserA".to_owned(),
-            password: hex.clone() + "_A",
-        });
-        manifest.logins.push(Login {
-            username: "myuserB".to_owned(),
-            password: hex + "_B",
-        });
+        let mut generated = vec![];
+        for username in ["myuserA", "myuserB"] {
+            let password = Alphanumeric.sample_string(&mut rand::rng(), 24);
+            manifest.logins.push(Login {
+                username: username.to_owned(),
+                password: Secret::new(hash_password(&password, HashAlgorithm::Argon2)?),
+                chips: vec![],
+            });
+            generated.push((username.to_owned(), password));
+        }
 
         let hash = &mut manifest.measure_name_to_field;
         hash.insert("SDS_P1".to_owned(), "P1".to_owned());
@@ -123,7 +128,7 @@ impl Manifest {
         hash.insert("BME280_pressure".to_owned(), "BME280".to_owned());
 
         let guard = manifest.logging.setup()?;
-        Ok((manifest, guard))
+        Ok((manifest, guard, generated))
     }
 
     pub fn load(
@@ -144,6 +149,14 @@ impl Manifest {
         Ok(())
     }
 
+    /// The known sensor types, as mapped from the measure names.
+    pub fn sensor_types(&self) -> Vec<String> {
+        let mut types: Vec<String> = self.measure_name_to_sensor_type.values().cloned().collect();
+        types.sort();
+        types.dedup();
+        types
+    }
+
     pub fn change_log_level(&mut self, level: &str) -> std::result::Result<(), anyhow::Error> {
         if let Some(reload_fn) = &mut self.logging.reload_fn.0 {
             reload_fn(level)?;
@@ -190,9 +203,27 @@ pub struct Logging {
     pub compress: bool, //`toml:"compress"`
     #[serde(rename = "level", default)] //  = Some("localhost:1883")
     pub level: String, // `toml:"mqtt-addr"`
+    /// OTLP/HTTP collector endpoint, e.g. http://localhost:4318, empty to disable
+    #[serde(rename = "otlp-endpoint", default)]
+    pub otlp_endpoint: String,
+    #[serde(rename = "otlp-service-name", default = "default_otlp_service_name")]
+    pub otlp_service_name: String,
+    /// level of the spans and logs exported to the collector
+    #[serde(rename = "otlp-level", default = "default_otlp_level")]
+    pub otlp_level: String,
 
     #[serde(skip_serializing, skip_deserializing)]
     reload_fn: ReloadFn,
+    #[serde(skip_serializing, skip_deserializing)]
+    telemetry: Option<telemetry::Telemetry>,
+}
+
+fn default_otlp_service_name() -> String {
+    "dataingester".to_owned()
+}
+
+fn default_otlp_level() -> String {
+    "info".to_owned()
 }
 
 impl Default for Logging {
@@ -205,7 +236,11 @@ impl Default for Logging {
             max_age_days: 30,
             compress: false,
             level: "ERROR".to_string(),
+            otlp_endpoint: "".to_owned(),
+            otlp_service_name: default_otlp_service_name(),
+            otlp_level: default_otlp_level(),
             reload_fn: ReloadFn(None),
+            telemetry: None,
         }
     }
 }
@@ -258,10 +293,21 @@ impl Logging {
 
         // let console_layer = console_subscriber::spawn();
 
+        let otlp_layer = match self.otlp_endpoint.trim() {
+            "" => None,
+            endpoint => {
+                let (layer, telemetry) =
+                    telemetry::otlp_layer(endpoint, &self.otlp_service_name, &self.otlp_level)?;
+                self.telemetry = Some(telemetry);
+                Some(layer)
+            }
+        };
+
         tracing_subscriber::registry()
             // add the console layer to the subscriber
             // .with(console_layer)
             // add other layers...
+            .with(otlp_layer)
             .with(filtered_layer)
             // .with(...)
             .try_init()
@@ -277,6 +323,13 @@ impl Logging {
 
         Ok(guard)
     }
+
+    /// Flushes the spans, logs and metrics still buffered for the OTLP collector.
+    pub fn shutdown_telemetry(&self) {
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.shutdown();
+        }
+    }
 }
 
 impl Default for PerfConfig {
@@ -284,34 +337,46 @@ impl Default for PerfConfig {
         PerfConfig {
             enabled: false,
             perf_addr: String::from(DEFAULT_PERF_ADDR),
+            pm_gauges: false,
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct PerfConfig {
     #[serde(rename = "enabled", default)]
     pub enabled: bool,
     #[serde(rename = "perf-addr", default)]
     pub perf_addr: String,
+    /// export the latest PM values per sensor as gauges
+    #[serde(rename = "pm-gauges", default)]
+    pub pm_gauges: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Login {
     #[serde(default)]
     pub username: String,
     #[serde(default)]
-    pub password: String,
+    pub password: Secret,
+    /// the chip ids the login may post data for, any chip when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chips: Vec<String>,
 }
 
 impl Default for InfluxDB {
     fn default() -> Self {
         InfluxDB {
             url: "".to_owned(),
-            token: "".to_owned(),
+            token: Secret::default(),
             org: "".to_owned(),
             bucket: "mypassword".to_owned(),
             measurement: "".to_owned(),
+            schema: Schema::default(),
+            coordinates_as_fields: false,
+            sensor_info_measurement: "".to_owned(),
+            sensor_info_bucket: "".to_owned(),
+            tls: BackendTls::default(),
         }
     }
 }
@@ -321,36 +386,141 @@ pub struct InfluxDB {
     #[serde(default)]
     pub url: String,
     #[serde(default)]
-    pub token: String,
+    pub token: Secret,
     #[serde(default)]
     pub org: String,
     #[serde(default)]
     pub bucket: String,
     #[serde(default)]
     pub measurement: String,
+    #[serde(default)]
+    pub schema: Schema,
+    /// deprecated, the same as `lat` and `lon` in `schema.fields`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub coordinates_as_fields: bool,
+    /// measurement with a point per sensor, disabled when empty; with it the
+    /// chip metadata can be dropped from the measurement tags. It is rewritten
+    /// on each refresh, after deleting its points
+    #[serde(default)]
+    pub sensor_info_measurement: String,
+    /// bucket of the sensor info when not the bucket, the points of open periods
+    /// are at the epoch so it must keep its data forever
+    #[serde(default)]
+    pub sensor_info_bucket: String,
+    #[serde(default)]
+    pub tls: BackendTls,
 }
 
 impl Default for InfluxDB3 {
     fn default() -> Self {
         InfluxDB3 {
             url: "".to_owned(),
-            token: "".to_owned(),
+            token: Secret::default(),
             database: "mydb".to_owned(),
             table: "".to_owned(),
+            schema: Schema::default(),
+            coordinates_as_fields: false,
+            sensor_info_table: "".to_owned(),
+            precision: Precision::default(),
+            accept_partial: default_accept_partial(),
+            no_sync: false,
+            last_caches: vec![],
+            distinct_caches: vec![],
+            tls: BackendTls::default(),
         }
     }
 }
 
+/// Settings of the InfluxDB 3 native write api, `/api/v3/write_lp`.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct InfluxDB3 {
     #[serde(default)]
     pub url: String,
     #[serde(default)]
-    pub token: String,
+    pub token: Secret,
     #[serde(default)]
     pub database: String,
     #[serde(default)]
     pub table: String,
+    #[serde(default)]
+    pub schema: Schema,
+    /// deprecated, the same as `lat` and `lon` in `schema.fields`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub coordinates_as_fields: bool,
+    /// table with a row per sensor, disabled when empty; with it the chip
+    /// metadata can be dropped from the table tags. Rows can't be deleted, so
+    /// `valid_to` is 0 for the open periods and `removed` is true for the periods
+    /// no longer in the registry
+    #[serde(default)]
+    pub sensor_info_table: String,
+    /// precision of the written timestamps
+    #[serde(default)]
+    pub precision: Precision,
+    /// write the valid lines of a batch when some are rejected
+    #[serde(default = "default_accept_partial")]
+    pub accept_partial: bool,
+    /// acknowledge the writes before they are persisted to the WAL
+    #[serde(default)]
+    pub no_sync: bool,
+    /// last value caches, created at start or, when the table is missing, after
+    /// the writes with a growing delay
+    #[serde(default)]
+    pub last_caches: Vec<LastCache>,
+    /// distinct value caches, created like the last value caches
+    #[serde(default)]
+    pub distinct_caches: Vec<DistinctCache>,
+    #[serde(default)]
+    pub tls: BackendTls,
+}
+
+fn default_accept_partial() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    Second,
+    Millisecond,
+    Microsecond,
+    Nanosecond,
+}
+
+/// A last value cache of InfluxDB 3 Core, the server defaults apply to the
+/// missing settings.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct LastCache {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// the measurement table when missing
+    #[serde(default)]
+    pub table: Option<String>,
+    #[serde(default)]
+    pub key_columns: Option<Vec<String>>,
+    #[serde(default)]
+    pub value_columns: Option<Vec<String>>,
+    /// values kept per key
+    #[serde(default)]
+    pub count: Option<usize>,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// A distinct value cache of InfluxDB 3 Core, e.g. of `chip_id` and `sensor_id`
+/// to list the stations.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct DistinctCache {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// the measurement table when missing
+    #[serde(default)]
+    pub table: Option<String>,
+    pub columns: Vec<String>,
+    #[serde(default)]
+    pub max_cardinality: Option<usize>,
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -362,26 +532,437 @@ pub struct QuestDB {
     #[serde(default)]
     pub username: String,
     #[serde(default)]
-    pub password: String,
+    pub password: Secret,
     #[serde(default)]
     pub table: String,
     #[serde(default = "default_sensor_info_table")]
     pub sensor_info_table: String,
+    #[serde(default)]
+    pub schema: Schema,
+    /// deprecated, the same as `lat` and `lon` in `schema.fields`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub coordinates_as_fields: bool,
+    /// partitioning of the table when it is created: HOUR, DAY, WEEK, MONTH or YEAR
+    #[serde(default = "default_partition_by")]
+    pub partition_by: String,
+    /// retention of the table, e.g. `30 DAYS`, none is set when empty
+    #[serde(default)]
+    pub ttl: String,
+    /// deduplicate the rows on timestamp, sensor_id and field (timestamp and
+    /// chip_id in the wide layout), so that resent payloads are upserted
+    #[serde(default = "default_dedup")]
+    pub dedup: bool,
+    /// table recording the applied schema migrations of the tables
+    #[serde(default = "default_migrations_table")]
+    pub migrations_table: String,
+    /// protocol of the schema and sensor info statements
+    #[serde(default)]
+    pub sql_protocol: SqlProtocol,
+    /// PostgreSQL wire address, the host of addr with port 8812 when empty
+    #[serde(default)]
+    pub pg_addr: String,
+    /// TLS of the REST, PostgreSQL wire and ILP connections when use_https is set
+    #[serde(default)]
+    pub tls: BackendTls,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SqlProtocol {
+    /// the `/exec` endpoint of the REST api at addr, the sensor info is
+    /// written with ILP
+    #[default]
+    Rest,
+    /// the PostgreSQL wire protocol with bound parameters, the sensor info is
+    /// written with INSERT statements
+    Pg,
 }
 
 fn default_sensor_info_table() -> String {
     "sensor_info".to_owned()
 }
 
+fn default_partition_by() -> String {
+    "DAY".to_owned()
+}
+
+fn default_dedup() -> bool {
+    true
+}
+
+fn default_migrations_table() -> String {
+    "schema_migrations".to_owned()
+}
+
 impl Default for QuestDB {
     fn default() -> Self {
         QuestDB {
             use_https: true,
             addr: "".to_owned(),
             username: "".to_owned(),
-            password: "".to_owned(),
+            password: Secret::default(),
             table: "".to_owned(),
             sensor_info_table: default_sensor_info_table(),
+            schema: Schema::default(),
+            coordinates_as_fields: false,
+            partition_by: default_partition_by(),
+            ttl: "".to_owned(),
+            dedup: default_dedup(),
+            migrations_table: default_migrations_table(),
+            sql_protocol: SqlProtocol::default(),
+            pg_addr: "".to_owned(),
+            tls: BackendTls::default(),
+        }
+    }
+}
+
+/// TLS settings of the connections to a backend.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct BackendTls {
+    /// verify the backend certificate, when off any certificate is accepted as
+    /// earlier versions did
+    #[serde(default)]
+    pub verify: bool,
+    /// PEM bundle of the trusted CAs replacing the built-in ones, e.g. of a proxy
+    /// with a self-signed certificate. QuestDB requires it with pins, the ILP
+    /// writes are verified with it instead
+    #[serde(default)]
+    pub ca_path: Option<PathBuf>,
+    /// PEM client certificate for mTLS, with its key unless client_key_path is set
+    #[serde(default)]
+    pub client_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub client_key_path: Option<PathBuf>,
+    /// SHA-256 fingerprints of the accepted certificates, as printed by
+    /// `openssl x509 -noout -fingerprint -sha256`; when set any other certificate
+    /// is refused whatever its issuer
+    #[serde(default)]
+    pub pins: Vec<String>,
+}
+
+/// How the points of a backend are laid out and named. The metadata are `chip_id`,
+/// `city`, `lat`, `lon`, `info`, `sensor_id`, `sensor_type` and the tags added by
+/// the geo enrichment.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Schema {
+    #[serde(default)]
+    pub layout: Layout,
+    /// metadata written as tags (symbols in QuestDB), when missing all the
+    /// metadata not written as fields
+    #[serde(default)]
+    pub tags: Option<Vec<String>>,
+    /// metadata written as fields (columns in QuestDB), e.g. `["lat", "lon"]`
+    /// to avoid a series per position
+    #[serde(default)]
+    pub fields: Vec<String>,
+    /// written names of metadata and values, e.g. `{ P1 = "pm10" }`
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+}
+
+impl Schema {
+    /// The schema with the deprecated `coordinates_as_fields` of a backend applied.
+    pub fn with_coordinates_as_fields(&self, backend: &str, coordinates_as_fields: bool) -> Schema {
+        let mut schema = self.clone();
+        if coordinates_as_fields {
+            tracing::warn!(
+                "{backend}: coordinates_as_fields is deprecated, use schema.fields = [\"lat\", \"lon\"]"
+            );
+            for name in ["lat", "lon"] {
+                if !schema.fields.iter().any(|f| f == name) {
+                    schema.fields.push(name.to_owned());
+                }
+            }
+        }
+        schema
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// a point per value, tagged with its sensor; in QuestDB the value name
+    /// is the `field` symbol and the value the `value` column
+    #[default]
+    Narrow,
+    /// a point per chip with a field per value and a `sensor_id_<sensor type>`
+    /// per sensor, the values of the same name from sensors of different types
+    /// written as `<name>_<sensor type>`;
+    /// QuestDB gets the missing columns added with `ALTER TABLE` before the
+    /// write, InfluxDB 3 adds them on write
+    Wide,
+}
+
+/// Admin api settings, the api is disabled when the address is empty.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct Admin {
+    #[serde(default)]
+    pub addr: String,
+    #[serde(default)]
+    pub logins: Vec<Login>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Mqtt {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub use_tls: bool,
+    /// broker address as host:port
+    #[serde(default)]
+    pub addr: String,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: Secret,
+    /// topic filter with a single `+` wildcard matching the chip id,
+    /// e.g. `sensorcommunity/+/data`
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+}
+
+fn default_mqtt_client_id() -> String {
+    "dataingester".to_owned()
+}
+
+fn default_mqtt_topic() -> String {
+    "sensorcommunity/+/data".to_owned()
+}
+
+impl Default for Mqtt {
+    fn default() -> Self {
+        Mqtt {
+            enabled: false,
+            use_tls: false,
+            addr: "".to_owned(),
+            client_id: default_mqtt_client_id(),
+            username: "".to_owned(),
+            password: Secret::default(),
+            topic: default_mqtt_topic(),
+        }
+    }
+}
+
+/// Where the payloads of chips missing from the chips file are held until approved.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Pending {
+    /// an empty folder disables holding, the payloads are dropped
+    #[serde(default = "default_pending_dir")]
+    pub dir: PathBuf,
+    #[serde(default = "default_pending_max_chips")]
+    pub max_chips: usize,
+    /// about a week of payloads sent every 5 minutes
+    #[serde(default = "default_pending_max_payloads")]
+    pub max_payloads_per_chip: usize,
+}
+
+fn default_pending_dir() -> PathBuf {
+    PathBuf::from("./pending_chips")
+}
+
+fn default_pending_max_chips() -> usize {
+    100
+}
+
+fn default_pending_max_payloads() -> usize {
+    2016
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Pending {
+            dir: default_pending_dir(),
+            max_chips: default_pending_max_chips(),
+            max_payloads_per_chip: default_pending_max_payloads(),
+        }
+    }
+}
+
+/// Certificates of the https listener issued and renewed through ACME, stored as
+/// `cert.pem` and `key.pem` in `tls_dir`; disabled without domains.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Acme {
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// emails for the expiry notices of the CA
+    #[serde(default)]
+    pub contacts: Vec<String>,
+    /// agreement to the terms of service of the CA, required to register the
+    /// account
+    #[serde(default)]
+    pub accept_terms: bool,
+    /// Let's Encrypt by default, `https://localhost:14000/dir` for a local
+    /// Pebble server
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    /// CA bundle verifying the directory, such as the `pebble.minica.pem` of
+    /// Pebble
+    #[serde(default)]
+    pub ca_path: Option<PathBuf>,
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    #[serde(default = "default_acme_renew_days")]
+    pub renew_days_before_expiry: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AcmeChallenge {
+    /// answered by the http listener redirecting to https, it must be
+    /// reachable on port 80
+    #[default]
+    Http01,
+    /// answered by the https listener, it must be reachable on port 443
+    TlsAlpn01,
+}
+
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+fn default_acme_renew_days() -> u64 {
+    30
+}
+
+impl Default for Acme {
+    fn default() -> Self {
+        Acme {
+            domains: vec![],
+            contacts: vec![],
+            accept_terms: false,
+            directory_url: default_acme_directory_url(),
+            ca_path: None,
+            challenge: AcmeChallenge::default(),
+            renew_days_before_expiry: default_acme_renew_days(),
+        }
+    }
+}
+
+/// Limits of the `/write` endpoint, a zero rate or a zero failure count disables
+/// the limit.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Limits {
+    /// token bucket per client address, refilled at the rate and holding burst
+    /// tokens
+    #[serde(default = "default_per_ip_per_minute")]
+    pub per_ip_per_minute: u32,
+    #[serde(default = "default_per_ip_burst")]
+    pub per_ip_burst: u32,
+    /// token bucket per chip, a node sends about every 145 seconds
+    #[serde(default = "default_per_chip_per_minute")]
+    pub per_chip_per_minute: u32,
+    #[serde(default = "default_per_chip_burst")]
+    pub per_chip_burst: u32,
+    /// failed logins of an address within the window that ban it
+    #[serde(default = "default_max_auth_failures")]
+    pub max_auth_failures: u32,
+    #[serde(default = "default_auth_failure_window_secs")]
+    pub auth_failure_window_secs: u64,
+    #[serde(default = "default_ban_secs")]
+    pub ban_secs: u64,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_per_ip_per_minute() -> u32 {
+    60
+}
+
+fn default_per_ip_burst() -> u32 {
+    30
+}
+
+fn default_per_chip_per_minute() -> u32 {
+    6
+}
+
+fn default_per_chip_burst() -> u32 {
+    10
+}
+
+fn default_max_auth_failures() -> u32 {
+    10
+}
+
+fn default_auth_failure_window_secs() -> u64 {
+    600
+}
+
+fn default_ban_secs() -> u64 {
+    3600
+}
+
+fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            per_ip_per_minute: default_per_ip_per_minute(),
+            per_ip_burst: default_per_ip_burst(),
+            per_chip_per_minute: default_per_chip_per_minute(),
+            per_chip_burst: default_per_chip_burst(),
+            max_auth_failures: default_max_auth_failures(),
+            auth_failure_window_secs: default_auth_failure_window_secs(),
+            ban_secs: default_ban_secs(),
+            max_body_bytes: default_max_body_bytes(),
+        }
+    }
+}
+
+/// Files used to resolve the municipality, province and elevation of the chips
+/// and the spatial index cells tagged, see [crate::geodata]. No data is bundled,
+/// the tags are off until the files are converted and configured as described
+/// in the README.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Geo {
+    /// GeoJSON municipal boundaries in WGS84
+    #[serde(default)]
+    pub boundaries_path: PathBuf,
+    /// DEM tile in the ESRI ASCII grid format
+    #[serde(default)]
+    pub dem_path: PathBuf,
+    #[serde(default = "default_municipality_property")]
+    pub municipality_property: String,
+    #[serde(default = "default_istat_code_property")]
+    pub istat_code_property: String,
+    #[serde(default = "default_province_property")]
+    pub province_property: String,
+    /// lengths of the geohash tags added to every point, e.g. `[5, 7]` adds
+    /// `geohash5` and `geohash7`, from 1 to 12
+    #[serde(default)]
+    pub geohash_precisions: Vec<usize>,
+    /// resolutions of the H3 cell tags added to every point, e.g. `[7]` adds `h3_7`,
+    /// from 0 to 15
+    #[serde(default)]
+    pub h3_resolutions: Vec<u8>,
+}
+
+fn default_municipality_property() -> String {
+    "COMUNE".to_owned()
+}
+
+fn default_istat_code_property() -> String {
+    "PRO_COM_T".to_owned()
+}
+
+fn default_province_property() -> String {
+    "COD_PROV".to_owned()
+}
+
+impl Default for Geo {
+    fn default() -> Self {
+        Geo {
+            boundaries_path: PathBuf::new(),
+            dem_path: PathBuf::new(),
+            municipality_property: default_municipality_property(),
+            istat_code_property: default_istat_code_property(),
+            province_property: default_province_property(),
+            geohash_precisions: vec![],
+            h3_resolutions: vec![],
         }
     }
 }
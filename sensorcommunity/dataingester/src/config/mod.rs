@@ -1,7 +1,16 @@
-mod hostname;
 mod init;
 mod manifest;
+mod password;
+mod secret;
 
 pub use clap::{Arg, Command, crate_version};
 pub use init::*;
-pub use manifest::{InfluxDB, InfluxDB3, Manifest, QuestDB};
+pub use manifest::{
+    Acme, AcmeChallenge, BackendTls, Geo, InfluxDB, InfluxDB3, Layout, Limits, Manifest, Mqtt,
+    Pending, PerfConfig, Precision, QuestDB, Schema, SqlProtocol,
+};
+pub use password::{
+    HashAlgorithm, LoginError, StoredPassword, hash_password, login_chips, login_passwords,
+    verify_login,
+};
+pub use secret::Secret;
@@ -0,0 +1,219 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::LazyLock;
+
+use anyhow::anyhow;
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use subtle::ConstantTimeEq;
+
+use super::manifest::Login;
+
+const BCRYPT_PREFIXES: [&str; 4] = ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    Argon2,
+    Bcrypt,
+}
+
+/// The password of a login as written in the manifest: an Argon2 PHC string, a
+/// bcrypt hash or plaintext, still accepted for the manifests written before
+/// the hashes.
+#[derive(Clone, PartialEq)]
+pub enum StoredPassword {
+    Argon2(String),
+    Bcrypt(String),
+    Plain(String),
+}
+
+impl StoredPassword {
+    pub fn parse(stored: &str) -> Self {
+        if stored.starts_with("$argon2") {
+            StoredPassword::Argon2(stored.to_owned())
+        } else if BCRYPT_PREFIXES.iter().any(|p| stored.starts_with(p)) {
+            StoredPassword::Bcrypt(stored.to_owned())
+        } else {
+            StoredPassword::Plain(stored.trim().to_owned())
+        }
+    }
+
+    pub fn is_plain(&self) -> bool {
+        matches!(self, StoredPassword::Plain(_))
+    }
+
+    /// Verifies a password, the hashes and the plaintext are compared in
+    /// constant time. The plaintext is compared trimmed, as it always was.
+    pub fn verify(&self, password: &str) -> bool {
+        match self {
+            StoredPassword::Argon2(phc) => PasswordHash::new(phc).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+            StoredPassword::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            StoredPassword::Plain(plain) => {
+                plain.as_bytes().ct_eq(password.trim().as_bytes()).into()
+            }
+        }
+    }
+
+    /// Verifies on the blocking threads, a hash takes tens of milliseconds.
+    pub async fn verify_blocking(&self, password: &str) -> bool {
+        let stored = self.clone();
+        let password = password.to_owned();
+        tokio::task::spawn_blocking(move || stored.verify(&password))
+            .await
+            .unwrap_or(false)
+    }
+}
+
+/// Why a login was refused.
+#[derive(Debug, PartialEq)]
+pub enum LoginError {
+    UnknownUser,
+    WrongPassword,
+}
+
+/// Verified for the unknown usernames, so that they take as long as a wrong
+/// password of a login.
+static DUMMY_PASSWORD: LazyLock<StoredPassword> = LazyLock::new(|| {
+    use rand::distr::{Alphanumeric, SampleString};
+    let password = Alphanumeric.sample_string(&mut rand::rng(), 24);
+    StoredPassword::Argon2(hash_password(&password, HashAlgorithm::Argon2).unwrap_or_default())
+});
+
+/// Verifies the password of a login in the passwords by lowercase username.
+pub async fn verify_login(
+    passwords: &HashMap<String, StoredPassword>,
+    username: &str,
+    password: &str,
+) -> Result<(), LoginError> {
+    match passwords.get(&username.to_lowercase()) {
+        Some(stored) => match stored.verify_blocking(password).await {
+            true => Ok(()),
+            false => Err(LoginError::WrongPassword),
+        },
+        None => {
+            DUMMY_PASSWORD.verify_blocking(password).await;
+            Err(LoginError::UnknownUser)
+        }
+    }
+}
+
+/// Hashes a password with a random salt, Argon2id gives a PHC string.
+pub fn hash_password(password: &str, algorithm: HashAlgorithm) -> anyhow::Result<String> {
+    match algorithm {
+        HashAlgorithm::Argon2 => {
+            let salt = SaltString::generate(&mut OsRng);
+            Ok(Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map_err(|e| anyhow!("failed to hash the password: {}", e))?
+                .to_string())
+        }
+        HashAlgorithm::Bcrypt => Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?),
+    }
+}
+
+/// The stored password of each login by lowercase username, with a warning for
+/// the plaintext and the malformed ones.
+pub fn login_passwords(logins: &[Login], section: &str) -> HashMap<String, StoredPassword> {
+    let mut passwords = HashMap::new();
+    for login in logins {
+        let stored = StoredPassword::parse(login.password.expose());
+        match &stored {
+            StoredPassword::Plain(_) => tracing::warn!(
+                "the password of login '{}' in {} is plaintext, replace it with the output of `dataingester hash-password`",
+                login.username,
+                section
+            ),
+            StoredPassword::Argon2(phc) if PasswordHash::new(phc).is_err() => tracing::warn!(
+                "the password hash of login '{}' in {} is malformed, the login is refused",
+                login.username,
+                section
+            ),
+            _ => {}
+        }
+        passwords.insert(login.username.to_lowercase(), stored);
+    }
+    passwords
+}
+
+/// The chips each login scoped to a list of chips may post data for, by
+/// lowercase username.
+pub fn login_chips(logins: &[Login]) -> HashMap<String, HashSet<String>> {
+    logins
+        .iter()
+        .filter(|login| !login.chips.is_empty())
+        .map(|login| {
+            (
+                login.username.to_lowercase(),
+                login.chips.iter().map(|c| c.trim().to_owned()).collect(),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let phc = hash_password("s3cret", HashAlgorithm::Argon2).unwrap();
+        let argon2 = StoredPassword::parse(&phc);
+        assert!(matches!(argon2, StoredPassword::Argon2(_)));
+        assert!(argon2.verify("s3cret"));
+        assert!(!argon2.verify("s3cret "));
+
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        let bcrypt = StoredPassword::parse(&hash);
+        assert!(matches!(bcrypt, StoredPassword::Bcrypt(_)));
+        assert!(bcrypt.verify("s3cret"));
+        assert!(!bcrypt.verify("other"));
+
+        let plain = StoredPassword::parse(" s3cret");
+        assert!(plain.is_plain());
+        assert!(plain.verify("s3cret "));
+        assert!(!plain.verify("s3cre"));
+
+        assert!(!StoredPassword::parse("$argon2id$malformed").verify("s3cret"));
+    }
+
+    #[tokio::test]
+    async fn test_verify_login() {
+        let phc = hash_password("s3cret", HashAlgorithm::Argon2).unwrap();
+        let passwords = HashMap::from([("station".to_owned(), StoredPassword::parse(&phc))]);
+        assert_eq!(verify_login(&passwords, "Station", "s3cret").await, Ok(()));
+        assert_eq!(
+            verify_login(&passwords, "station", "other").await,
+            Err(LoginError::WrongPassword)
+        );
+        assert_eq!(
+            verify_login(&passwords, "other", "s3cret").await,
+            Err(LoginError::UnknownUser)
+        );
+    }
+
+    #[test]
+    fn test_login_chips() {
+        let logins = vec![
+            Login {
+                username: "Station".to_owned(),
+                password: Default::default(),
+                chips: vec!["esp8266-1".to_owned(), " esp8266-2 ".to_owned()],
+            },
+            Login {
+                username: "shared".to_owned(),
+                password: Default::default(),
+                chips: vec![],
+            },
+        ];
+        let chips = login_chips(&logins);
+        assert_eq!(chips.len(), 1);
+        assert_eq!(
+            chips["station"],
+            HashSet::from(["esp8266-1".to_owned(), "esp8266-2".to_owned()])
+        );
+    }
+}
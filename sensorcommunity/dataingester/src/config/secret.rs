@@ -0,0 +1,162 @@
+use std::{cell::Cell, env, fmt, fs, path::PathBuf};
+
+use anyhow::{Context, anyhow};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const MASKED: &str = "********";
+
+thread_local! {
+    static MASK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A password or token of the manifest, written inline or as a reference
+/// resolved at load:
+///
+/// - `env:NAME` the environment variable `NAME`
+/// - `file:/run/secrets/token` the content of the file
+/// - `credential:NAME` the credential `NAME` passed by systemd with
+///   `LoadCredential=`, read from `$CREDENTIALS_DIRECTORY`
+///
+/// A trailing newline of a file is dropped. The manifest is saved with the
+/// reference, never with the resolved value.
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret {
+    reference: String,
+    value: String,
+}
+
+impl Secret {
+    /// A secret written inline in the manifest.
+    pub fn new(value: impl Into<String>) -> Self {
+        let value = value.into();
+        Secret {
+            reference: value.clone(),
+            value,
+        }
+    }
+
+    /// Resolves a reference, any other text is the secret itself.
+    pub fn resolve(reference: &str) -> anyhow::Result<Self> {
+        let value = if let Some(name) = reference.strip_prefix("env:") {
+            env::var(name).with_context(|| format!("secret {}", reference))?
+        } else if let Some(path) = reference.strip_prefix("file:") {
+            read(PathBuf::from(path), reference)?
+        } else if let Some(name) = reference.strip_prefix("credential:") {
+            let dir = env::var_os("CREDENTIALS_DIRECTORY").ok_or(anyhow!(
+                "secret {}: CREDENTIALS_DIRECTORY is not set, is LoadCredential= configured?",
+                reference
+            ))?;
+            read(PathBuf::from(dir).join(name), reference)?
+        } else {
+            reference.to_owned()
+        };
+        Ok(Secret {
+            reference: reference.to_owned(),
+            value,
+        })
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.value
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.value.is_empty()
+    }
+
+    /// Runs `f` with the inline secrets serialized masked, to show a manifest
+    /// without its passwords and tokens. The references are kept, they name
+    /// where the secret is and not the secret.
+    pub fn masked<T>(f: impl FnOnce() -> T) -> T {
+        MASK.with(|mask| mask.set(true));
+        let result = f();
+        MASK.with(|mask| mask.set(false));
+        result
+    }
+
+    /// Whether the secret is written inline in the manifest.
+    pub fn is_inline(&self) -> bool {
+        !["env:", "file:", "credential:"]
+            .iter()
+            .any(|prefix| self.reference.starts_with(prefix))
+    }
+}
+
+fn read(path: PathBuf, reference: &str) -> anyhow::Result<String> {
+    let value = fs::read_to_string(&path)
+        .with_context(|| format!("secret {}: failed to read {}", reference, path.display()))?;
+    Ok(value
+        .strip_suffix('\n')
+        .map(|v| v.strip_suffix('\r').unwrap_or(v))
+        .unwrap_or(&value)
+        .to_owned())
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.is_inline() {
+            true => write!(f, "Secret(***)"),
+            false => write!(f, "Secret({})", self.reference),
+        }
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if MASK.with(Cell::get) && self.is_inline() && !self.reference.is_empty() {
+            return serializer.serialize_str(MASKED);
+        }
+        serializer.serialize_str(&self.reference)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let reference = String::deserialize(deserializer)?;
+        Secret::resolve(&reference).map_err(|e| serde::de::Error::custom(format!("{:#}", e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_resolve() {
+        let dir = TempDir::new("secret").unwrap();
+        let path = dir.path().join("token");
+        fs::write(&path, "s3cret\n").unwrap();
+
+        let file_ref = format!("file:{}", path.display());
+        let secret = Secret::resolve(&file_ref).unwrap();
+        assert_eq!(secret.expose(), "s3cret");
+        assert!(!secret.is_inline());
+        // saved with the reference
+        assert_eq!(
+            toml::to_string(&HashMap::from([("token", &secret)])).unwrap(),
+            format!("token = \"{}\"\n", file_ref)
+        );
+        assert_eq!(format!("{:?}", secret), format!("Secret({})", file_ref));
+
+        let inline = Secret::resolve("plain").unwrap();
+        assert_eq!(inline.expose(), "plain");
+        assert_eq!(format!("{:?}", inline), "Secret(***)");
+        let masked = Secret::masked(|| {
+            serde_json::to_value(HashMap::from([
+                ("file", &secret),
+                ("inline", &inline),
+                ("empty", &Secret::default()),
+            ]))
+            .unwrap()
+        });
+        assert_eq!(masked["file"], file_ref.as_str());
+        assert_eq!(masked["inline"], MASKED);
+        assert_eq!(masked["empty"], "");
+        assert_eq!(serde_json::to_value(&inline).unwrap(), "plain");
+
+        assert!(Secret::resolve("env:DATAINGESTER_TEST_MISSING_SECRET").is_err());
+        assert!(Secret::resolve("file:/nonexistent/secret").is_err());
+    }
+}
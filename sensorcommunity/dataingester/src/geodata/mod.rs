@@ -0,0 +1,310 @@
+//! Resolves the municipality and province of a chip from its coordinates, and
+//! optionally its elevation, so the places are spelled the same way everywhere.
+//! The geohash and H3 cells of the position can be added too, to group the points
+//! by area without tagging the raw coordinates.
+//!
+//! The boundaries are a GeoJSON file in WGS84 (EPSG:4326), e.g. the ISTAT municipal
+//! boundaries of Veneto converted with
+//! `ogr2ogr -f GeoJSON -t_srs EPSG:4326 -where "COD_REG=5" comuni_veneto.geojson Com01012024_WGS84.shp`.
+//! The elevation is read from a DEM tile in the ESRI ASCII grid format, in the same
+//! coordinates, e.g. `gdal_translate -of AAIGrid -a_srs EPSG:4326 dem.tif dem.asc`.
+
+use std::{fs, path::Path};
+
+use anyhow::{Result, anyhow};
+use geo::{BoundingRect, Contains, MultiPolygon, Point, Rect};
+
+use crate::sensor_data::{ELEVATION, GEOHASH, H3, ISTAT_CODE, MUNICIPALITY, PROVINCE};
+
+struct Boundary {
+    bbox: Rect<f64>,
+    shape: MultiPolygon<f64>,
+    municipality: String,
+    istat_code: String,
+    province: String,
+}
+
+/// Elevation model on a regular grid, rows from north to south.
+struct Dem {
+    ncols: usize,
+    nrows: usize,
+    /// lower left corner of the grid
+    x0: f64,
+    y0: f64,
+    cellsize: f64,
+    nodata: f64,
+    values: Vec<f64>,
+}
+
+impl Dem {
+    fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        let mut header = std::collections::HashMap::new();
+        for _ in 0..6 {
+            let line = lines.next().ok_or_else(|| anyhow!("truncated header"))?;
+            let mut parts = line.split_whitespace();
+            if let (Some(k), Some(v)) = (parts.next(), parts.next()) {
+                header.insert(k.to_lowercase(), v.parse::<f64>()?);
+            }
+        }
+        let get = |k: &str| {
+            header
+                .get(k)
+                .copied()
+                .ok_or_else(|| anyhow!("missing {} in the header", k))
+        };
+        let ncols = get("ncols")? as usize;
+        let nrows = get("nrows")? as usize;
+        let cellsize = get("cellsize")?;
+        // the corner may be given as the center of the lower left cell
+        let (x0, y0) = match (get("xllcorner"), get("yllcorner")) {
+            (Ok(x), Ok(y)) => (x, y),
+            _ => (
+                get("xllcenter")? - cellsize / 2.0,
+                get("yllcenter")? - cellsize / 2.0,
+            ),
+        };
+        let nodata = get("nodata_value").unwrap_or(-9999.0);
+
+        let values = lines
+            .flat_map(str::split_whitespace)
+            .map(str::parse::<f64>)
+            .collect::<std::result::Result<Vec<f64>, _>>()?;
+        if values.len() != ncols * nrows {
+            return Err(anyhow!(
+                "expected {} values, found {}",
+                ncols * nrows,
+                values.len()
+            ));
+        }
+        Ok(Dem {
+            ncols,
+            nrows,
+            x0,
+            y0,
+            cellsize,
+            nodata,
+            values,
+        })
+    }
+
+    fn elevation(&self, lat: f64, lon: f64) -> Option<f64> {
+        let col = ((lon - self.x0) / self.cellsize).floor();
+        let row_from_south = ((lat - self.y0) / self.cellsize).floor();
+        if col < 0.0 || row_from_south < 0.0 {
+            return None;
+        }
+        let (col, row_from_south) = (col as usize, row_from_south as usize);
+        if col >= self.ncols || row_from_south >= self.nrows {
+            return None;
+        }
+        let v = self.values[(self.nrows - 1 - row_from_south) * self.ncols + col];
+        (v != self.nodata).then_some(v)
+    }
+}
+
+/// Boundaries and elevation loaded once at startup, used at every registry load.
+pub struct Enricher {
+    boundaries: Vec<Boundary>,
+    dem: Option<Dem>,
+    geohash_precisions: Vec<usize>,
+    h3_resolutions: Vec<h3o::Resolution>,
+}
+
+impl Enricher {
+    /// Returns None when no file nor spatial index is configured.
+    pub fn load(settings: &crate::config::Geo) -> Result<Option<Self>> {
+        let boundaries_path = settings.boundaries_path.as_os_str().to_string_lossy();
+        let dem_path = settings.dem_path.as_os_str().to_string_lossy();
+        if boundaries_path.is_empty()
+            && dem_path.is_empty()
+            && settings.geohash_precisions.is_empty()
+            && settings.h3_resolutions.is_empty()
+        {
+            return Ok(None);
+        }
+
+        if let Some(p) = settings
+            .geohash_precisions
+            .iter()
+            .find(|p| !(1..=12).contains(*p))
+        {
+            return Err(anyhow!("invalid geohash precision {}, from 1 to 12", p));
+        }
+        let h3_resolutions = settings
+            .h3_resolutions
+            .iter()
+            .map(|r| {
+                h3o::Resolution::try_from(*r)
+                    .map_err(|_| anyhow!("invalid H3 resolution {}, from 0 to 15", r))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut boundaries = vec![];
+        if !boundaries_path.is_empty() {
+            let path = shellexpand::env(&boundaries_path)?.into_owned();
+            let geojson: geojson::GeoJson = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("error reading {}: {}", path, e))?
+                .parse()?;
+            let geojson::GeoJson::FeatureCollection(collection) = geojson else {
+                return Err(anyhow!("{} is not a feature collection", path));
+            };
+            for feature in collection.features {
+                let property = |name: &str| match feature.property(name) {
+                    Some(serde_json::Value::String(s)) => s.clone(),
+                    Some(serde_json::Value::Null) | None => String::new(),
+                    Some(v) => v.to_string(),
+                };
+                let municipality = property(&settings.municipality_property);
+                let istat_code = property(&settings.istat_code_property);
+                let province = property(&settings.province_property);
+                let Some(geometry) = feature.geometry else {
+                    continue;
+                };
+                let shape = match geo::Geometry::<f64>::try_from(geometry)? {
+                    geo::Geometry::Polygon(p) => MultiPolygon(vec![p]),
+                    geo::Geometry::MultiPolygon(mp) => mp,
+                    _ => continue,
+                };
+                let Some(bbox) = shape.bounding_rect() else {
+                    continue;
+                };
+                boundaries.push(Boundary {
+                    bbox,
+                    shape,
+                    municipality,
+                    istat_code,
+                    province,
+                });
+            }
+            tracing::info!("Loaded {} boundaries from {}", boundaries.len(), path);
+        }
+
+        let dem = if dem_path.is_empty() {
+            None
+        } else {
+            let path = shellexpand::env(&dem_path)?.into_owned();
+            let dem = Dem::load(Path::new(&path))
+                .map_err(|e| anyhow!("error reading {}: {}", path, e))?;
+            tracing::info!(
+                "Loaded a {}x{} elevation grid from {}",
+                dem.ncols,
+                dem.nrows,
+                path
+            );
+            Some(dem)
+        };
+
+        Ok(Some(Enricher {
+            boundaries,
+            dem,
+            geohash_precisions: settings.geohash_precisions.clone(),
+            h3_resolutions,
+        }))
+    }
+
+    /// The tags of a position: municipality, ISTAT code and province when inside
+    /// a boundary, elevation in meters when inside the DEM, then the spatial cells.
+    pub fn tags(&self, lat: f64, lon: f64) -> Vec<(String, String)> {
+        let point = Point::new(lon, lat);
+        let mut tags = vec![];
+        if let Some(b) = self
+            .boundaries
+            .iter()
+            .find(|b| b.bbox.contains(&point) && b.shape.contains(&point))
+        {
+            tags.push((MUNICIPALITY.to_owned(), b.municipality.clone()));
+            tags.push((ISTAT_CODE.to_owned(), b.istat_code.clone()));
+            tags.push((PROVINCE.to_owned(), b.province.clone()));
+        }
+        if let Some(elevation) = self.dem.as_ref().and_then(|d| d.elevation(lat, lon)) {
+            tags.push((ELEVATION.to_owned(), format!("{:.0}", elevation)));
+        }
+        for precision in &self.geohash_precisions {
+            if let Ok(hash) = geohash::encode(geo::coord! { x: lon, y: lat }, *precision) {
+                tags.push((format!("{}{}", GEOHASH, precision), hash));
+            }
+        }
+        if let Ok(position) = h3o::LatLng::new(lat, lon) {
+            for resolution in &self.h3_resolutions {
+                let cell = position.to_cell(*resolution);
+                tags.push((
+                    format!("{}_{}", H3, u8::from(*resolution)),
+                    cell.to_string(),
+                ));
+            }
+        }
+        tags
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_enricher_tags() {
+        let dir = TempDir::new("geo").unwrap();
+        let boundaries = dir.path().join("comuni.geojson");
+        fs::write(
+            &boundaries,
+            r#"{"type":"FeatureCollection","features":[
+                {"type":"Feature","properties":{"COMUNE":"Cittadella","PRO_COM_T":"028032","COD_PROV":28},
+                 "geometry":{"type":"Polygon","coordinates":[[[11.7,45.6],[11.8,45.6],[11.8,45.7],[11.7,45.7],[11.7,45.6]]]}}
+            ]}"#,
+        )
+        .unwrap();
+        let dem = dir.path().join("dem.asc");
+        fs::write(
+            &dem,
+            "ncols 2\nnrows 2\nxllcorner 11.7\nyllcorner 45.6\ncellsize 0.05\nNODATA_value -9999\n\
+             60 -9999\n40 45\n",
+        )
+        .unwrap();
+
+        let settings = crate::config::Geo {
+            boundaries_path: boundaries,
+            dem_path: dem,
+            ..Default::default()
+        };
+        let enricher = Enricher::load(&settings).unwrap().unwrap();
+
+        let tags = enricher.tags(45.62, 11.72);
+        assert_eq!(
+            tags,
+            vec![
+                (MUNICIPALITY.to_owned(), "Cittadella".to_owned()),
+                (ISTAT_CODE.to_owned(), "028032".to_owned()),
+                (PROVINCE.to_owned(), "28".to_owned()),
+                (ELEVATION.to_owned(), "40".to_owned()),
+            ]
+        );
+        // no data cell in the north east
+        assert_eq!(enricher.tags(45.68, 11.78).len(), 3);
+        assert!(enricher.tags(45.0, 11.0).is_empty());
+    }
+
+    #[test]
+    fn test_spatial_tags() {
+        let settings = crate::config::Geo {
+            geohash_precisions: vec![5, 7],
+            h3_resolutions: vec![7],
+            ..Default::default()
+        };
+        let enricher = Enricher::load(&settings).unwrap().unwrap();
+        let tags = enricher.tags(45.6485, 11.7856);
+        let names: Vec<&str> = tags.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, vec!["geohash5", "geohash7", "h3_7"]);
+        assert_eq!(tags[0].1, "u207s");
+        assert!(tags[1].1.starts_with(&tags[0].1));
+        assert_eq!(tags[2].1.len(), 15);
+
+        let settings = crate::config::Geo {
+            geohash_precisions: vec![13],
+            ..Default::default()
+        };
+        assert!(Enricher::load(&settings).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::Limits;
+
+/// The keys kept before the idle ones are dropped.
+const MAX_KEYS: usize = 10_000;
+
+/// Drops the entries updated least recently until a tenth of the keys is free,
+/// when the ones gone idle were not enough.
+fn evict_oldest<K: Eq + Hash + Clone, V>(map: &mut HashMap<K, V>, updated: impl Fn(&V) -> Instant) {
+    if map.len() < MAX_KEYS {
+        return;
+    }
+    let excess = map.len() - (MAX_KEYS - MAX_KEYS / 10);
+    let mut ages: Vec<(Instant, K)> = map.iter().map(|(k, v)| (updated(v), k.clone())).collect();
+    ages.select_nth_unstable_by_key(excess - 1, |(updated, _)| *updated);
+    for (_, key) in &ages[..excess] {
+        map.remove(key);
+    }
+}
+
+/// A request refused by a limit.
+#[derive(Debug, PartialEq)]
+pub struct Throttled {
+    pub retry_after: Duration,
+    /// whether the previous request of the key was let through, to log only the
+    /// start of a run of refused requests
+    pub first: bool,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    throttled: bool,
+}
+
+/// Token buckets by key, refilled at a constant rate up to the burst.
+pub struct TokenBuckets<K> {
+    per_sec: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K: Eq + Hash + Clone> TokenBuckets<K> {
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        TokenBuckets {
+            per_sec: per_minute as f64 / 60.0,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token of the key, always granted with a zero rate.
+    pub fn take(&self, key: K, now: Instant) -> Result<(), Throttled> {
+        if self.per_sec == 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_KEYS {
+            // the buckets refilled to the burst are the same as missing ones
+            let (per_sec, burst) = (self.per_sec, self.burst);
+            buckets.retain(|_, b| {
+                b.tokens + now.saturating_duration_since(b.updated).as_secs_f64() * per_sec < burst
+            });
+            evict_oldest(&mut buckets, |b| b.updated);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+            throttled: false,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_sec).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.throttled = false;
+            return Ok(());
+        }
+        let first = !bucket.throttled;
+        bucket.throttled = true;
+        Err(Throttled {
+            retry_after: Duration::from_secs_f64((1.0 - bucket.tokens) / self.per_sec),
+            first,
+        })
+    }
+}
+
+struct Failures {
+    count: u32,
+    since: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// Failed logins by address, too many within the window ban the address.
+pub struct AuthFailures {
+    max: u32,
+    window: Duration,
+    ban: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl AuthFailures {
+    pub fn new(max: u32, window: Duration, ban: Duration) -> Self {
+        AuthFailures {
+            max,
+            window,
+            ban,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The time left of the ban of the address.
+    pub fn banned_for(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        failures
+            .get(&ip)
+            .and_then(|f| f.banned_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Counts a failed login, true when it bans the address.
+    pub fn record(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.max == 0 {
+            return false;
+        }
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_KEYS {
+            let (window, ban) = (self.window, self.ban);
+            failures.retain(|_, f| {
+                now.saturating_duration_since(f.since) < window.max(ban)
+                    || f.banned_until.is_some_and(|until| until > now)
+            });
+            // the bans last
+            evict_oldest(&mut failures, |f| {
+                f.banned_until.unwrap_or(f.since).max(f.since)
+            });
+        }
+        let entry = failures.entry(ip).or_insert(Failures {
+            count: 0,
+            since: now,
+            banned_until: None,
+        });
+        if now.saturating_duration_since(entry.since) >= self.window {
+            entry.count = 0;
+            entry.since = now;
+        }
+        entry.count += 1;
+        if entry.count < self.max {
+            return false;
+        }
+        entry.count = 0;
+        entry.since = now;
+        entry.banned_until = Some(now + self.ban);
+        true
+    }
+
+    /// Forgets the failures of the address after a successful login, unless
+    /// it is banned.
+    pub fn clear(&self, ip: IpAddr, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+        if failures
+            .get(&ip)
+            .is_some_and(|f| f.banned_until.is_none_or(|until| until <= now))
+        {
+            failures.remove(&ip);
+        }
+    }
+}
+
+/// The limits of the `/write` endpoint shared by the requests.
+pub struct Throttle {
+    pub per_ip: TokenBuckets<IpAddr>,
+    pub per_chip: TokenBuckets<String>,
+    pub auth_failures: AuthFailures,
+}
+
+impl Throttle {
+    pub fn new(settings: &Limits) -> Self {
+        Throttle {
+            per_ip: TokenBuckets::new(settings.per_ip_per_minute, settings.per_ip_burst),
+            per_chip: TokenBuckets::new(settings.per_chip_per_minute, settings.per_chip_burst),
+            auth_failures: AuthFailures::new(
+                settings.max_auth_failures,
+                Duration::from_secs(settings.auth_failure_window_secs),
+                Duration::from_secs(settings.ban_secs),
+            ),
+        }
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Throttle::new(&Limits::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_buckets() {
+        let buckets = TokenBuckets::new(60, 2);
+        let now = Instant::now();
+        assert_eq!(buckets.take("a", now), Ok(()));
+        assert_eq!(buckets.take("a", now), Ok(()));
+        assert_eq!(
+            buckets.take("a", now),
+            Err(Throttled {
+                retry_after: Duration::from_secs(1),
+                first: true
+            })
+        );
+        assert!(!buckets.take("a", now).unwrap_err().first);
+        // other keys have their own bucket
+        assert_eq!(buckets.take("b", now), Ok(()));
+        // a token per second
+        assert_eq!(buckets.take("a", now + Duration::from_secs(1)), Ok(()));
+
+        let unlimited = TokenBuckets::new(0, 0);
+        for _ in 0..100 {
+            assert_eq!(unlimited.take("a", now), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_max_keys() {
+        // none of the buckets is refilled, the oldest ones go
+        let buckets = TokenBuckets::new(1, 10);
+        let now = Instant::now();
+        for i in 0..MAX_KEYS {
+            let _ = buckets.take(i, now + Duration::from_millis(i as u64));
+        }
+        let later = now + Duration::from_secs(11);
+        assert_eq!(buckets.take(MAX_KEYS, later), Ok(()));
+        let kept = buckets.buckets.lock().unwrap();
+        assert!(kept.len() < MAX_KEYS);
+        assert!(!kept.contains_key(&0));
+        assert!(kept.contains_key(&(MAX_KEYS - 1)));
+
+        // the banned addresses stay
+        let failures = AuthFailures::new(2, Duration::from_secs(60), Duration::from_secs(600));
+        let banned: IpAddr = "192.0.2.1".parse().unwrap();
+        failures.record(banned, now);
+        assert!(failures.record(banned, now));
+        for i in 1..MAX_KEYS as u32 {
+            let ip = IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + i));
+            failures.record(ip, now + Duration::from_millis(i as u64));
+        }
+        failures.record("192.0.2.2".parse().unwrap(), later);
+        assert!(failures.failures.lock().unwrap().len() < MAX_KEYS);
+        assert!(failures.banned_for(banned, later).is_some());
+    }
+
+    #[test]
+    fn test_auth_failures() {
+        let failures = AuthFailures::new(3, Duration::from_secs(60), Duration::from_secs(600));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        assert!(!failures.record(ip, now));
+        assert!(!failures.record(ip, now));
+        // the window expired, counting again from one
+        let later = now + Duration::from_secs(60);
+        assert!(!failures.record(ip, later));
+        assert!(!failures.record(ip, later));
+        assert_eq!(failures.banned_for(ip, later), None);
+        assert!(failures.record(ip, later));
+        assert_eq!(
+            failures.banned_for(ip, later + Duration::from_secs(100)),
+            Some(Duration::from_secs(500))
+        );
+        // a login during the ban does not lift it
+        failures.clear(ip, later);
+        assert!(failures.banned_for(ip, later).is_some());
+        assert_eq!(
+            failures.banned_for(ip, later + Duration::from_secs(600)),
+            None
+        );
+    }
+}
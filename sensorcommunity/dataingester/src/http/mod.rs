@@ -1,9 +1,12 @@
-use crate::{ChipInfo, SensorData, SensorInfo, sensor_data};
+mod limits;
+
+use crate::{SensorData, sensor_data};
 use axum::{
     Json, RequestPartsExt,
-    extract::{FromRef, FromRequest, Request, State, rejection::JsonRejection},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{ConnectInfo, FromRef, FromRequest, Request, State, rejection::JsonRejection},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
 };
 use axum_extra::{
     TypedHeader,
@@ -11,10 +14,22 @@ use axum_extra::{
 };
 use tracing::{Level, enabled};
 
-use crate::cache::Cache;
+use crate::config::{LoginError, StoredPassword, verify_login};
+use crate::pending::PendingStore;
+use crate::registry::Registry;
+use crate::metrics::{self, METRICS};
 use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
 use serde_json::json;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+pub use limits::Throttle;
 
 // Use anyhow, define error and enable '?'
 // For a simplified example of using anyhow in axum check /examples/anyhow-error-response
@@ -41,27 +56,49 @@ where
     }
 }
 
+/// Last time data was received from each chip.
+pub type LastSeen = Arc<RwLock<HashMap<String, DateTime<Utc>>>>;
+
 #[derive(Clone)]
 pub struct ReqState {
-    pub chip_cache: Cache<ChipInfo>,
-    pub sensor_cache: Cache<SensorInfo>,
+    pub registry: Registry,
     pub sensor_data_dir: PathBuf,
     pub measure_name_to_field: HashMap<String, String>,
     pub measure_name_to_sensor_type: HashMap<String, String>,
     pub writers: Vec<Arc<dyn crate::sensor_data::DataWriter>>,
-    pub logins: HashMap<String, String>,
+    pub logins: HashMap<String, StoredPassword>,
+    /// the chips of the logins scoped to some chips, the others post for any
+    pub chips_by_login: HashMap<String, HashSet<String>>,
+    pub throttle: Arc<Throttle>,
+    pub last_seen: LastSeen,
+    pub pending: PendingStore,
+}
+
+impl ReqState {
+    pub fn write_context(&self) -> sensor_data::WriteContext<'_> {
+        sensor_data::WriteContext {
+            writers: &self.writers,
+            measure_name_to_field: &self.measure_name_to_field,
+            measure_name_to_sensor_type: &self.measure_name_to_sensor_type,
+            registry: &self.registry,
+        }
+    }
 }
 
 pub async fn handler(
     State(ReqState {
-        chip_cache,
-        sensor_cache,
+        registry,
         sensor_data_dir,
         measure_name_to_field,
         measure_name_to_sensor_type,
         writers,
         logins: _,
+        chips_by_login: _,
+        throttle: _,
+        last_seen,
+        pending,
     }): State<ReqState>,
+    ConnectInfo(sender): ConnectInfo<SocketAddr>,
 
     SensorData { json, sensor }: SensorData<sensor_data::Payload>,
 ) -> Result<(), AppError> {
@@ -69,34 +106,36 @@ pub async fn handler(
     // tracing::debug!(?json, "json body");
     // println!("sensor: {}, json: {:?}", sensor, json);
 
-    let formatted_day = format!("{}", chrono::Utc::now().format("%Y-%m-%d"));
+    crate::metrics::observe_request(&sensor, "http", registry.contains_chip(&sensor));
+    last_seen
+        .write()
+        .unwrap()
+        .insert(sensor.clone(), Utc::now());
 
-    let root_folder = sensor_data_dir.join(&formatted_day);
-    let file_name = format!("{}_chip_{}.csv", &formatted_day, &sensor);
-
-    if let Err(e) = std::fs::create_dir_all(&root_folder) {
-        tracing::error!(
-            "Error creating sensor data folder at: {}, {}",
-            root_folder.as_os_str().to_string_lossy(),
-            e
-        );
-        return Err(AppError(anyhow!("{}", e)));
-    }
+    let file_path = match sensor_data::archive_file_path(&sensor_data_dir, &sensor) {
+        Ok(p) => p,
+        Err(e) => return Err(AppError(anyhow!("{}", e))),
+    };
 
-    let file_path = root_folder.join(file_name);
-
-    match sensor_data::write(
-        &writers,
-        &file_path,
-        &measure_name_to_field,
-        &measure_name_to_sensor_type,
-        chip_cache,
-        sensor_cache,
-        &sensor,
-        json,
-    )
-    .await
-    {
+    let ctx = sensor_data::WriteContext {
+        writers: &writers,
+        measure_name_to_field: &measure_name_to_field,
+        measure_name_to_sensor_type: &measure_name_to_sensor_type,
+        registry: &registry,
+    };
+    // the error is not Send, it can't be held across the await of hold
+    let res = sensor_data::write(&ctx, &file_path, &sensor, Utc::now().timestamp(), json)
+        .await
+        .map_err(|e| e.to_string());
+    match res {
+        Ok(sensor_data::WriteOutcome::UnknownChip(payload)) => {
+            if let Err(e) = pending
+                .hold(&sensor, &sender.ip().to_string(), payload)
+                .await
+            {
+                tracing::error!("Error trying to hold data for sensor {}: {}", &sensor, e);
+            }
+        }
         Ok(_) => {}
         Err(e) => {
             tracing::error!("Error trying to write data for sensor {}: {}", &sensor, e);
@@ -129,6 +168,78 @@ pub async fn handler(
 
 const X_SENSOR_HEADER: &str = "x-sensor";
 
+/// Span wrapping every request, tagged with the chip id sent in the `X-Sensor` header.
+pub fn make_span(req: &Request) -> tracing::Span {
+    let sensor = req
+        .headers()
+        .get(X_SENSOR_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri(),
+        chip_id = sensor,
+    )
+}
+
+/// Refuses the requests of the banned addresses and over the rate of their
+/// address, before the credentials are verified.
+pub async fn throttle(
+    State(state): State<ReqState>,
+    ConnectInfo(sender): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let ip = sender.ip();
+    let now = Instant::now();
+    if let Some(left) = state.throttle.auth_failures.banned_for(ip, now) {
+        metrics::observe_throttled("banned");
+        tracing::debug!("refused request of banned address {}", ip);
+        return too_many_requests(
+            "address banned after repeated authentication failures",
+            left,
+        );
+    }
+    if let Err(throttled) = state.throttle.per_ip.take(ip, now) {
+        metrics::observe_throttled("ip_rate");
+        if throttled.first {
+            tracing::warn!("throttling requests from address {}", ip);
+        }
+        return too_many_requests("too many requests from this address", throttled.retry_after);
+    }
+    next.run(req).await
+}
+
+fn too_many_requests(error: &str, retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string())],
+        Json(json!({"error": error, "retry_after_secs": secs})),
+    )
+        .into_response()
+}
+
+/// Counts a failed login of the address, which is banned after too many.
+fn record_auth_failure(state: &ReqState, sender: Option<SocketAddr>) {
+    let Some(sender) = sender else {
+        return;
+    };
+    if state
+        .throttle
+        .auth_failures
+        .record(sender.ip(), Instant::now())
+    {
+        METRICS.auth_bans.inc();
+        tracing::warn!(
+            target: "audit",
+            "banned address {} after repeated authentication failures",
+            sender.ip()
+        );
+    }
+}
+
 // the state your library needs
 
 impl<S, T> FromRequest<S> for SensorData<T>
@@ -139,7 +250,7 @@ where
     T: 'static,
     ReqState: FromRef<S>,
 {
-    type Rejection = (StatusCode, axum::Json<serde_json::Value>);
+    type Rejection = Response;
 
     async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
         // Extract the token from the authorization header
@@ -164,9 +275,18 @@ where
             );
         }
 
+        let sender = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| *addr);
+
         let creds = match parts.extract::<TypedHeader<Authorization<Basic>>>().await {
             Ok(TypedHeader(Authorization(bearer))) => bearer,
             Err(_) => {
+                METRICS
+                    .auth_failures
+                    .with_label_values(&["missing_credentials"])
+                    .inc();
                 if enabled!(Level::DEBUG) {
                     tracing::debug!(
                         "missing credentials for sensor: {}, origin: {}",
@@ -180,46 +300,83 @@ where
                     Json(json!({
                         "error": "missing credentials",
                     })),
-                ));
+                )
+                    .into_response());
             }
         };
 
         let mystate: ReqState = ReqState::from_ref(state);
 
-        let pwd = mystate
-            .logins
-            .get(&creds.username().to_lowercase())
-            .ok_or_else(|| {
-                if enabled!(Level::DEBUG) {
-                    tracing::debug!(
-                        "wrong credentials in configuration for username {}, sensor: {}, origin: {}",
-                        &creds.username(),
-                        &sensor,
-                        origin
-                    );
-                }
-                (
-                    StatusCode::UNAUTHORIZED,
-                    Json(json!({
-                        "error": "wrong credentials",
-                    })),
-                )
-            })?;
+        // the same answer and time whether the username is known or not
+        if let Err(e) = verify_login(&mystate.logins, creds.username(), creds.password()).await {
+            let reason = match e {
+                LoginError::UnknownUser => "unknown_user",
+                LoginError::WrongPassword => "wrong_password",
+            };
+            METRICS.auth_failures.with_label_values(&[reason]).inc();
+            record_auth_failure(&mystate, sender);
+            if enabled!(Level::DEBUG) {
+                tracing::debug!(
+                    "wrong credentials ({}) for username {}, sensor: {}, origin: {}",
+                    reason,
+                    &creds.username(),
+                    &sensor,
+                    origin
+                );
+            }
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                Json(json!({"error": "wrong credentials"})),
+            )
+                .into_response());
+        }
+        if let Some(sender) = sender {
+            mystate
+                .throttle
+                .auth_failures
+                .clear(sender.ip(), Instant::now());
+        }
 
-        let config_password = creds.password().trim();
-        let req_password = pwd.trim();
-        if req_password != config_password {
-            tracing::debug!(
-                "wrong password for username {}, sensor: {}, origin: {}",
-                &creds.username(),
-                &sensor,
-                origin,
+        if let Some(chips) = mystate.chips_by_login.get(&creds.username().to_lowercase())
+            && !chips.contains(&sensor)
+        {
+            METRICS
+                .auth_failures
+                .with_label_values(&["chip_not_allowed"])
+                .inc();
+            tracing::warn!(
+                target: "audit",
+                "login {} is not allowed to post data for chip {}, address: {}, origin: {}",
+                creds.username(),
+                sensor,
+                sender.map(|s| s.to_string()).unwrap_or_default(),
+                origin
             );
             return Err((
-                StatusCode::UNAUTHORIZED,
-                Json(json!({"error": "Unauthorized"})),
+                StatusCode::FORBIDDEN,
+                Json(json!({
+                    "error": "login not allowed for this chip",
+                    "chip_id": sensor,
+                })),
+            )
+                .into_response());
+        }
+
+        // after the credentials, so that others cannot use up the tokens of a chip
+        if let Err(throttled) = mystate
+            .throttle
+            .per_chip
+            .take(sensor.clone(), Instant::now())
+        {
+            metrics::observe_throttled("chip_rate");
+            if throttled.first {
+                tracing::warn!("throttling data of chip {}", sensor);
+            }
+            return Err(too_many_requests(
+                "too many requests for this chip",
+                throttled.retry_after,
             ));
-        };
+        }
 
         // tracing::debug!(headers = ?parts.headers);
         // tracing::debug!(body = ?body);
@@ -240,7 +397,17 @@ where
         let json = match axum::Json::<T>::from_request(req, state).await {
             Ok(value) => Ok(value.0),
             // convert the error from `axum::Json` into whatever we want
+            Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                metrics::observe_throttled("body_too_large");
+                tracing::warn!("refused a body too large from chip {}", sensor);
+                Err((
+                    rejection.status(),
+                    axum::Json(json!({"error": rejection.body_text()})),
+                )
+                    .into_response())
+            }
             Err(rejection) => {
+                METRICS.payload_parse_errors.inc();
                 // println!("--- rejection: {}", rejection.body_text());
                 let payload = json!({
                     "message": rejection.body_text(),
@@ -248,7 +415,7 @@ where
                     "path": path,
                 });
 
-                Err((rejection.status(), axum::Json(payload)))
+                Err((rejection.status(), axum::Json(payload)).into_response())
             }
         }?;
 
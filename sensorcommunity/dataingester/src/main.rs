@@ -1,55 +1,137 @@
+mod acme;
+mod admin;
 mod cache;
 mod config;
+mod geodata;
 mod http;
 mod logging;
+mod metrics;
+mod mqtt;
+mod pending;
+mod registry;
 mod sensor_data;
+mod telemetry;
 
-use crate::config::{Arg, Command, Context, Manifest, crate_version, init_cli};
+use crate::config::{
+    Arg, Command, Context, HashAlgorithm, Manifest, crate_version, hash_password, init_cli,
+    login_chips, login_passwords,
+};
 use axum::{
     BoxError, Router,
-    handler::HandlerWithoutStateExt,
+    extract::DefaultBodyLimit,
     http::{StatusCode, Uri, uri::Authority},
+    middleware,
     response::Redirect,
-    routing::post,
+    routing::{get, post},
 };
 use axum_extra::extract::Host;
 use axum_server::tls_rustls::RustlsConfig;
+use tower_http::trace::TraceLayer;
 use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
-use crate::cache::{CacheKey, load_cache};
+use crate::cache::CacheKey;
+use crate::registry::Registry;
+use crate::sensor_data::ManagedWriter;
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use std::{
-    collections::HashMap, future::Future, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 use tokio::signal;
 
 pub const MANIFEST_NAME: &str = "dataingester.toml";
 
+// chip_id,lat,lon,city,info,enabled,valid_from,valid_to
+// a chip can have several rows, one per period, e.g. when it is moved to a new location
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ChipInfo {
     pub chip_id: String,
-    pub city: String,
-    pub info: String,
     pub lat: f64,
     pub lon: f64,
+    pub city: String,
+    pub info: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default, with = "registry::validity")]
+    pub valid_from: Option<DateTime<Utc>>,
+    #[serde(default, with = "registry::validity")]
+    pub valid_to: Option<DateTime<Utc>>,
 }
 
-// chip_id,sensor_id,sensor_type
+// chip_id,sensor_id,sensor_type,enabled
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SensorInfo {
     pub chip_id: String,
     pub sensor_id: String,
     pub sensor_type: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl ChipInfo {
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.chip_id.trim().is_empty() {
+            return Err("empty chip id".to_owned());
+        }
+        if !(-90.0..=90.0).contains(&self.lat) {
+            return Err(format!("latitude {} out of range", self.lat));
+        }
+        if !(-180.0..=180.0).contains(&self.lon) {
+            return Err(format!("longitude {} out of range", self.lon));
+        }
+        if let (Some(from), Some(to)) = (self.valid_from, self.valid_to)
+            && from >= to
+        {
+            return Err(format!("valid_from {} is not before valid_to {}", from, to));
+        }
+        Ok(())
+    }
+
+    /// Whether this row describes the chip at the given unix timestamp in seconds.
+    pub fn is_valid_at(&self, timestamp: i64) -> bool {
+        self.valid_from.is_none_or(|from| from.timestamp() <= timestamp)
+            && self.valid_to.is_none_or(|to| timestamp < to.timestamp())
+    }
+}
+
+impl SensorInfo {
+    /// Checks the sensor against the known sensor types, when there are any.
+    pub fn validate(&self, sensor_types: &[String]) -> std::result::Result<(), String> {
+        if self.chip_id.trim().is_empty() {
+            return Err("empty chip id".to_owned());
+        }
+        if self.sensor_id.trim().is_empty() {
+            return Err("empty sensor id".to_owned());
+        }
+        if !sensor_types.is_empty() && !sensor_types.contains(&self.sensor_type) {
+            return Err(format!("unknown sensor type {}", self.sensor_type));
+        }
+        Ok(())
+    }
 }
 
 impl CacheKey for ChipInfo {
+    const NAME: &'static str = "chips";
     fn id(&self) -> String {
-        self.chip_id.clone()
+        match self.valid_from {
+            Some(from) => format!("{}@{}", self.chip_id, from.to_rfc3339()),
+            None => self.chip_id.clone(),
+        }
     }
 }
 
 impl CacheKey for SensorInfo {
+    const NAME: &'static str = "sensors";
     fn id(&self) -> String {
         format!("{}:{}", self.chip_id, self.sensor_type)
     }
@@ -92,14 +174,74 @@ async fn main() {
                     .value_parser(clap::value_parser!(std::path::PathBuf)),
             ),
         )
+        .subcommand(
+            clap::command!("hash-password")
+                .about("Hash a login password, read from the terminal or stdin, for the manifest")
+                .arg(
+                    Arg::new("bcrypt")
+                        .long("bcrypt")
+                        .help("Use bcrypt instead of Argon2id")
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            clap::command!("pending")
+                .about("Chips missing from the chips file whose payloads are held")
+                .subcommand_required(true)
+                .subcommand(clap::command!("list"))
+                .subcommand(
+                    clap::command!("approve")
+                        .about("Add the chip and its inferred sensors, then write the held payloads")
+                        .arg(Arg::new("chip_id").required(true))
+                        .arg(
+                            Arg::new("lat")
+                                .long("lat")
+                                .required(true)
+                                .allow_negative_numbers(true)
+                                .value_parser(clap::value_parser!(f64)),
+                        )
+                        .arg(
+                            Arg::new("lon")
+                                .long("lon")
+                                .required(true)
+                                .allow_negative_numbers(true)
+                                .value_parser(clap::value_parser!(f64)),
+                        )
+                        .arg(Arg::new("city").long("city").default_value(""))
+                        .arg(Arg::new("info").long("info").default_value(""))
+                        .arg(
+                            Arg::new("sensor")
+                                .long("sensor")
+                                .value_name("TYPE=ID")
+                                .help("Sensor id for a sensor type, by default <chip_id>-<type>")
+                                .action(clap::ArgAction::Append),
+                        ),
+                ),
+        )
         .get_matches();
 
+    // needs no manifest
+    if let Some(("hash-password", matches)) = matches.subcommand() {
+        let algorithm = match matches.get_flag("bcrypt") {
+            true => HashAlgorithm::Bcrypt,
+            false => HashAlgorithm::Argon2,
+        };
+        if let Err(e) = print_password_hash(algorithm) {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let config_path = match matches.get_one::<String>("config") {
         Some(file) => file,
         _ => MANIFEST_NAME,
     };
 
-    let (config, log_guard, ctx) = init_cli(config_path).unwrap();
+    let (config, log_guard, ctx, generated_logins) = init_cli(config_path).unwrap();
+    for (username, password) in generated_logins {
+        println!("generated login {} with password: {}", username, password);
+    }
 
     match matches.subcommand() {
         Some(("serve", _matches)) => {
@@ -115,11 +257,36 @@ async fn main() {
             };
             import(config, log_guard, ctx, dir).await;
         }
+        Some(("pending", matches)) => {
+            pending(config, log_guard, ctx, matches).await;
+        }
         _ => unreachable!("clap should ensure we don't get here"),
     };
 }
 
-fn get_writers(config: &Manifest) -> Vec<Arc<dyn crate::sensor_data::DataWriter>> {
+/// Reads the password twice from the terminal, or once from stdin when piped,
+/// and prints its hash.
+fn print_password_hash(algorithm: HashAlgorithm) -> Result<()> {
+    use std::io::IsTerminal;
+    let password = if std::io::stdin().is_terminal() {
+        let password = rpassword::prompt_password("Password: ")?;
+        if password != rpassword::prompt_password("Confirm password: ")? {
+            return Err(anyhow::anyhow!("the passwords do not match"));
+        }
+        password
+    } else {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        line.trim_end_matches(['\r', '\n']).to_owned()
+    };
+    if password.is_empty() {
+        return Err(anyhow::anyhow!("the password is empty"));
+    }
+    println!("{}", hash_password(&password, algorithm)?);
+    Ok(())
+}
+
+fn get_writers(config: &Manifest) -> Vec<Arc<ManagedWriter>> {
     // register writers
     let mut writers = vec![];
     if config.influxdb.url.len() > 0 {
@@ -129,59 +296,45 @@ fn get_writers(config: &Manifest) -> Vec<Arc<dyn crate::sensor_data::DataWriter>
         writers.push(inf);
     }
     if config.influxdb3.url.len() > 0 {
-        let inf: Arc<dyn crate::sensor_data::DataWriter> = Arc::new(
-            crate::sensor_data::InfluxDB3DataWriter::new(config.influxdb3.clone()),
-        );
-        writers.push(inf);
+        match crate::sensor_data::InfluxDB3DataWriter::new(config.influxdb3.clone()) {
+            Ok(w) => writers.push(Arc::new(w)),
+            Err(e) => tracing::error!("could not create the influxdb3 writer: {}", e),
+        }
     }
     if config.questdb.addr.len() > 0 {
-        let inf: Arc<dyn crate::sensor_data::DataWriter> = Arc::new(
-            crate::sensor_data::QuestDBDataWriter::new(config.questdb.clone()),
-        );
-        writers.push(inf);
+        match crate::sensor_data::QuestDBDataWriter::new(config.questdb.clone()) {
+            Ok(w) => writers.push(Arc::new(w)),
+            Err(e) => tracing::error!("could not create the questdb writer: {}", e),
+        }
     }
     writers
+        .into_iter()
+        .map(|w| Arc::new(ManagedWriter::new(w)))
+        .collect()
 }
 
-fn build_sensor_info_records(
-    chip_cache: &crate::cache::Cache<ChipInfo>,
-    sensor_cache: &crate::cache::Cache<SensorInfo>,
-) -> Vec<sensor_data::SensorInfoRecord> {
-    let chips = chip_cache.read().unwrap();
-    let sensors = sensor_cache.read().unwrap();
-
-    let mut records = Vec::new();
-    for sensor in sensors.values() {
-        match chips.get(&sensor.chip_id) {
-            Some(chip) => {
-                records.push(sensor_data::SensorInfoRecord {
-                    sensor_id: sensor.sensor_id.clone(),
-                    sensor_type: sensor.sensor_type.clone(),
-                    chip_id: sensor.chip_id.clone(),
-                    lat: chip.lat,
-                    lon: chip.lon,
-                    city: chip.city.clone(),
-                    info: chip.info.clone(),
-                });
-            }
-            None => {
-                tracing::warn!(
-                    "sensor {} (chip_id={}) has no matching chip info, skipping",
-                    sensor.sensor_id,
-                    sensor.chip_id
-                );
-            }
-        }
-    }
-    records
+/// Loads the chips and sensors from the locations in the config, see [cache::open_source],
+/// with the tags resolved from their positions when the geo files are configured.
+fn load_registry(config: &Manifest) -> Result<Registry, Box<dyn std::error::Error>> {
+    let chips_location = shellexpand::env(&config.chips_filepath.as_os_str().to_string_lossy())?
+        .into_owned();
+    let sensors_location =
+        shellexpand::env(&config.sensors_filepath.as_os_str().to_string_lossy())?.into_owned();
+    let geo = geodata::Enricher::load(&config.geo)?.map(Arc::new);
+    Registry::load(
+        &chips_location,
+        &sensors_location,
+        config.sensor_types(),
+        Duration::from_secs(config.registry_poll_secs),
+        geo,
+    )
 }
 
 async fn refresh_sensor_info_on_writers(
-    chip_cache: &crate::cache::Cache<ChipInfo>,
-    sensor_cache: &crate::cache::Cache<SensorInfo>,
+    registry: &Registry,
     writers: &[Arc<dyn crate::sensor_data::DataWriter>],
 ) {
-    let records = build_sensor_info_records(chip_cache, sensor_cache);
+    let records = registry.sensor_info_records();
     for writer in writers {
         if let Err(e) = writer.refresh_sensor_info(&records).await {
             tracing::error!("failed to refresh sensor info: {}", e);
@@ -196,30 +349,18 @@ async fn serve(
 ) {
     tracing::debug!("working directory: {}", ctx.working_dir.display());
 
-    let chips_filepath = shellexpand::env(&config.chips_filepath.as_os_str().to_string_lossy())
-        .unwrap()
-        .as_ref()
-        .to_owned();
-
-    // load chip cache with hot reload
-    let (chip_cache, _watcher, chip_watch_rx) = match load_cache::<ChipInfo>(&chips_filepath) {
-        Ok(c) => c,
+    // load chips and sensors with hot reload
+    let registry = match load_registry(&config) {
+        Ok(r) => r,
         Err(e) => {
-            tracing::error!("could not load the chip info cache: {}", e);
+            tracing::error!("could not load the chip and sensor registry: {}", e);
             return;
         }
     };
-
-    let sensors_filepath = shellexpand::env(&config.sensors_filepath.as_os_str().to_string_lossy())
-        .unwrap()
-        .as_ref()
-        .to_owned();
-
-    // load sensor cache with hot reload
-    let (sensor_cache, _watcher, sensor_watch_rx) = match load_cache::<SensorInfo>(&sensors_filepath) {
-        Ok(c) => c,
+    let (_watchers, registry_watch_rx) = match registry.watch() {
+        Ok(w) => w,
         Err(e) => {
-            tracing::error!("could not load the sensor info cache: {}", e);
+            tracing::error!("could not watch the chip and sensor files: {}", e);
             return;
         }
     };
@@ -238,108 +379,170 @@ async fn serve(
     );
 
     // register writers
-    let writers = get_writers(&config);
+    let managed_writers = get_writers(&config);
+    let writers = sensor_data::as_data_writers(&managed_writers);
+
+    // tables and migrations, the writes go on without them
+    for writer in &writers {
+        if let Err(e) = writer.start().await {
+            tracing::warn!("could not prepare the {} writer: {:#}", writer.name(), e);
+        }
+    }
 
     // initial sensor info sync
-    refresh_sensor_info_on_writers(&chip_cache, &sensor_cache, &writers).await;
+    refresh_sensor_info_on_writers(&registry, &writers).await;
 
-    // spawn background task to refresh sensor info on cache changes
+    // spawn background task to refresh sensor info on registry changes
     {
-        let chip_cache_bg = chip_cache.clone();
-        let sensor_cache_bg = sensor_cache.clone();
+        let registry_bg = registry.clone();
         let writers_bg: Vec<Arc<dyn crate::sensor_data::DataWriter>> = writers.clone();
-        let mut chip_rx = chip_watch_rx;
-        let mut sensor_rx = sensor_watch_rx;
+        let mut registry_rx = registry_watch_rx;
         tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    res = chip_rx.changed() => {
-                        if res.is_err() { break; }
-                    }
-                    res = sensor_rx.changed() => {
-                        if res.is_err() { break; }
-                    }
-                }
-                tracing::info!("cache change detected, refreshing sensor info");
-                refresh_sensor_info_on_writers(&chip_cache_bg, &sensor_cache_bg, &writers_bg).await;
+            while registry_rx.changed().await.is_ok() {
+                tracing::info!("registry change detected, refreshing sensor info");
+                refresh_sensor_info_on_writers(&registry_bg, &writers_bg).await;
             }
         });
     }
 
-    let mut logins: HashMap<String, String> = HashMap::new();
-    for login in config.logins {
-        logins.insert(login.username.to_lowercase(), login.password);
+    let logins = login_passwords(&config.logins, "logins");
+    let chips_by_login = login_chips(&config.logins);
+
+    let pending = match pending::PendingStore::new(&config.pending) {
+        Ok(p) => p,
+        Err(e) => {
+            tracing::error!("could not open the pending chips folder: {}", e);
+            return;
+        }
+    };
+
+    let state = http::ReqState {
+        registry,
+        sensor_data_dir,
+        measure_name_to_field: config.measure_name_to_field.clone(),
+        measure_name_to_sensor_type: config.measure_name_to_sensor_type.clone(),
+        writers,
+        logins,
+        chips_by_login,
+        throttle: Arc::new(http::Throttle::new(&config.limits)),
+        last_seen: Default::default(),
+        pending,
+    };
+
+    if config.perf.enabled {
+        let perf = config.perf.clone();
+        tokio::spawn(async move { metrics::serve(&perf).await });
     }
 
-    // let use_influxdb_3 = influxdb_settings.url.len() == 0;
+    // optional mqtt listener, payloads go through the same write path as /write
+    if config.mqtt.enabled {
+        tokio::spawn(mqtt::listen(config.mqtt.clone(), state.clone()));
+    }
 
     let app = Router::new()
         .route("/write", post(http::handler))
-        .with_state(http::ReqState {
-            chip_cache: chip_cache,
-            sensor_cache: sensor_cache,
-            sensor_data_dir,
-            measure_name_to_field: config.measure_name_to_field,
-            measure_name_to_sensor_type: config.measure_name_to_sensor_type,
-            writers,
-            logins,
-        });
+        .route_layer(middleware::from_fn_with_state(state.clone(), http::throttle))
+        .layer(DefaultBodyLimit::max(config.limits.max_body_bytes))
+        .layer(TraceLayer::new_for_http().make_span_with(http::make_span))
+        .with_state(state.clone());
     //.layer(middleware::from_fn(print_request_body));
 
-    let https_addr = config.https_addr.trim();
+    let https_addr = config.https_addr.trim().to_owned();
+    let acme_settings = config.acme.clone();
+    let tls_dir = PathBuf::from(
+        shellexpand::env(&config.tls_dir.as_os_str().to_string_lossy())
+            .unwrap()
+            .as_ref(),
+    );
+
+    // the manifest is shared with the admin api, which can change and save it
+    let admin_addr = config.admin.addr.trim().to_owned();
+    let admin_logins = login_passwords(&config.admin.logins, "admin.logins");
+    let config = Arc::new(Mutex::new(config));
+
+    if !admin_addr.is_empty() {
+        let admin_state = admin::AdminState {
+            manifest: config.clone(),
+            ingest: state.clone(),
+            writers: managed_writers,
+            logins: admin_logins,
+        };
+        tokio::spawn(async move { admin::serve(&admin_addr, admin_state).await });
+    }
+
     if https_addr.is_empty() {
+        if !acme_settings.domains.is_empty() {
+            tracing::warn!("ACME certificates need the https listener, https_addr is empty");
+        }
         let addr = SocketAddr::from(http_addr);
         let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
         tracing::info!("listening on address: {}", addr);
 
         // Run the server with graceful shutdown
-        axum::serve(listener, app)
-            .with_graceful_shutdown(shutdown_future)
-            .await
-            .unwrap();
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_future)
+        .await
+        .unwrap();
     } else {
         rustls::crypto::ring::default_provider()
             .install_default()
             .expect("Failed to install default CryptoProvider");
 
-        let https_addr: SocketAddr = config.https_addr.parse().unwrap();
+        let https_addr: SocketAddr = https_addr.parse().unwrap();
         let addresses = Addresses {
             http_addr,
             https_addr,
         };
-        // optional: spawn a second server to redirect http requests to this server
-        tokio::spawn(redirect_http_to_https(addresses, shutdown_future));
-
-        let tls_dir = PathBuf::from(
-            shellexpand::env(&config.tls_dir.as_os_str().to_string_lossy())
-                .unwrap()
-                .as_ref(),
-        );
-
-        const CERT_FILE: &str = "cert.pem";
-        const KEY_FILE: &str = "key.pem";
-
-        // configure certificate and private key used by https
-        let config = RustlsConfig::from_pem_file(tls_dir.join(CERT_FILE), tls_dir.join(KEY_FILE))
-        .await
-        .map_err(|e| {
-            format!(
-            "error loading TLS config files from folder: {}, certificate: {}, private key: {}, error: {}",
-            &tls_dir.display(),
-            CERT_FILE, KEY_FILE,
-            e
-        )
-        })
-        .unwrap();
+        // optional: spawn a second server to redirect http requests to this server,
+        // it also answers the ACME HTTP-01 challenges
+        let http_challenges = acme::HttpChallenges::default();
+        tokio::spawn(redirect_http_to_https(
+            addresses,
+            http_challenges.clone(),
+            shutdown_future,
+        ));
+
+        let config = if acme_settings.domains.is_empty() {
+            use acme::{CERT_FILE, KEY_FILE};
+
+            // configure certificate and private key used by https
+            RustlsConfig::from_pem_file(tls_dir.join(CERT_FILE), tls_dir.join(KEY_FILE))
+            .await
+            .map_err(|e| {
+                format!(
+                "error loading TLS config files from folder: {}, certificate: {}, private key: {}, error: {}",
+                &tls_dir.display(),
+                CERT_FILE, KEY_FILE,
+                e
+            )
+            })
+            .unwrap()
+        } else {
+            // certificates ordered and renewed in the background, served without restarts
+            let resolver = Arc::new(acme::CertResolver::default());
+            let manager = acme::CertManager::new(
+                acme_settings,
+                tls_dir.clone(),
+                resolver.clone(),
+                http_challenges,
+            );
+            tokio::spawn(manager.run());
+            RustlsConfig::from_config(acme::server_config(resolver))
+        };
 
         // run https server
         tracing::debug!("listening on TLS address: {}", addresses.https_addr);
         axum_server::bind_rustls(addresses.https_addr, config)
             .handle(handle)
-            .serve(app.into_make_service())
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             .await
             .unwrap();
     }
+
+    config.lock().unwrap().logging.shutdown_telemetry();
 }
 
 async fn shutdown_signal(handle: axum_server::Handle) {
@@ -370,7 +573,7 @@ async fn shutdown_signal(handle: axum_server::Handle) {
     // to force shutdown
 }
 
-async fn redirect_http_to_https<F>(addrs: Addresses, signal: F)
+async fn redirect_http_to_https<F>(addrs: Addresses, challenges: acme::HttpChallenges, signal: F)
 where
     F: Future<Output = ()> + Send + 'static,
 {
@@ -416,7 +619,14 @@ where
         addr,
         addrs.https_addr
     );
-    axum::serve(listener, redirect.into_make_service())
+    let app = Router::new()
+        .route(
+            "/.well-known/acme-challenge/{token}",
+            get(acme::http_challenge),
+        )
+        .fallback(redirect)
+        .with_state(challenges);
+    axum::serve(listener, app)
         .with_graceful_shutdown(signal)
         .await
         .unwrap();
@@ -430,19 +640,13 @@ async fn import(
 ) {
 
     // register writers
-    let writers = get_writers(&config);
-
+    let writers = sensor_data::as_data_writers(&get_writers(&config));
 
-    let sensors_filepath = shellexpand::env(&config.sensors_filepath.as_os_str().to_string_lossy())
-        .unwrap()
-        .as_ref()
-        .to_owned();
-
-    // load chip cache with hot reload
-    let (sensor_cache, _watcher, _watch_rx) = match load_cache::<SensorInfo>(&sensors_filepath) {
-        Ok(c) => c,
+    // load chips and sensors, no reload needed while importing
+    let registry = match load_registry(&config) {
+        Ok(r) => r,
         Err(e) => {
-            tracing::error!("could not load the chip info cache: {}", e);
+            tracing::error!("could not load the chip and sensor registry: {}", e);
             return;
         }
     };
@@ -456,7 +660,7 @@ async fn import(
 
         // Check if the file is a CSV
         if path.is_file() && path.extension().map_or(false, |ext| ext == "csv") {
-            match sensor_data::import_csv(path, &config, &writers, sensor_cache.clone()).await {
+            match sensor_data::import_csv(path, &config, &writers, &registry).await {
                 Ok(r) => {
                     if r.record_count > 0 {
                         tracing::info!(
@@ -470,4 +674,91 @@ async fn import(
             }
         }
     }
+
+    config.logging.shutdown_telemetry();
+}
+
+async fn pending(
+    config: Manifest,
+    _log_guard: tracing_appender::non_blocking::WorkerGuard,
+    _ctx: Context,
+    matches: &clap::ArgMatches,
+) {
+    let store = match pending::PendingStore::new(&config.pending) {
+        Ok(p) => p,
+        Err(e) => {
+            tracing::error!("could not open the pending chips folder: {}", e);
+            return;
+        }
+    };
+
+    let (chip_id, approval) = match matches.subcommand() {
+        Some(("list", _)) => {
+            match store.list(&config.measure_name_to_sensor_type) {
+                Ok(chips) => println!("{}", serde_json::to_string_pretty(&chips).unwrap()),
+                Err(e) => tracing::error!("could not list the pending chips: {}", e),
+            }
+            return;
+        }
+        Some(("approve", matches)) => {
+            let mut sensor_ids = HashMap::new();
+            for s in matches.get_many::<String>("sensor").unwrap_or_default() {
+                match s.split_once('=') {
+                    Some((t, id)) => sensor_ids.insert(t.trim().to_owned(), id.trim().to_owned()),
+                    None => {
+                        tracing::error!("invalid sensor {}, expected TYPE=ID", s);
+                        return;
+                    }
+                };
+            }
+            let approval = pending::Approval {
+                lat: *matches.get_one::<f64>("lat").unwrap(),
+                lon: *matches.get_one::<f64>("lon").unwrap(),
+                city: matches.get_one::<String>("city").unwrap().clone(),
+                info: matches.get_one::<String>("info").unwrap().clone(),
+                sensor_ids,
+            };
+            (matches.get_one::<String>("chip_id").unwrap().clone(), approval)
+        }
+        _ => unreachable!("clap should ensure we don't get here"),
+    };
+
+    let registry = match load_registry(&config) {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!("could not load the chip and sensor registry: {}", e);
+            return;
+        }
+    };
+
+    let state = http::ReqState {
+        registry,
+        sensor_data_dir: PathBuf::from(
+            shellexpand::env(&config.sensor_data_dir.as_os_str().to_string_lossy())
+                .unwrap()
+                .as_ref(),
+        ),
+        measure_name_to_field: config.measure_name_to_field.clone(),
+        measure_name_to_sensor_type: config.measure_name_to_sensor_type.clone(),
+        writers: sensor_data::as_data_writers(&get_writers(&config)),
+        logins: HashMap::new(),
+        chips_by_login: HashMap::new(),
+        throttle: Default::default(),
+        last_seen: Default::default(),
+        pending: store,
+    };
+
+    match pending::approve(&state, &chip_id, approval).await {
+        Ok(report) => {
+            if report.registry.applied {
+                refresh_sensor_info_on_writers(&state.registry, &state.writers).await;
+            } else {
+                tracing::error!("chip {} not added:\n{}", chip_id, report.registry);
+            }
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        }
+        Err(e) => tracing::error!("could not approve chip {}: {}", chip_id, e),
+    }
+
+    config.logging.shutdown_telemetry();
 }
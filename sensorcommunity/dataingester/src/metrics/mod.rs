@@ -0,0 +1,397 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use axum::{Router, http::StatusCode, response::IntoResponse, routing::get};
+use opentelemetry::KeyValue;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::sensor_data::{P1, P2, VALUE_TYPES};
+
+/// The label of the chips missing from the registry and of the value types
+/// outside the known ones, the payloads are untrusted and would otherwise add a
+/// series per made up chip id or value type.
+pub const OTHER: &str = "other";
+
+pub struct Metrics {
+    registry: Registry,
+    pub requests: IntCounterVec,
+    pub auth_failures: IntCounterVec,
+    pub throttled_requests: IntCounterVec,
+    pub auth_bans: IntCounter,
+    pub payload_parse_errors: IntCounter,
+    pub unknown_value_types: IntCounterVec,
+    pub writer_duration: HistogramVec,
+    pub writer_errors: IntCounterVec,
+    pub cache_reloads: IntCounterVec,
+    pub cache_reload_rejections: IntCounterVec,
+    pub cache_items: IntGaugeVec,
+    pub pending_payloads: IntCounterVec,
+    pub sensor_info_refreshes: IntCounterVec,
+    pub sensor_info_changes: IntCounterVec,
+    pub pm_values: GaugeVec,
+}
+
+/// Instruments exported through OTLP, no-ops unless an OTLP endpoint is configured.
+struct OtelMetrics {
+    writer_duration: opentelemetry::metrics::Histogram<f64>,
+    writer_errors: opentelemetry::metrics::Counter<u64>,
+}
+
+static OTEL_METRICS: LazyLock<OtelMetrics> = LazyLock::new(|| {
+    let meter = opentelemetry::global::meter("dataingester");
+    OtelMetrics {
+        writer_duration: meter
+            .f64_histogram("dataingester.writer.duration")
+            .with_unit("s")
+            .with_description("Latency of writes per backend")
+            .build(),
+        writer_errors: meter
+            .u64_counter("dataingester.writer.errors")
+            .with_description("Failed writes per backend")
+            .build(),
+    }
+});
+
+static PM_GAUGES_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let registry = Registry::new_custom(Some("dataingester".to_owned()), None).unwrap();
+
+    let requests = IntCounterVec::new(
+        Opts::new("requests_total", "Payloads received per chip"),
+        &["chip_id", "source"],
+    )
+    .unwrap();
+    let auth_failures = IntCounterVec::new(
+        Opts::new("auth_failures_total", "Rejected authentication attempts"),
+        &["reason"],
+    )
+    .unwrap();
+    let throttled_requests = IntCounterVec::new(
+        Opts::new("throttled_requests_total", "Requests refused by the limits"),
+        &["reason"],
+    )
+    .unwrap();
+    let auth_bans = IntCounter::new(
+        "auth_bans_total",
+        "Addresses banned after repeated authentication failures",
+    )
+    .unwrap();
+    let payload_parse_errors = IntCounter::new(
+        "payload_parse_errors_total",
+        "Payloads that could not be decoded",
+    )
+    .unwrap();
+    let unknown_value_types = IntCounterVec::new(
+        Opts::new(
+            "unknown_value_types_total",
+            "Values skipped because their value type has no sensor type",
+        ),
+        &["value_type"],
+    )
+    .unwrap();
+    let writer_duration = HistogramVec::new(
+        HistogramOpts::new("writer_duration_seconds", "Latency of writes per backend"),
+        &["backend"],
+    )
+    .unwrap();
+    let writer_errors = IntCounterVec::new(
+        Opts::new("writer_errors_total", "Failed writes per backend"),
+        &["backend"],
+    )
+    .unwrap();
+    let cache_reloads = IntCounterVec::new(
+        Opts::new("cache_reloads_total", "Successful cache reloads"),
+        &["cache"],
+    )
+    .unwrap();
+    let cache_reload_rejections = IntCounterVec::new(
+        Opts::new(
+            "cache_reload_rejections_total",
+            "Cache reloads refused because the file did not validate",
+        ),
+        &["cache"],
+    )
+    .unwrap();
+    let cache_items = IntGaugeVec::new(
+        Opts::new("cache_items", "Number of items in the cache"),
+        &["cache"],
+    )
+    .unwrap();
+    let pending_payloads = IntCounterVec::new(
+        Opts::new(
+            "pending_payloads_total",
+            "Payloads of chips missing from the chips file, held or dropped",
+        ),
+        &["outcome"],
+    )
+    .unwrap();
+    let sensor_info_refreshes = IntCounterVec::new(
+        Opts::new(
+            "sensor_info_refreshes_total",
+            "Sensor info refreshes per backend and outcome, a rebuilt table is briefly missing",
+        ),
+        &["backend", "outcome"],
+    )
+    .unwrap();
+    let sensor_info_changes = IntCounterVec::new(
+        Opts::new(
+            "sensor_info_changes_total",
+            "Sensor info rows upserted or removed by the refreshes",
+        ),
+        &["backend", "change"],
+    )
+    .unwrap();
+    let pm_values = GaugeVec::new(
+        Opts::new("pm_value", "Latest particulate matter value per sensor"),
+        &["chip_id", "sensor_id", "field"],
+    )
+    .unwrap();
+
+    registry.register(Box::new(requests.clone())).unwrap();
+    registry.register(Box::new(auth_failures.clone())).unwrap();
+    registry
+        .register(Box::new(throttled_requests.clone()))
+        .unwrap();
+    registry.register(Box::new(auth_bans.clone())).unwrap();
+    registry
+        .register(Box::new(payload_parse_errors.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(unknown_value_types.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(writer_duration.clone()))
+        .unwrap();
+    registry.register(Box::new(writer_errors.clone())).unwrap();
+    registry.register(Box::new(cache_reloads.clone())).unwrap();
+    registry
+        .register(Box::new(cache_reload_rejections.clone()))
+        .unwrap();
+    registry.register(Box::new(cache_items.clone())).unwrap();
+    registry
+        .register(Box::new(pending_payloads.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(sensor_info_refreshes.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(sensor_info_changes.clone()))
+        .unwrap();
+    registry.register(Box::new(pm_values.clone())).unwrap();
+
+    Metrics {
+        registry,
+        requests,
+        auth_failures,
+        throttled_requests,
+        auth_bans,
+        payload_parse_errors,
+        unknown_value_types,
+        writer_duration,
+        writer_errors,
+        cache_reloads,
+        cache_reload_rejections,
+        cache_items,
+        pending_payloads,
+        sensor_info_refreshes,
+        sensor_info_changes,
+        pm_values,
+    }
+});
+
+pub fn observe_write(backend: &str, elapsed: Duration, ok: bool) {
+    METRICS
+        .writer_duration
+        .with_label_values(&[backend])
+        .observe(elapsed.as_secs_f64());
+    if !ok {
+        METRICS.writer_errors.with_label_values(&[backend]).inc();
+    }
+
+    let attributes = [KeyValue::new("backend", backend.to_owned())];
+    OTEL_METRICS
+        .writer_duration
+        .record(elapsed.as_secs_f64(), &attributes);
+    if !ok {
+        OTEL_METRICS.writer_errors.add(1, &attributes);
+    }
+}
+
+pub fn set_cache_items(cache: &str, items: usize) {
+    METRICS
+        .cache_items
+        .with_label_values(&[cache])
+        .set(items as i64);
+}
+
+pub fn observe_cache_reload(cache: &str, items: usize) {
+    METRICS.cache_reloads.with_label_values(&[cache]).inc();
+    set_cache_items(cache, items);
+}
+
+pub fn observe_cache_reload_rejection(cache: &str) {
+    METRICS
+        .cache_reload_rejections
+        .with_label_values(&[cache])
+        .inc();
+}
+
+/// Counts a received payload, by chip id only for the registered chips.
+pub fn observe_request(chip_id: &str, source: &str, registered: bool) {
+    let chip_id = if registered { chip_id } else { OTHER };
+    METRICS.requests.with_label_values(&[chip_id, source]).inc();
+}
+
+/// Counts a value skipped because its value type has no sensor type, by value
+/// type only for the ones of the firmware and of the field mapping.
+pub fn observe_unknown_value_type(
+    value_type: &str,
+    measure_name_to_field: &HashMap<String, String>,
+) {
+    let value_type =
+        if VALUE_TYPES.contains(&value_type) || measure_name_to_field.contains_key(value_type) {
+            value_type
+        } else {
+            OTHER
+        };
+    METRICS
+        .unknown_value_types
+        .with_label_values(&[value_type])
+        .inc();
+}
+
+/// Counts a payload of an unknown chip, `outcome` is `held` or `dropped`.
+pub fn observe_pending(outcome: &str) {
+    METRICS.pending_payloads.with_label_values(&[outcome]).inc();
+}
+
+/// Counts a sensor info refresh, `outcome` is `unchanged`, `upserted`, `rebuilt`
+/// or `failed`.
+pub fn observe_sensor_info_refresh(backend: &str, outcome: &str, upserted: usize, removed: usize) {
+    METRICS
+        .sensor_info_refreshes
+        .with_label_values(&[backend, outcome])
+        .inc();
+    METRICS
+        .sensor_info_changes
+        .with_label_values(&[backend, "upserted"])
+        .inc_by(upserted as u64);
+    METRICS
+        .sensor_info_changes
+        .with_label_values(&[backend, "removed"])
+        .inc_by(removed as u64);
+}
+
+/// Counts a request refused by the limits, `reason` is `ip_rate`, `chip_rate`,
+/// `banned` or `body_too_large`.
+pub fn observe_throttled(reason: &str) {
+    METRICS
+        .throttled_requests
+        .with_label_values(&[reason])
+        .inc();
+}
+
+/// Keeps the latest PM values as gauges, only when enabled in the perf settings.
+pub fn observe_value(chip_id: &str, sensor_id: &str, field: &str, value: f64) {
+    if !PM_GAUGES_ENABLED.load(Ordering::Relaxed) || (field != P1 && field != P2) {
+        return;
+    }
+    METRICS
+        .pm_values
+        .with_label_values(&[chip_id, sensor_id, field])
+        .set(value);
+}
+
+async fn handler() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(e) = encoder.encode(&METRICS.registry.gather(), &mut buffer) {
+        tracing::error!("Error encoding metrics: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, String::new()).into_response();
+    }
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            encoder.format_type().to_owned(),
+        )],
+        buffer,
+    )
+        .into_response()
+}
+
+/// Serves the `/metrics` endpoint on the perf address.
+pub async fn serve(settings: &crate::config::PerfConfig) {
+    PM_GAUGES_ENABLED.store(settings.pm_gauges, Ordering::Relaxed);
+
+    let addr: SocketAddr = match settings.perf_addr.trim().parse() {
+        Ok(a) => a,
+        Err(e) => {
+            tracing::error!("invalid perf address {}: {}", settings.perf_addr, e);
+            return;
+        }
+    };
+
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::error!("could not bind metrics address {}: {}", addr, e);
+            return;
+        }
+    };
+    tracing::info!("serving metrics on address: {}", addr);
+
+    let app = Router::new().route("/metrics", get(handler));
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!("metrics server error: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bounded_labels() {
+        let other = METRICS.requests.with_label_values(&[OTHER, "test"]).get();
+        observe_request("made-up-chip", "test", false);
+        observe_request("esp8266-1", "test", true);
+        assert_eq!(
+            METRICS.requests.with_label_values(&[OTHER, "test"]).get(),
+            other + 1
+        );
+        assert_eq!(
+            METRICS
+                .requests
+                .with_label_values(&["esp8266-1", "test"])
+                .get(),
+            1
+        );
+
+        let mapping = HashMap::from([("PM10".to_owned(), P1.to_owned())]);
+        observe_unknown_value_type("made-up-type", &mapping);
+        observe_unknown_value_type(crate::sensor_data::SIGNAL, &mapping);
+        observe_unknown_value_type("PM10", &mapping);
+        let labels: Vec<String> = METRICS
+            .registry
+            .gather()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .flat_map(|metric| metric.get_label())
+            .map(|label| label.get_value().to_owned())
+            .collect();
+        assert!(!labels.iter().any(|l| l.starts_with("made-up")));
+        assert!(labels.iter().any(|l| l == crate::sensor_data::SIGNAL));
+        assert!(labels.iter().any(|l| l == "PM10"));
+    }
+}
@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use tracing::Instrument;
+
+use crate::http::ReqState;
+use crate::metrics::METRICS;
+use crate::sensor_data;
+
+const DEFAULT_MQTT_PORT: u16 = 1883;
+const DEFAULT_MQTTS_PORT: u16 = 8883;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Extracts the chip id from a topic, the chip id being the level matched by
+/// the single `+` wildcard of the topic filter.
+pub fn chip_id_from_topic(filter: &str, topic: &str) -> Option<String> {
+    let filter_levels: Vec<&str> = filter.split('/').collect();
+    let topic_levels: Vec<&str> = topic.split('/').collect();
+    if filter_levels.len() != topic_levels.len() {
+        return None;
+    }
+
+    let mut chip_id = None;
+    for (f, t) in filter_levels.iter().zip(topic_levels.iter()) {
+        if *f == "+" {
+            if t.is_empty() || chip_id.is_some() {
+                return None;
+            }
+            chip_id = Some(t.to_string());
+        } else if f != t {
+            return None;
+        }
+    }
+    chip_id
+}
+
+fn mqtt_options(settings: &crate::config::Mqtt) -> anyhow::Result<MqttOptions> {
+    let default_port = match settings.use_tls {
+        true => DEFAULT_MQTTS_PORT,
+        false => DEFAULT_MQTT_PORT,
+    };
+    let (host, port) = match settings.addr.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>()?),
+        None => (settings.addr.as_str(), default_port),
+    };
+
+    let mut options = MqttOptions::new(&settings.client_id, host, port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if !settings.username.is_empty() {
+        options.set_credentials(&settings.username, settings.password.expose());
+    }
+    if settings.use_tls {
+        options.set_transport(Transport::tls_with_default_config());
+    }
+    Ok(options)
+}
+
+/// Subscribes to the configured topic filter and writes every received payload
+/// through the same path used by the http `/write` endpoint.
+pub async fn listen(settings: crate::config::Mqtt, state: ReqState) {
+    let options = match mqtt_options(&settings) {
+        Ok(o) => o,
+        Err(e) => {
+            tracing::error!("invalid mqtt broker address {}: {}", settings.addr, e);
+            return;
+        }
+    };
+
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+
+    tracing::info!(
+        "connecting to mqtt broker: {}, topic: {}",
+        settings.addr,
+        settings.topic
+    );
+
+    loop {
+        let publish = match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                // subscribe on every (re)connection, the session is not persisted
+                if let Err(e) = client
+                    .subscribe(settings.topic.as_str(), QoS::AtLeastOnce)
+                    .await
+                {
+                    tracing::error!("error subscribing to mqtt topic {}: {}", settings.topic, e);
+                }
+                continue;
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => p,
+            Ok(_) => continue,
+            Err(e) => {
+                tracing::error!("mqtt connection error: {}, reconnecting", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+
+        let chip_id = match chip_id_from_topic(&settings.topic, &publish.topic) {
+            Some(c) => c,
+            None => {
+                tracing::debug!("ignoring message on topic: {}", publish.topic);
+                continue;
+            }
+        };
+
+        let payload = match serde_json::from_slice::<sensor_data::Payload>(&publish.payload) {
+            Ok(p) => p,
+            Err(e) => {
+                METRICS.payload_parse_errors.inc();
+                tracing::error!(
+                    "invalid payload from chip {} on topic {}: {}",
+                    chip_id,
+                    publish.topic,
+                    e
+                );
+                continue;
+            }
+        };
+
+        tracing::debug!("mqtt message received from sensor: {}", chip_id);
+        crate::metrics::observe_request(&chip_id, "mqtt", state.registry.contains_chip(&chip_id));
+        state
+            .last_seen
+            .write()
+            .unwrap()
+            .insert(chip_id.clone(), chrono::Utc::now());
+
+        let file_path = match sensor_data::archive_file_path(&state.sensor_data_dir, &chip_id) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+
+        match sensor_data::write(
+            &state.write_context(),
+            &file_path,
+            &chip_id,
+            chrono::Utc::now().timestamp(),
+            payload,
+        )
+        .instrument(tracing::info_span!("mqtt.write", chip_id = %chip_id))
+        .await
+        // the error is not Send, it can't be held across the await of hold
+        .map_err(|e| e.to_string())
+        {
+            Ok(sensor_data::WriteOutcome::UnknownChip(payload)) => {
+                if let Err(e) = state.pending.hold(&chip_id, "mqtt", payload).await {
+                    tracing::error!("Error trying to hold data for sensor {}: {}", &chip_id, e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!("Error trying to write data for sensor {}: {}", &chip_id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chip_id_from_topic() {
+        let filter = "sensorcommunity/+/data";
+        assert_eq!(
+            chip_id_from_topic(filter, "sensorcommunity/esp32-1234/data"),
+            Some("esp32-1234".to_owned())
+        );
+        assert_eq!(chip_id_from_topic(filter, "sensorcommunity//data"), None);
+        assert_eq!(
+            chip_id_from_topic(filter, "sensorcommunity/esp32-1234"),
+            None
+        );
+        assert_eq!(chip_id_from_topic(filter, "other/esp32-1234/data"), None);
+        assert_eq!(chip_id_from_topic("+/+", "a/b"), None);
+    }
+}
@@ -0,0 +1,543 @@
+//! Payloads sent by chips missing from the chips file. Instead of being dropped they are
+//! held, one json line per payload in `<dir>/<chip_id>.jsonl`, until the chip is approved:
+//! the chip and the sensors inferred from its value types are then added to the csv files
+//! and the held payloads are written with their original timestamps.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::{self, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ChipInfo, SensorInfo,
+    http::ReqState,
+    metrics,
+    registry::RegistryReport,
+    sensor_data::{self, Payload, WriteOutcome},
+};
+
+const EXTENSION: &str = "jsonl";
+/// locked while the files are changed, by the server and the cli alike
+const LOCK_FILE: &str = ".lock";
+
+/// A payload received from a chip that is not registered yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeldPayload {
+    pub received: DateTime<Utc>,
+    /// ip address of the http client, or `mqtt`
+    pub sender: String,
+    pub payload: Payload,
+}
+
+/// Summary of what a pending chip has sent so far.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingChip {
+    pub chip_id: String,
+    pub payloads: usize,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub senders: Vec<String>,
+    pub value_types: Vec<String>,
+    /// sensor types inferred from the value types, the sensors added on approval
+    pub sensor_types: Vec<String>,
+}
+
+/// Location of the chip and optional sensor ids by sensor type, the sensors
+/// without an id are registered as `<chip_id>-<sensor_type>`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Approval {
+    pub lat: f64,
+    pub lon: f64,
+    #[serde(default)]
+    pub city: String,
+    #[serde(default)]
+    pub info: String,
+    #[serde(default)]
+    pub sensor_ids: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApprovalReport {
+    pub chip: ChipInfo,
+    pub sensors: Vec<SensorInfo>,
+    pub registry: RegistryReport,
+    /// held payloads written, zero when the registry refused the new rows
+    pub backfilled: usize,
+    pub failed: usize,
+}
+
+/// The length and modification time of a file, a change of the file by another
+/// process changes it.
+type FileStamp = (u64, Option<SystemTime>);
+
+fn stamp(metadata: &fs::Metadata) -> FileStamp {
+    (metadata.len(), metadata.modified().ok())
+}
+
+/// The folder of held payloads, shared by the http and mqtt listeners. The
+/// files are changed under a lock of the folder, so the cli can list and
+/// approve while the server runs.
+#[derive(Clone)]
+pub struct PendingStore {
+    dir: Option<PathBuf>,
+    max_chips: usize,
+    max_payloads: usize,
+    /// payloads held per chip, with the stamp of the file they were counted in
+    counts: Arc<Mutex<HashMap<String, (FileStamp, usize)>>>,
+}
+
+/// Chip ids become file names, only plain names are accepted.
+fn valid_chip_id(chip_id: &str) -> bool {
+    !chip_id.is_empty()
+        && !chip_id.starts_with('.')
+        && chip_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+impl PendingStore {
+    pub fn new(settings: &crate::config::Pending) -> Result<Self> {
+        let dir = settings.dir.as_os_str().to_string_lossy();
+        let dir = if dir.trim().is_empty() {
+            None
+        } else {
+            let dir = PathBuf::from(shellexpand::env(&dir)?.as_ref());
+            fs::create_dir_all(&dir)?;
+            Some(dir)
+        };
+        Ok(PendingStore {
+            dir,
+            max_chips: settings.max_chips,
+            max_payloads: settings.max_payloads_per_chip,
+            counts: Default::default(),
+        })
+    }
+
+    /// Locks the folder until the returned file is dropped.
+    fn lock(&self) -> Result<fs::File> {
+        let dir = self
+            .dir
+            .as_ref()
+            .ok_or_else(|| anyhow!("holding payloads of unknown chips is disabled"))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(dir.join(LOCK_FILE))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    /// The number of payloads held in a file, counted again only when the
+    /// file changed since the last count.
+    fn count(&self, chip_id: &str, path: &Path, metadata: &fs::Metadata) -> Result<usize> {
+        let stamp = stamp(metadata);
+        if let Some((counted, count)) = self.counts.lock().unwrap().get(chip_id)
+            && *counted == stamp
+        {
+            return Ok(*count);
+        }
+        let count = BufReader::new(fs::File::open(path)?).lines().count();
+        self.counts
+            .lock()
+            .unwrap()
+            .insert(chip_id.to_owned(), (stamp, count));
+        Ok(count)
+    }
+
+    fn file_path(&self, chip_id: &str) -> Result<PathBuf> {
+        let dir = self
+            .dir
+            .as_ref()
+            .ok_or_else(|| anyhow!("holding payloads of unknown chips is disabled"))?;
+        if !valid_chip_id(chip_id) {
+            return Err(anyhow!("invalid chip id {:?}", chip_id));
+        }
+        Ok(dir.join(format!("{}.{}", chip_id, EXTENSION)))
+    }
+
+    fn chip_ids(&self) -> Result<Vec<String>> {
+        let Some(dir) = &self.dir else {
+            return Ok(vec![]);
+        };
+        let mut ids = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == EXTENSION)
+                && let Some(id) = path.file_stem().and_then(|s| s.to_str())
+            {
+                ids.push(id.to_owned());
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Holds a payload of an unknown chip, returns false when it was dropped
+    /// because holding is disabled or a limit was reached. The files are
+    /// written on the blocking threads.
+    pub async fn hold(&self, chip_id: &str, sender: &str, payload: Payload) -> Result<bool> {
+        if self.dir.is_none() {
+            metrics::observe_pending("dropped");
+            return Ok(false);
+        }
+        let (store, chip_id, sender) = (self.clone(), chip_id.to_owned(), sender.to_owned());
+        tokio::task::spawn_blocking(move || store.hold_blocking(&chip_id, &sender, payload)).await?
+    }
+
+    fn hold_blocking(&self, chip_id: &str, sender: &str, payload: Payload) -> Result<bool> {
+        let path = self.file_path(chip_id)?;
+
+        let _lock = self.lock()?;
+        let held = if let Ok(metadata) = fs::metadata(&path) {
+            self.count(chip_id, &path, &metadata)?
+        } else {
+            if self.chip_ids()?.len() >= self.max_chips {
+                tracing::warn!(
+                    "dropping payload of unknown chip {}, already holding {} chips",
+                    chip_id,
+                    self.max_chips
+                );
+                metrics::observe_pending("dropped");
+                return Ok(false);
+            }
+            tracing::info!(
+                "holding payloads of unknown chip {} sent by {} until approved",
+                chip_id,
+                sender
+            );
+            0
+        };
+        if held >= self.max_payloads {
+            tracing::debug!(
+                "dropping payload of unknown chip {}, already holding {} payloads",
+                chip_id,
+                held
+            );
+            metrics::observe_pending("dropped");
+            return Ok(false);
+        }
+
+        let line = serde_json::to_string(&HeldPayload {
+            received: Utc::now(),
+            sender: sender.to_owned(),
+            payload,
+        })?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        writeln!(file, "{}", line)?;
+        self.counts
+            .lock()
+            .unwrap()
+            .insert(chip_id.to_owned(), (stamp(&file.metadata()?), held + 1));
+        metrics::observe_pending("held");
+        Ok(true)
+    }
+
+    /// The payloads held for a chip, oldest first.
+    pub fn read(&self, chip_id: &str) -> Result<Vec<HeldPayload>> {
+        if !self.file_path(chip_id)?.exists() {
+            return Err(anyhow!("no payloads held for chip id {}", chip_id));
+        }
+        Ok(self.read_lines(chip_id)?.0)
+    }
+
+    /// The payloads held for a chip and the number of lines they were read
+    /// from, the malformed lines are skipped.
+    fn read_lines(&self, chip_id: &str) -> Result<(Vec<HeldPayload>, usize)> {
+        let path = self.file_path(chip_id)?;
+        if !path.exists() {
+            return Ok((vec![], 0));
+        }
+        let mut payloads = vec![];
+        let mut lines = 0;
+        for (i, line) in BufReader::new(fs::File::open(&path)?).lines().enumerate() {
+            let line = line?;
+            lines += 1;
+            match serde_json::from_str(&line) {
+                Ok(p) => payloads.push(p),
+                Err(e) => tracing::warn!("{} line {}: {}", path.display(), i + 1, e),
+            }
+        }
+        Ok((payloads, lines))
+    }
+
+    /// Replaces the first lines held for a chip with the kept payloads, the
+    /// ones held since the lines were read follow them.
+    fn replace_lines(&self, chip_id: &str, count: usize, kept: &[HeldPayload]) -> Result<()> {
+        let path = self.file_path(chip_id)?;
+        let _lock = self.lock()?;
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            // dropped meanwhile
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = kept
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<String>>>()?;
+        for line in BufReader::new(file).lines().skip(count) {
+            lines.push(line?);
+        }
+        if lines.is_empty() {
+            fs::remove_file(&path)?;
+            return Ok(());
+        }
+        let tmp_path = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Drops the payloads held for a chip, returns false if there were none.
+    pub fn remove(&self, chip_id: &str) -> Result<bool> {
+        let path = self.file_path(chip_id)?;
+        let _lock = self.lock()?;
+        match fs::remove_file(path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Summaries of all the pending chips, sorted by chip id.
+    pub fn list(
+        &self,
+        measure_name_to_sensor_type: &HashMap<String, String>,
+    ) -> Result<Vec<PendingChip>> {
+        let mut chips = vec![];
+        for chip_id in self.chip_ids()? {
+            let payloads = self.read(&chip_id)?;
+            let (Some(first), Some(last)) = (payloads.first(), payloads.last()) else {
+                continue;
+            };
+            let senders: BTreeSet<&str> = payloads.iter().map(|p| p.sender.as_str()).collect();
+            let value_types: BTreeSet<&str> = payloads
+                .iter()
+                .flat_map(|p| p.payload.value_types())
+                .collect();
+            chips.push(PendingChip {
+                chip_id: chip_id.clone(),
+                payloads: payloads.len(),
+                first_seen: first.received,
+                last_seen: last.received,
+                senders: senders.into_iter().map(str::to_owned).collect(),
+                sensor_types: infer_sensor_types(&value_types, measure_name_to_sensor_type),
+                value_types: value_types.into_iter().map(str::to_owned).collect(),
+            });
+        }
+        Ok(chips)
+    }
+}
+
+fn infer_sensor_types(
+    value_types: &BTreeSet<&str>,
+    measure_name_to_sensor_type: &HashMap<String, String>,
+) -> Vec<String> {
+    let types: BTreeSet<&String> = value_types
+        .iter()
+        .filter_map(|t| measure_name_to_sensor_type.get(*t))
+        .collect();
+    types.into_iter().cloned().collect()
+}
+
+/// Registers a pending chip with the sensors inferred from its payloads, then writes
+/// the held payloads and drops them. When the registry refuses the new rows nothing
+/// is written and the payloads stay held, as do the payloads failing to be written.
+pub async fn approve(
+    state: &ReqState,
+    chip_id: &str,
+    approval: Approval,
+) -> Result<ApprovalReport> {
+    let held = state.pending.read(chip_id)?;
+    let value_types: BTreeSet<&str> = held.iter().flat_map(|p| p.payload.value_types()).collect();
+    let known_types = state.registry.sensor_types();
+
+    let chip = ChipInfo {
+        chip_id: chip_id.to_owned(),
+        lat: approval.lat,
+        lon: approval.lon,
+        city: approval.city,
+        info: approval.info,
+        enabled: true,
+        valid_from: None,
+        valid_to: None,
+    };
+    let sensors: Vec<SensorInfo> =
+        infer_sensor_types(&value_types, &state.measure_name_to_sensor_type)
+            .into_iter()
+            .filter(|t| known_types.contains(t))
+            .map(|sensor_type| SensorInfo {
+                chip_id: chip_id.to_owned(),
+                sensor_id: approval
+                    .sensor_ids
+                    .get(&sensor_type)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{}-{}", chip_id, sensor_type)),
+                sensor_type,
+                enabled: true,
+            })
+            .collect();
+
+    let registry = {
+        let (registry, chip, sensors) = (state.registry.clone(), chip.clone(), sensors.clone());
+        tokio::task::spawn_blocking(move || {
+            registry.enroll(chip, sensors).map_err(|e| anyhow!("{}", e))
+        })
+        .await??
+    };
+    let mut report = ApprovalReport {
+        chip,
+        sensors,
+        registry,
+        backfilled: 0,
+        failed: 0,
+    };
+    if !report.registry.applied {
+        return Ok(report);
+    }
+
+    // the chip is registered, the payloads held while it was being registered
+    // are read by the next round; the failed ones are kept first in the file
+    let mut failed: Vec<HeldPayload> = vec![];
+    loop {
+        let (held, lines) = state.pending.read_lines(chip_id)?;
+        if lines == failed.len() {
+            break;
+        }
+        for p in held.into_iter().skip(failed.len()) {
+            let file_path =
+                sensor_data::archive_file_path_at(&state.sensor_data_dir, chip_id, p.received)?;
+            let res = sensor_data::write(
+                &state.write_context(),
+                &file_path,
+                chip_id,
+                p.received.timestamp(),
+                p.payload.clone(),
+            )
+            .await
+            .map_err(|e| e.to_string());
+            match res {
+                Ok(WriteOutcome::Written) => report.backfilled += 1,
+                Ok(_) => failed.push(p),
+                Err(e) => {
+                    tracing::error!("Error trying to back-fill data for chip {}: {}", chip_id, e);
+                    failed.push(p);
+                }
+            }
+        }
+        state.pending.replace_lines(chip_id, lines, &failed)?;
+    }
+    report.failed = failed.len();
+    tracing::info!(
+        "approved chip {} with {} sensors, back-filled {} payloads, {} failed",
+        chip_id,
+        report.sensors.len(),
+        report.backfilled,
+        report.failed
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hold_and_list() {
+        let dir = TempDir::new("pending").unwrap();
+        let store = PendingStore::new(&crate::config::Pending {
+            dir: dir.path().to_owned(),
+            max_chips: 1,
+            max_payloads_per_chip: 2,
+        })
+        .unwrap();
+        let payload: Payload = serde_json::from_str(
+            r#"{"software_version":"1","sensordatavalues":[{"value_type":"SDS_P1","value":"3.1"},{"value_type":"temperature","value":"20"}]}"#,
+        )
+        .unwrap();
+
+        assert!(
+            store
+                .hold("../chips", "127.0.0.1", payload.clone())
+                .await
+                .is_err()
+        );
+        assert!(
+            store
+                .hold("esp8266-1", "127.0.0.1", payload.clone())
+                .await
+                .unwrap()
+        );
+        assert!(
+            store
+                .hold("esp8266-1", "mqtt", payload.clone())
+                .await
+                .unwrap()
+        );
+        // too many payloads and too many chips
+        assert!(
+            !store
+                .hold("esp8266-1", "mqtt", payload.clone())
+                .await
+                .unwrap()
+        );
+        assert!(
+            !store
+                .hold("esp8266-2", "mqtt", payload.clone())
+                .await
+                .unwrap()
+        );
+
+        let m2t = HashMap::from([("SDS_P1".to_owned(), "SDS011".to_owned())]);
+        let chips = store.list(&m2t).unwrap();
+        assert_eq!(chips.len(), 1);
+        assert_eq!(chips[0].payloads, 2);
+        assert_eq!(chips[0].senders, vec!["127.0.0.1", "mqtt"]);
+        assert_eq!(chips[0].value_types, vec!["SDS_P1", "temperature"]);
+        assert_eq!(chips[0].sensor_types, vec!["SDS011"]);
+
+        // the payloads held after the ones replayed are kept, after the kept ones
+        let held = store.read("esp8266-1").unwrap();
+        store.replace_lines("esp8266-1", 1, &[]).unwrap();
+        assert_eq!(store.read("esp8266-1").unwrap()[0].sender, "mqtt");
+        assert_eq!(store.read_lines("esp8266-1").unwrap().1, 1);
+        store.replace_lines("esp8266-1", 0, &held[..1]).unwrap();
+        let senders: Vec<String> = store
+            .read("esp8266-1")
+            .unwrap()
+            .into_iter()
+            .map(|p| p.sender)
+            .collect();
+        assert_eq!(senders, vec!["127.0.0.1", "mqtt"]);
+
+        // the count follows the changes of another process, as the cli
+        let cli = PendingStore::new(&crate::config::Pending {
+            dir: dir.path().to_owned(),
+            max_chips: 1,
+            max_payloads_per_chip: 2,
+        })
+        .unwrap();
+        cli.replace_lines("esp8266-1", 1, &[]).unwrap();
+        assert!(store.hold("esp8266-1", "mqtt", payload).await.unwrap());
+        assert_eq!(store.read_lines("esp8266-1").unwrap().1, 2);
+
+        assert!(store.remove("esp8266-1").unwrap());
+        assert_eq!(store.read_lines("esp8266-1").unwrap().1, 0);
+        assert!(store.list(&m2t).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,679 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use serde::Serialize;
+
+use crate::cache::{CacheKey, ReloadReport, Source, SourceWatcher, Validator, open_source};
+use crate::geodata::Enricher;
+use crate::sensor_data::{MUNICIPALITY, SensorInfoRecord};
+use crate::{ChipInfo, SensorInfo};
+
+pub mod validity;
+
+/// Chips and sensors loaded together, with the indexes used for the lookups.
+#[derive(Default)]
+struct RegistryData {
+    /// the rows of each chip, sorted by validity start
+    chips: HashMap<String, Vec<ChipInfo>>,
+    /// keyed by `chip_id:sensor_type`
+    sensors: HashMap<String, SensorInfo>,
+    by_sensor_id: HashMap<String, String>,
+    by_city: HashMap<String, Vec<String>>,
+    by_sensor_type: HashMap<String, Vec<String>>,
+    /// tags resolved from the position of each chip row, keyed by row id
+    geo_tags: HashMap<String, Vec<(String, String)>>,
+}
+
+impl RegistryData {
+    fn new(chips: HashMap<String, ChipInfo>, sensors: HashMap<String, SensorInfo>) -> Self {
+        let mut data = RegistryData {
+            sensors,
+            ..Default::default()
+        };
+        for chip in chips.into_values() {
+            data.by_city
+                .entry(chip.city.clone())
+                .or_default()
+                .push(chip.chip_id.clone());
+            data.chips
+                .entry(chip.chip_id.clone())
+                .or_default()
+                .push(chip);
+        }
+        for versions in data.chips.values_mut() {
+            versions.sort_by_key(|c| c.valid_from);
+        }
+        for ids in data.by_city.values_mut() {
+            ids.sort();
+            ids.dedup();
+        }
+        for (key, sensor) in &data.sensors {
+            data.by_sensor_id
+                .insert(sensor.sensor_id.clone(), key.clone());
+            data.by_sensor_type
+                .entry(sensor.sensor_type.clone())
+                .or_default()
+                .push(key.clone());
+        }
+        data
+    }
+}
+
+/// Outcome of loading both files, nothing is swapped in unless both are valid.
+#[derive(Debug, Clone, Serialize)]
+pub struct RegistryReport {
+    pub chips: ReloadReport,
+    pub sensors: ReloadReport,
+    pub applied: bool,
+}
+
+impl RegistryReport {
+    pub fn is_valid(&self) -> bool {
+        self.chips.is_valid() && self.sensors.is_valid()
+    }
+}
+
+impl fmt::Display for RegistryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n{}", self.chips, self.sensors)
+    }
+}
+
+/// Refuses the rows of a chip whose validity periods overlap, rows are keyed by
+/// chip and start so two rows of a chip without a start are already duplicates.
+fn check_periods(chips: &HashMap<String, ChipInfo>, report: &mut ReloadReport) {
+    let mut by_chip: HashMap<&str, Vec<&ChipInfo>> = HashMap::new();
+    for chip in chips.values() {
+        by_chip.entry(&chip.chip_id).or_default().push(chip);
+    }
+    let mut errors = Vec::new();
+    for versions in by_chip.values_mut() {
+        versions.sort_by_key(|c| c.valid_from);
+        for pair in versions.windows(2) {
+            let (prev, next) = (pair[0], pair[1]);
+            let overlaps = match (prev.valid_to, next.valid_from) {
+                (Some(to), Some(from)) => to > from,
+                _ => true,
+            };
+            if overlaps {
+                let line = report.lines.get(&next.id()).copied().unwrap_or_default();
+                errors.push((
+                    line,
+                    format!(
+                        "chip {} period starting {} overlaps the one at line {}",
+                        next.chip_id,
+                        next.valid_from.map(|t| t.to_rfc3339()).unwrap_or_default(),
+                        report.lines.get(&prev.id()).copied().unwrap_or_default()
+                    ),
+                ));
+            }
+        }
+    }
+    errors.sort();
+    for (line, message) in errors {
+        report.error(line, message);
+    }
+}
+
+/// Refuses the sensors reusing the id of a sensor of another chip or type, the
+/// lookups by sensor id would return either.
+fn check_sensor_ids(sensors: &HashMap<String, SensorInfo>, report: &mut ReloadReport) {
+    let mut by_id: HashMap<&str, Vec<&SensorInfo>> = HashMap::new();
+    for sensor in sensors.values() {
+        by_id.entry(&sensor.sensor_id).or_default().push(sensor);
+    }
+    let mut errors = Vec::new();
+    for duplicates in by_id.values_mut().filter(|d| d.len() > 1) {
+        let line_of = |s: &SensorInfo| report.lines.get(&s.id()).copied().unwrap_or_default();
+        duplicates.sort_by_key(|s| line_of(s));
+        let first = duplicates[0];
+        for sensor in &duplicates[1..] {
+            errors.push((
+                line_of(sensor),
+                format!(
+                    "sensor id {} of chip {} is already used at line {}",
+                    sensor.sensor_id,
+                    sensor.chip_id,
+                    line_of(first)
+                ),
+            ));
+        }
+    }
+    errors.sort();
+    for (line, message) in errors {
+        report.error(line, message);
+    }
+}
+
+/// Resolves the tags of every chip row, warning about the cities that do not
+/// match the municipality found at the position.
+fn resolve_geo_tags(
+    geo: &Enricher,
+    chips: &HashMap<String, ChipInfo>,
+) -> HashMap<String, Vec<(String, String)>> {
+    let mut geo_tags = HashMap::new();
+    for (id, chip) in chips {
+        let tags = geo.tags(chip.lat, chip.lon);
+        match tags.iter().find(|(k, _)| k == MUNICIPALITY) {
+            Some((_, municipality)) if !municipality.eq_ignore_ascii_case(chip.city.trim()) => {
+                tracing::warn!(
+                    "chip {} is in {} but its city is {:?}",
+                    id,
+                    municipality,
+                    chip.city
+                );
+            }
+            Some(_) => {}
+            None => tracing::debug!("chip {} is outside the known boundaries", id),
+        }
+        geo_tags.insert(id.clone(), tags);
+    }
+    geo_tags
+}
+
+/// The rows last read from each source, reused when only the other one changed.
+#[derive(Default)]
+struct Loaded {
+    chips: Option<(HashMap<String, ChipInfo>, ReloadReport)>,
+    sensors: Option<(HashMap<String, SensorInfo>, ReloadReport)>,
+}
+
+/// Owns the chips and the sensors read from their sources, every sensor belongs
+/// to a known chip: a source breaking the relation is refused as a whole.
+#[derive(Clone)]
+pub struct Registry {
+    data: Arc<RwLock<RegistryData>>,
+    chips_source: Arc<dyn Source<ChipInfo>>,
+    sensors_source: Arc<dyn Source<SensorInfo>>,
+    sensor_types: Arc<Vec<String>>,
+    geo: Option<Arc<Enricher>>,
+    // serializes the edits, each one rewrites a whole source
+    edit_lock: Arc<Mutex<()>>,
+    loaded: Arc<Mutex<Loaded>>,
+}
+
+impl Registry {
+    /// Loads both sources, failing if they can not be read or do not validate.
+    /// See [crate::cache::open_source] for the locations accepted.
+    pub fn load(
+        chips_location: &str,
+        sensors_location: &str,
+        sensor_types: Vec<String>,
+        poll_every: Duration,
+        geo: Option<Arc<Enricher>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let registry = Registry {
+            data: Default::default(),
+            chips_source: open_source(chips_location, poll_every)?.into(),
+            sensors_source: open_source(sensors_location, poll_every)?.into(),
+            sensor_types: Arc::new(sensor_types),
+            geo,
+            edit_lock: Default::default(),
+            loaded: Default::default(),
+        };
+
+        let (data, report) = registry.read_sources(true, true)?;
+        if !report.is_valid() {
+            return Err(report.to_string().into());
+        }
+        tracing::info!(
+            "Loaded {} chips from {} and {} sensors from {}",
+            report.chips.items,
+            registry.chips_source.location(),
+            report.sensors.items,
+            registry.sensors_source.location(),
+        );
+        crate::metrics::set_cache_items(ChipInfo::NAME, report.chips.items);
+        crate::metrics::set_cache_items(SensorInfo::NAME, report.sensors.items);
+        *registry.data.write().unwrap() = data;
+        Ok(registry)
+    }
+
+    /// Reads the chips and the sensors, the source not read is taken as it was
+    /// last read. The sensors are checked against the chips in both cases.
+    fn read_sources(
+        &self,
+        read_chips: bool,
+        read_sensors: bool,
+    ) -> Result<(RegistryData, RegistryReport), Box<dyn Error>> {
+        let mut loaded = self.loaded.lock().unwrap();
+        let (chips, chips_report) = match &loaded.chips {
+            Some(cached) if !read_chips => cached.clone(),
+            _ => {
+                let chip_validator: Validator<ChipInfo> =
+                    Arc::new(|chip: &ChipInfo| chip.validate());
+                let (chips, mut report) = self.chips_source.load(&chip_validator)?;
+                check_periods(&chips, &mut report);
+                loaded.chips = Some((chips.clone(), report.clone()));
+                (chips, report)
+            }
+        };
+
+        // the sensors are checked against the chips just read, not the ones in use
+        let chip_ids: HashSet<String> = chips.values().map(|c| c.chip_id.clone()).collect();
+        let sensor_types = self.sensor_types.clone();
+        let sensor_validator: Validator<SensorInfo> = Arc::new(move |sensor: &SensorInfo| {
+            sensor.validate(&sensor_types)?;
+            if !chip_ids.contains(&sensor.chip_id) {
+                return Err(format!("unknown chip id {}", sensor.chip_id));
+            }
+            Ok(())
+        });
+        let (sensors, mut sensors_report) = match &loaded.sensors {
+            // the refused rows are not kept, a source with errors is read again
+            Some((sensors, report)) if !read_sensors && report.is_valid() => {
+                let mut report = report.clone();
+                let mut errors = Vec::new();
+                let sensors: HashMap<String, SensorInfo> = sensors
+                    .iter()
+                    .filter(|(id, sensor)| match sensor_validator(sensor) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            errors.push((report.lines.get(*id).copied().unwrap_or_default(), e));
+                            false
+                        }
+                    })
+                    .map(|(id, sensor)| (id.clone(), sensor.clone()))
+                    .collect();
+                errors.sort();
+                for (line, message) in errors {
+                    report.error(line, message);
+                }
+                report.items = sensors.len();
+                (sensors, report)
+            }
+            _ => {
+                let (sensors, report) = self.sensors_source.load(&sensor_validator)?;
+                loaded.sensors = Some((sensors.clone(), report.clone()));
+                (sensors, report)
+            }
+        };
+        drop(loaded);
+        check_sensor_ids(&sensors, &mut sensors_report);
+
+        let geo_tags = match &self.geo {
+            Some(geo) => resolve_geo_tags(geo, &chips),
+            None => HashMap::new(),
+        };
+
+        Ok((
+            RegistryData {
+                geo_tags,
+                ..RegistryData::new(chips, sensors)
+            },
+            RegistryReport {
+                chips: chips_report,
+                sensors: sensors_report,
+                applied: false,
+            },
+        ))
+    }
+
+    /// Reloads both sources, the current data is kept if either can not be loaded
+    /// or has invalid rows, the returned report tells which rows.
+    pub fn reload(&self) -> Result<RegistryReport, Box<dyn Error>> {
+        self.reload_sources(true, true)
+    }
+
+    /// Reloads the sources that changed, see [Registry::reload].
+    fn reload_sources(&self, chips: bool, sensors: bool) -> Result<RegistryReport, Box<dyn Error>> {
+        let (data, mut report) = self.read_sources(chips, sensors)?;
+        if !report.is_valid() {
+            tracing::error!("Refusing to reload the registry:\n{}", report);
+            for r in [&report.chips, &report.sensors] {
+                if !r.is_valid() {
+                    crate::metrics::observe_cache_reload_rejection(r.cache);
+                }
+            }
+            return Ok(report);
+        }
+
+        report.applied = true;
+        report.chips.applied = true;
+        report.sensors.applied = true;
+        tracing::info!(
+            "Successfully reloaded the registry, chips: {}, sensors: {}",
+            report.chips.items,
+            report.sensors.items,
+        );
+        crate::metrics::observe_cache_reload(ChipInfo::NAME, report.chips.items);
+        crate::metrics::observe_cache_reload(SensorInfo::NAME, report.sensors.items);
+        *self.data.write().unwrap() = data;
+        Ok(report)
+    }
+
+    /// Reloads the registry whenever one of its sources changes, the receiver is
+    /// notified after each successful reload. Only the changed source is read
+    /// again, on the blocking threads.
+    pub fn watch(
+        &self,
+    ) -> Result<(Vec<SourceWatcher>, tokio::sync::watch::Receiver<u64>), Box<dyn Error>> {
+        let (watch_tx, watch_rx) = tokio::sync::watch::channel(0u64);
+        let watch_tx = Arc::new(watch_tx);
+
+        let on_change = |chips: bool, sensors: bool| {
+            let registry = self.clone();
+            let watch_tx = watch_tx.clone();
+            Box::new(move || {
+                let registry = registry.clone();
+                let watch_tx = watch_tx.clone();
+                tokio::task::spawn_blocking(move || {
+                    match registry.reload_sources(chips, sensors) {
+                        Ok(report) if report.applied => watch_tx.send_modify(|v| *v += 1),
+                        Ok(_) => {}
+                        Err(error) => {
+                            tracing::error!("Error reloading the registry: {:?}", error)
+                        }
+                    }
+                });
+            })
+        };
+        let watchers = vec![
+            self.chips_source.watch(on_change(true, false))?,
+            self.sensors_source.watch(on_change(false, true))?,
+        ];
+        Ok((watchers, watch_rx))
+    }
+
+    /// The tags resolved from the position of a chip row, empty without enrichment.
+    pub fn geo_tags(&self, chip: &ChipInfo) -> Vec<(String, String)> {
+        self.data
+            .read()
+            .unwrap()
+            .geo_tags
+            .get(&chip.id())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn sensor_types(&self) -> &[String] {
+        &self.sensor_types
+    }
+
+    /// The chip as it is now.
+    pub fn chip(&self, chip_id: &str) -> Option<ChipInfo> {
+        self.chip_at(chip_id, chrono::Utc::now().timestamp())
+    }
+
+    /// The chip as it was at the given unix timestamp in seconds.
+    pub fn chip_at(&self, chip_id: &str, timestamp: i64) -> Option<ChipInfo> {
+        self.data
+            .read()
+            .unwrap()
+            .chips
+            .get(chip_id)?
+            .iter()
+            .find(|c| c.is_valid_at(timestamp))
+            .cloned()
+    }
+
+    pub fn contains_chip(&self, chip_id: &str) -> bool {
+        self.data.read().unwrap().chips.contains_key(chip_id)
+    }
+
+    /// All the rows of all the chips, sorted by id and validity.
+    pub fn chips(&self) -> Vec<ChipInfo> {
+        let mut chips: Vec<ChipInfo> = self
+            .data
+            .read()
+            .unwrap()
+            .chips
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        chips.sort_by(|a, b| (&a.chip_id, a.valid_from).cmp(&(&b.chip_id, b.valid_from)));
+        chips
+    }
+
+    /// The chips currently in the city.
+    pub fn chips_in_city(&self, city: &str) -> Vec<ChipInfo> {
+        let ids = match self.data.read().unwrap().by_city.get(city) {
+            Some(ids) => ids.clone(),
+            None => return vec![],
+        };
+        ids.iter()
+            .filter_map(|id| self.chip(id))
+            .filter(|c| c.city == city)
+            .collect()
+    }
+
+    /// All the sensors, sorted by chip and sensor type.
+    pub fn sensors(&self) -> Vec<SensorInfo> {
+        let mut sensors: Vec<SensorInfo> = self
+            .data
+            .read()
+            .unwrap()
+            .sensors
+            .values()
+            .cloned()
+            .collect();
+        sensors.sort_by_key(|s| s.id());
+        sensors
+    }
+
+    pub fn sensor(&self, sensor_id: &str) -> Option<SensorInfo> {
+        let data = self.data.read().unwrap();
+        data.by_sensor_id
+            .get(sensor_id)
+            .and_then(|key| data.sensors.get(key).cloned())
+    }
+
+    pub fn sensors_of_chip(&self, chip_id: &str) -> Vec<SensorInfo> {
+        let mut sensors: Vec<SensorInfo> = self
+            .data
+            .read()
+            .unwrap()
+            .sensors
+            .values()
+            .filter(|s| s.chip_id == chip_id)
+            .cloned()
+            .collect();
+        sensors.sort_by_key(|s| s.id());
+        sensors
+    }
+
+    pub fn sensors_of_type(&self, sensor_type: &str) -> Vec<SensorInfo> {
+        let data = self.data.read().unwrap();
+        let mut sensors: Vec<SensorInfo> = data
+            .by_sensor_type
+            .get(sensor_type)
+            .into_iter()
+            .flatten()
+            .filter_map(|key| data.sensors.get(key).cloned())
+            .collect();
+        sensors.sort_by_key(|s| s.id());
+        sensors
+    }
+
+    /// The id of the enabled sensor of the given type on a chip.
+    pub fn sensor_id(&self, chip_id: &str, sensor_type: &str) -> anyhow::Result<String> {
+        let cache_id = format!("{}:{}", chip_id, sensor_type);
+        match self.data.read().unwrap().sensors.get(&cache_id) {
+            Some(info) if !info.enabled => Err(anyhow!("sensor {} is disabled", info.sensor_id)),
+            Some(info) => Ok(info.sensor_id.to_owned()),
+            None => Err(anyhow!("missing sensory id for key: {}", cache_id)),
+        }
+    }
+
+    /// The records sent to the writers' sensor info tables, one for each period
+    /// of the chip, skipping disabled chips and sensors.
+    pub fn sensor_info_records(&self) -> Vec<SensorInfoRecord> {
+        let data = self.data.read().unwrap();
+        let mut records = Vec::new();
+        for sensor in data.sensors.values().filter(|s| s.enabled) {
+            // the relation is enforced on load, every sensor has its chip
+            let Some(versions) = data.chips.get(&sensor.chip_id) else {
+                continue;
+            };
+            for chip in versions.iter().filter(|c| c.enabled) {
+                records.push(SensorInfoRecord {
+                    sensor_id: sensor.sensor_id.clone(),
+                    sensor_type: sensor.sensor_type.clone(),
+                    chip_id: sensor.chip_id.clone(),
+                    lat: chip.lat,
+                    lon: chip.lon,
+                    city: chip.city.clone(),
+                    info: chip.info.clone(),
+                    valid_from: chip.valid_from,
+                    valid_to: chip.valid_to,
+                });
+            }
+        }
+        records
+    }
+
+    /// Adds or updates a chip, writing the chips file back and reloading the registry.
+    pub fn upsert_chip(&self, chip: ChipInfo) -> Result<RegistryReport, Box<dyn Error>> {
+        chip.validate()?;
+
+        let _guard = self.edit_lock.lock().unwrap();
+        let mut chips: HashMap<String, ChipInfo> =
+            self.chips().into_iter().map(|c| (c.id(), c)).collect();
+        chips.insert(chip.id(), chip);
+        let items: Vec<&ChipInfo> = chips.values().collect();
+        self.chips_source.save(&items)?;
+        self.reload()
+    }
+
+    /// Adds or updates a sensor of a known chip, writing the sensors file back
+    /// and reloading the registry.
+    pub fn upsert_sensor(&self, sensor: SensorInfo) -> Result<RegistryReport, Box<dyn Error>> {
+        sensor.validate(&self.sensor_types)?;
+        if !self.contains_chip(&sensor.chip_id) {
+            return Err(format!("unknown chip id {}", sensor.chip_id).into());
+        }
+
+        let _guard = self.edit_lock.lock().unwrap();
+        let mut sensors = self.data.read().unwrap().sensors.clone();
+        sensors.insert(sensor.id(), sensor);
+        let items: Vec<&SensorInfo> = sensors.values().collect();
+        self.sensors_source.save(&items)?;
+        self.reload()
+    }
+
+    /// Adds a new chip together with its sensors. The chips file is written first
+    /// so the sensors never refer to a chip missing from the files.
+    pub fn enroll(
+        &self,
+        chip: ChipInfo,
+        new_sensors: Vec<SensorInfo>,
+    ) -> Result<RegistryReport, Box<dyn Error>> {
+        chip.validate()?;
+        for sensor in &new_sensors {
+            sensor.validate(&self.sensor_types)?;
+            if sensor.chip_id != chip.chip_id {
+                return Err(format!(
+                    "sensor {} belongs to chip {}, not {}",
+                    sensor.sensor_id, sensor.chip_id, chip.chip_id
+                )
+                .into());
+            }
+        }
+
+        let _guard = self.edit_lock.lock().unwrap();
+        if self.contains_chip(&chip.chip_id) {
+            return Err(format!("chip id {} is already registered", chip.chip_id).into());
+        }
+        let mut chips: HashMap<String, ChipInfo> =
+            self.chips().into_iter().map(|c| (c.id(), c)).collect();
+        chips.insert(chip.id(), chip);
+        let mut sensors = self.data.read().unwrap().sensors.clone();
+        for sensor in new_sensors {
+            sensors.insert(sensor.id(), sensor);
+        }
+
+        let items: Vec<&ChipInfo> = chips.values().collect();
+        self.chips_source.save(&items)?;
+        let items: Vec<&SensorInfo> = sensors.values().collect();
+        self.sensors_source.save(&items)?;
+        self.reload()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    /// The files are removed with the returned folder.
+    fn write_files(name: &str, chips: &str, sensors: &str) -> (TempDir, String, String) {
+        let dir = TempDir::new(&format!("registry-{}", name)).unwrap();
+        let chips_path = dir.path().join("chips.csv");
+        let sensors_path = dir.path().join("sensors.csv");
+        std::fs::write(&chips_path, chips).unwrap();
+        std::fs::write(&sensors_path, sensors).unwrap();
+        (
+            dir,
+            chips_path.to_string_lossy().into_owned(),
+            sensors_path.to_string_lossy().into_owned(),
+        )
+    }
+
+    const CHIPS: &str =
+        "chip_id,lat,lon,city,info\na,45.6,11.7,Cittadella,\nb,45.7,11.8,Tombolo,\n";
+
+    #[test]
+    fn test_registry_lookups_and_relation() {
+        let sensors = "chip_id,sensor_id,sensor_type\na,1,SDS011\na,2,BME280\nb,3,SDS011\n";
+        let (_dir, chips_path, sensors_path) = write_files("valid", CHIPS, sensors);
+        let registry =
+            Registry::load(&chips_path, &sensors_path, vec![], Duration::ZERO, None).unwrap();
+
+        assert_eq!(registry.sensor_id("a", "BME280").unwrap(), "2");
+        assert_eq!(registry.sensor("3").unwrap().chip_id, "b");
+        assert_eq!(registry.chips_in_city("Tombolo").len(), 1);
+        assert_eq!(registry.sensors_of_type("SDS011").len(), 2);
+        assert_eq!(registry.sensors_of_chip("a").len(), 2);
+
+        // a change of the chips does not read the sensors again
+        std::fs::write(&sensors_path, "chip_id,sensor_id,sensor_type\na,1,SDS011\n").unwrap();
+        assert!(registry.reload_sources(true, false).unwrap().applied);
+        assert_eq!(registry.sensors().len(), 3);
+        std::fs::write(&sensors_path, sensors).unwrap();
+
+        // a sensor pointing to a missing chip refuses the whole reload
+        std::fs::write(&sensors_path, format!("{}c,4,SDS011\n", sensors)).unwrap();
+        let report = registry.reload().unwrap();
+        assert!(!report.applied);
+        assert_eq!(report.sensors.errors[0].line, 5);
+        assert_eq!(registry.sensors().len(), 3);
+
+        // a sensor id can not be used by two sensors
+        std::fs::write(&sensors_path, format!("{}b,1,BME280\n", sensors)).unwrap();
+        let report = registry.reload().unwrap();
+        assert!(!report.applied);
+        assert_eq!(report.sensors.errors[0].line, 5);
+        assert_eq!(registry.sensor("1").unwrap().chip_id, "a");
+    }
+
+    #[test]
+    fn test_chip_periods() {
+        let chips = "chip_id,lat,lon,city,info,valid_from,valid_to\n\
+                     a,45.6,11.7,Cittadella,,,2025-01-01\n\
+                     a,45.7,11.8,Tombolo,,2025-01-01,\n";
+        let sensors = "chip_id,sensor_id,sensor_type\na,1,SDS011\n";
+        let (_dir, chips_path, sensors_path) = write_files("periods", chips, sensors);
+        let registry =
+            Registry::load(&chips_path, &sensors_path, vec![], Duration::ZERO, None).unwrap();
+
+        let before = validity::parse("2024-06-01").unwrap().unwrap().timestamp();
+        assert_eq!(registry.chip_at("a", before).unwrap().city, "Cittadella");
+        assert_eq!(registry.chip("a").unwrap().city, "Tombolo");
+        assert_eq!(registry.sensor_info_records().len(), 2);
+
+        // the second period starts before the first one ends
+        let overlapping = chips.replace(",2025-01-01,", ",2024-12-01,");
+        std::fs::write(&chips_path, overlapping).unwrap();
+        let report = registry.reload().unwrap();
+        assert!(!report.applied);
+        assert_eq!(report.chips.errors[0].line, 3);
+    }
+}
@@ -0,0 +1,60 @@
+//! Serde helpers for the optional validity bounds of a chip row: an empty field
+//! is an open bound, dates like `2025-03-22` start at midnight UTC, RFC 3339 is
+//! accepted for a precise time.
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+pub fn parse(value: &str) -> Result<Option<DateTime<Utc>>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(Some(date.and_hms_opt(0, 0, 0).unwrap().and_utc()));
+    }
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| Some(t.with_timezone(&Utc)))
+        .map_err(|e| format!("invalid date {}: {}", value, e))
+}
+
+pub fn serialize<S: Serializer>(
+    value: &Option<DateTime<Utc>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(t) => serializer.serialize_str(&t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+        None => serializer.serialize_str(""),
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => parse(&s).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(" ").unwrap(), None);
+        assert_eq!(
+            parse("2025-03-22").unwrap().unwrap().to_rfc3339(),
+            "2025-03-22T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse("2025-03-22T10:30:00+01:00")
+                .unwrap()
+                .unwrap()
+                .to_rfc3339(),
+            "2025-03-22T09:30:00+00:00"
+        );
+        assert!(parse("22/03/2025").is_err());
+    }
+}
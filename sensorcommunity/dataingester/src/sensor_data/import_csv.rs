@@ -1,12 +1,14 @@
 use csv::Reader;
 use std::error::Error;
 use std::path::Path;
+use std::time::Instant;
+use tracing::Instrument;
 use std::{collections::HashMap, sync::Arc};
 
-use crate::cache::Cache;
+use crate::registry::Registry;
 use crate::config::Manifest;
 
-use super::{CHIP_ID, CITY, INFO, LAT, LON, TIMESTAMP, get_sensor_id};
+use super::{CHIP_ID, CITY, INFO, LAT, LON, TIMESTAMP};
 
 // Structure to hold our CSV data
 pub struct CsvData {
@@ -20,7 +22,7 @@ pub async fn import_csv(
     // influxdb3_settings: &crate::config::InfluxDB3,
     config: &Manifest,
     writers: &[Arc<dyn crate::sensor_data::DataWriter>],
-    sensor_cache: Cache<crate::SensorInfo>,
+    registry: &Registry,
 ) -> Result<CsvData, Box<dyn Error>> {
     /*
         chip_id,sensor_id,sensor_type,lat,lon,timestamp,P1,ratioP1,durP1,P2,ratioP2,durP2,SDS_P1,SDS_P2,temperature,humidity,BMP_temperature,BMP_pressure,BME280_temperature,BMP280_humidity,BMP280_pressure,signal,city,info
@@ -75,8 +77,6 @@ pub async fn import_csv(
 
     // Read all records
 
-    // let mut write_queries = Vec::<influxdb::WriteQuery>::new();
-
     let mut data_recs = vec![];
 
     for result in reader.records() {
@@ -167,9 +167,20 @@ pub async fn import_csv(
                 lon,
                 city: city.to_owned(),
                 info: info.to_owned(),
+                tags: vec![],
                 values: vec![],
             };
 
+            // the registry knows where the chip was at the time, the file only
+            // has what was known when it was written
+            if let Some(chip) = registry.chip_at(chip_id, timestamp as i64) {
+                data_rec.tags = registry.geo_tags(&chip);
+                data_rec.lat = chip.lat;
+                data_rec.lon = chip.lon;
+                data_rec.city = chip.city;
+                data_rec.info = chip.info;
+            }
+
             for (measure, sensor_type) in &config.measure_name_to_sensor_type {
                 if let Some(f) = config.measure_name_to_field.get(measure) {
                     let field_idx = match fields.get(measure) {
@@ -197,7 +208,7 @@ pub async fn import_csv(
                         }
                     };
 
-                    let sensor_id = match get_sensor_id(&sensor_cache, chip_id, sensor_type) {
+                    let sensor_id = match registry.sensor_id(chip_id, sensor_type) {
                         Ok(id) => id,
                         Err(e) => {
                             tracing::error!(
@@ -216,22 +227,6 @@ pub async fn import_csv(
                         field: f.to_owned(),
                         value,
                     });
-
-                    /*
-                    let mut wq =
-                        influxdb::Timestamp::Seconds(timestamp).into_query(&config.influxdb3.table);
-                    wq = wq
-                        .add_tag(CHIP_ID, chip_id)
-                        .add_tag(CITY, city)
-                        .add_tag(LAT, lat)
-                        .add_tag(LON, lon)
-                        .add_tag(SENSOR_ID, sensor_id.to_owned())
-                        .add_tag(SENSOR_TYPE, sensor_type.to_owned())
-                        .add_tag(INFO, info)
-                        .add_field(f, value);
-
-                    write_queries.push(wq);
-                    */
                 }
             }
 
@@ -241,27 +236,18 @@ pub async fn import_csv(
 
     let mut record_count = 0;
     for w in writers {
-        if let Err(e) = w.write(&data_recs).await {
+        let start = Instant::now();
+        let res = w
+            .write(&data_recs)
+            .instrument(tracing::info_span!("writer.write", backend = w.name()))
+            .await;
+        crate::metrics::observe_write(w.name(), start.elapsed(), res.is_ok());
+        if let Err(e) = res {
             tracing::error!("Error trying to write records: {}", e);
         } else {
             record_count = record_count + 1;
         }
     }
-    /*
-    let influxdb3_settings = &config.influxdb3;
-    let mut client = influxdb::Client::new(&influxdb3_settings.url, &influxdb3_settings.database);
-    if influxdb3_settings.token.len() > 0 {
-        client = client.with_token(&influxdb3_settings.token);
-    }
-
-    if let Err(e) = client.query(&write_queries).await {
-        tracing::error!(
-            "Error trying to write to InfluxDB at {}: {}",
-            &influxdb3_settings.url,
-            e
-        );
-    }
-    */
 
     Ok(CsvData {
         _filename: filename,
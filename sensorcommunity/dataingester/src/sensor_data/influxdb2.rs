@@ -1,48 +1,123 @@
+use std::collections::HashSet;
+
 use async_trait::async_trait;
+use influxdb2::api::query::FluxRecord;
+use influxdb2::api::write::TimestampPrecision;
+use influxdb2::models::Query;
+use influxdb2_structmap::value::Value;
 
-use super::DataWriter;
-use super::{CHIP_ID, CITY, INFO, LAT, LON, SENSOR_ID, SENSOR_TYPE};
+use super::{CHIP_ID, CITY, INFO, LAT, LON, SENSOR_ID, SENSOR_TYPE, VALID_TO};
+use super::{DataWriter, FieldValue, RowMapper, tls};
 
 pub struct InfluxDB2DataWriter {
     pub settings: crate::config::InfluxDB,
+    mapper: RowMapper,
 }
 
 impl InfluxDB2DataWriter {
     pub fn new(settings: crate::config::InfluxDB) -> Self {
-        InfluxDB2DataWriter { settings }
+        let mapper = RowMapper::new(
+            settings
+                .schema
+                .with_coordinates_as_fields("influxdb2", settings.coordinates_as_fields),
+        );
+        if settings.url.starts_with("https") {
+            tls::warn_settings("influxdb2", &settings.tls, false);
+        }
+        InfluxDB2DataWriter { settings, mapper }
     }
-}
 
-#[async_trait]
-impl DataWriter for InfluxDB2DataWriter {
-    async fn write(&self, recs: &[super::Record]) -> anyhow::Result<()> {
-        let mut points = vec![];
-        let req_builder = reqwest::Client::builder()
-            .redirect(reqwest::redirect::Policy::none())
-            .danger_accept_invalid_certs(true);
+    fn client(&self) -> anyhow::Result<influxdb2::Client> {
+        let req_builder =
+            tls::http_client(&self.settings.tls)?.redirect(reqwest::redirect::Policy::none());
         let builder = influxdb2::ClientBuilder::with_builder(
             req_builder,
             &self.settings.url,
             &self.settings.org,
-            &self.settings.token,
+            self.settings.token.expose(),
         );
-        let client = builder.build()?;
+        Ok(builder.build()?)
+    }
+
+    /// The sensor id and valid_from of the sensor info points of the bucket.
+    async fn sensor_info_keys(
+        &self,
+        client: &influxdb2::Client,
+        bucket: &str,
+    ) -> anyhow::Result<HashSet<(String, i64)>> {
+        let flux = format!(
+            "from(bucket: \"{}\") \
+             |> range(start: 0, stop: 2262-01-01T00:00:00Z) \
+             |> filter(fn: (r) => r._measurement == \"{}\" and r._field == \"{}\") \
+             |> keep(columns: [\"{}\", \"_time\"])",
+            flux_string(bucket),
+            flux_string(&self.settings.sensor_info_measurement),
+            CHIP_ID,
+            SENSOR_ID
+        );
+        let records = client
+            .query_raw(Some(Query::new(flux)))
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Error trying to read the sensor info from InfluxDB at {}: {}",
+                    self.settings.url,
+                    e
+                )
+            })?;
+        sensor_info_keys(&records)
+    }
+}
+
+/// Escapes a Flux string literal.
+fn flux_string(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// The keys of the records of [InfluxDB2DataWriter::sensor_info_keys].
+fn sensor_info_keys(records: &[FluxRecord]) -> anyhow::Result<HashSet<(String, i64)>> {
+    records
+        .iter()
+        .map(
+            |record| match (record.values.get(SENSOR_ID), record.values.get("_time")) {
+                (Some(Value::String(sensor_id)), Some(Value::TimeRFC(time))) => {
+                    Ok((sensor_id.clone(), time.timestamp()))
+                }
+                _ => Err(anyhow::anyhow!(
+                    "unexpected sensor info record: {:?}",
+                    record.values
+                )),
+            },
+        )
+        .collect()
+}
+
+#[async_trait]
+impl DataWriter for InfluxDB2DataWriter {
+    fn name(&self) -> &'static str {
+        "influxdb2"
+    }
+
+    async fn write(&self, recs: &[super::Record]) -> anyhow::Result<()> {
+        let mut points = vec![];
+        let client = self.client()?;
 
         for rec in recs {
-            for d in &rec.values {
-                let mut dp = influxdb2::models::DataPoint::builder(&self.settings.measurement);
-
-                dp = dp
-                    .timestamp(rec.timestamp as i64)
-                    .tag(CHIP_ID, rec.chip_id.as_str())
-                    .tag(CITY, rec.city.as_str())
-                    .tag(LAT, rec.lat.to_string())
-                    .tag(LON, rec.lon.to_string())
-                    .tag(INFO, rec.info.as_str())
-                    .tag(SENSOR_ID, d.sensor_id.as_str())
-                    .tag(SENSOR_TYPE, d.sensor_type.as_str());
-
-                dp = dp.field(d.field.as_str(), d.value);
+            for row in self.mapper.rows(rec) {
+                let mut dp = influxdb2::models::DataPoint::builder(&self.settings.measurement)
+                    .timestamp(row.timestamp as i64);
+                for (k, v) in row.tags {
+                    dp = dp.tag(k, v);
+                }
+                for (k, v) in row.fields {
+                    dp = match v {
+                        FieldValue::F64(v) => dp.field(k, v),
+                        FieldValue::Str(v) => dp.field(k, v),
+                    };
+                }
+                for (k, v) in row.values {
+                    dp = dp.field(k, v);
+                }
                 points.push(dp.build()?);
             }
         }
@@ -60,7 +135,132 @@ impl DataWriter for InfluxDB2DataWriter {
         Ok(())
     }
 
-    async fn refresh_sensor_info(&self, _recs: &[super::SensorInfoRecord]) -> anyhow::Result<()> {
+    async fn refresh_sensor_info(&self, recs: &[super::SensorInfoRecord]) -> anyhow::Result<()> {
+        let measurement = &self.settings.sensor_info_measurement;
+        if measurement.is_empty() {
+            return Ok(());
+        }
+
+        // a point per period at its valid_from, the epoch when open, so that
+        // rewriting a period overwrites its point; the fields of a point are
+        // merged, so an open period has a zero valid_to
+        let mut points = vec![];
+        for rec in recs {
+            let from = rec.valid_from.unwrap_or(chrono::DateTime::UNIX_EPOCH);
+            let dp = influxdb2::models::DataPoint::builder(measurement)
+                .timestamp(from.timestamp())
+                .tag(SENSOR_ID, rec.sensor_id.as_str())
+                .field(CHIP_ID, rec.chip_id.as_str())
+                .field(SENSOR_TYPE, rec.sensor_type.as_str())
+                .field(LAT, rec.lat)
+                .field(LON, rec.lon)
+                .field(CITY, rec.city.as_str())
+                .field(INFO, rec.info.as_str())
+                .field(VALID_TO, rec.valid_to.map_or(0, |to| to.timestamp()));
+            points.push(dp.build()?);
+        }
+
+        let bucket = match self.settings.sensor_info_bucket.is_empty() {
+            true => &self.settings.bucket,
+            false => &self.settings.sensor_info_bucket,
+        };
+        let client = self.client()?;
+        // the points are written before the stale ones are deleted, so the
+        // measurement is never missing a station
+        let current = self.sensor_info_keys(&client, bucket).await?;
+        let count = points.len();
+        if !points.is_empty() {
+            client
+                .write_with_precision(
+                    bucket,
+                    futures::stream::iter(points),
+                    TimestampPrecision::Seconds,
+                )
+                .await
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "Error trying to write the sensor info to InfluxDB at {}: {}",
+                        self.settings.url,
+                        e
+                    )
+                })?;
+        }
+
+        let wanted: HashSet<(String, i64)> = recs
+            .iter()
+            .map(|rec| {
+                let from = rec.valid_from.unwrap_or(chrono::DateTime::UNIX_EPOCH);
+                (rec.sensor_id.clone(), from.timestamp())
+            })
+            .collect();
+        let mut removed = 0;
+        for (sensor_id, from) in current.difference(&wanted) {
+            let at = chrono::DateTime::from_timestamp(*from, 0)
+                .unwrap_or_default()
+                .naive_utc();
+            client
+                .delete(
+                    bucket,
+                    at,
+                    at,
+                    Some(format!(
+                        "_measurement=\"{}\" AND {}=\"{}\"",
+                        measurement.replace('"', "\\\""),
+                        SENSOR_ID,
+                        sensor_id.replace('"', "\\\"")
+                    )),
+                )
+                .await
+                .map_err(|e| {
+                    anyhow::anyhow!(
+                        "Error trying to delete the sensor info from InfluxDB at {}: {}",
+                        self.settings.url,
+                        e
+                    )
+                })?;
+            removed += 1;
+        }
+
+        tracing::info!(
+            "refreshed sensor info measurement '{}' with {} records, {} removed",
+            measurement,
+            count,
+            removed
+        );
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_sensor_info_keys() {
+        let record = |sensor_id: &str, time: &str| FluxRecord {
+            table: 0,
+            values: BTreeMap::from([
+                (SENSOR_ID.to_owned(), Value::String(sensor_id.to_owned())),
+                (
+                    "_time".to_owned(),
+                    Value::TimeRFC(chrono::DateTime::parse_from_rfc3339(time).unwrap()),
+                ),
+            ]),
+        };
+        let records = vec![
+            record("1", "1970-01-01T00:00:00Z"),
+            record("2", "2024-01-01T00:00:00Z"),
+        ];
+        assert_eq!(
+            sensor_info_keys(&records).unwrap(),
+            HashSet::from([("1".to_owned(), 0), ("2".to_owned(), 1704067200)])
+        );
+        let missing = FluxRecord {
+            table: 0,
+            values: BTreeMap::new(),
+        };
+        assert!(sensor_info_keys(&[missing]).is_err());
+        assert_eq!(flux_string(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
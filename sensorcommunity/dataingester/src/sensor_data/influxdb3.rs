@@ -1,60 +1,536 @@
-use super::DataWriter;
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::line_protocol::Line;
+use super::{CHIP_ID, CITY, INFO, LAT, LON, REMOVED, SENSOR_ID, SENSOR_TYPE, VALID_TO};
+use super::{DataWriter, FieldValue, RowMapper, tls};
+use crate::config::Precision;
 use async_trait::async_trait;
-use influxdb::InfluxDbWriteable;
+use flate2::write::GzEncoder;
+use serde::Serialize;
+
+/// The delay before configuring the caches again, doubled after each failure.
+const CACHE_RETRY_DELAY: Duration = Duration::from_secs(60);
+const CACHE_ATTEMPTS: u32 = 8;
 
-use super::{CHIP_ID, CITY, INFO, LAT, LON, SENSOR_ID, SENSOR_TYPE};
 pub struct InfluxDB3DataWriter {
     pub settings: crate::config::InfluxDB3,
+    mapper: RowMapper,
+    client: reqwest::Client,
+    caches: Mutex<CacheRetry>,
 }
 
-impl InfluxDB3DataWriter {
-    pub fn new(settings: crate::config::InfluxDB3) -> Self {
-        InfluxDB3DataWriter { settings }
+/// When the caches are configured next, never once created or given up.
+#[derive(Debug)]
+struct CacheRetry {
+    attempts: u32,
+    next: Option<Instant>,
+}
+
+impl CacheRetry {
+    /// Whether an attempt is due, the next one is then delayed so that
+    /// concurrent writes don't attempt too.
+    fn take(&mut self, now: Instant) -> bool {
+        match self.next {
+            Some(next) if next <= now => {
+                self.attempts += 1;
+                self.next = Some(now + CACHE_RETRY_DELAY * 2u32.pow(self.attempts - 1));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Records the result of an attempt, false when there won't be another.
+    fn done(&mut self, created: bool) -> bool {
+        if created || self.attempts >= CACHE_ATTEMPTS {
+            self.next = None;
+        }
+        self.next.is_some()
     }
 }
 
-#[async_trait]
-impl DataWriter for InfluxDB3DataWriter {
-    async fn write(&self, recs: &[super::Record]) -> anyhow::Result<()> {
-        let mut write_queries = Vec::<influxdb::WriteQuery>::new();
+#[derive(Serialize)]
+struct LastCacheRequest<'a> {
+    db: &'a str,
+    table: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_columns: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_columns: Option<&'a [String]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+}
 
-        for rec in recs {
-            for d in &rec.values {
-                // chrono::Utc::now().timestamp() as u128
-                let mut wq = influxdb::Timestamp::Seconds(rec.timestamp)
-                    .into_query(&self.settings.table);
+#[derive(Serialize)]
+struct DistinctCacheRequest<'a> {
+    db: &'a str,
+    table: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    columns: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_cardinality: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_age: Option<u64>,
+}
 
-                wq = wq
-                    .add_tag(CHIP_ID, rec.chip_id.as_str())
-                    .add_tag(CITY, rec.city.as_str())
-                    .add_tag(LAT, rec.lat)
-                    .add_tag(LON, rec.lon)
-                    .add_tag(INFO, rec.info.as_str())
-                    .add_tag(SENSOR_ID, d.sensor_id.as_str())
-                    .add_tag(SENSOR_TYPE, d.sensor_type.as_str());
+impl InfluxDB3DataWriter {
+    pub fn new(settings: crate::config::InfluxDB3) -> anyhow::Result<Self> {
+        let mapper = RowMapper::new(
+            settings
+                .schema
+                .with_coordinates_as_fields("influxdb3", settings.coordinates_as_fields),
+        );
+        if settings.url.starts_with("https") {
+            tls::warn_settings("influxdb3", &settings.tls, false);
+        }
+        let client = tls::http_client(&settings.tls)?.build()?;
+        Ok(InfluxDB3DataWriter {
+            settings,
+            mapper,
+            client,
+            caches: Mutex::new(CacheRetry {
+                attempts: 0,
+                next: Some(Instant::now()),
+            }),
+        })
+    }
 
-                wq = wq.add_field(d.field.as_str(), d.value);
-                write_queries.push(wq);
-            }
+    fn precision(&self) -> &'static str {
+        match self.settings.precision {
+            Precision::Second => "second",
+            Precision::Millisecond => "millisecond",
+            Precision::Microsecond => "microsecond",
+            Precision::Nanosecond => "nanosecond",
+        }
+    }
 
-            let mut client = influxdb::Client::new(&self.settings.url, &self.settings.database);
-            if self.settings.token.len() > 0 {
-                client = client.with_token(&self.settings.token);
-            }
+    /// A timestamp in seconds in the configured precision.
+    fn timestamp(&self, secs: i64) -> i64 {
+        match self.settings.precision {
+            Precision::Second => secs,
+            Precision::Millisecond => secs * 1_000,
+            Precision::Microsecond => secs * 1_000_000,
+            Precision::Nanosecond => secs * 1_000_000_000,
+        }
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        let req = self.client.post(format!(
+            "{}{}",
+            self.settings.url.trim_end_matches('/'),
+            path
+        ));
+        match self.settings.token.is_empty() {
+            true => req,
+            false => req.bearer_auth(self.settings.token.expose()),
+        }
+    }
+
+    /// Posts the lines gzip compressed. The lines rejected in a partial write
+    /// are logged one by one and the write fails with their count.
+    async fn write_lines(&self, lines: Vec<String>) -> anyhow::Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(lines.join("\n").as_bytes())?;
+        let body = encoder.finish()?;
 
-            let _ = client.query(&write_queries).await.map_err(|e| {
+        let resp = self
+            .post("/api/v3/write_lp")
+            .query(&[
+                ("db", self.settings.database.as_str()),
+                ("precision", self.precision()),
+                ("accept_partial", &self.settings.accept_partial.to_string()),
+                ("no_sync", &self.settings.no_sync.to_string()),
+            ])
+            .header(reqwest::header::CONTENT_ENCODING, "gzip")
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| {
                 anyhow::anyhow!(
                     "Error trying to write to InfluxDB at {}: {}",
                     self.settings.url,
                     e
                 )
             })?;
+
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let text = resp.text().await.unwrap_or_default();
+        let rejected = rejected_lines(&text);
+        if rejected.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Error trying to write to InfluxDB at {}: {} {}",
+                self.settings.url,
+                status,
+                text
+            ));
+        }
+        for (line_number, message, line) in &rejected {
+            tracing::warn!(
+                "InfluxDB at {} rejected line {}: {}: {}",
+                self.settings.url,
+                line_number,
+                message,
+                line
+            );
+        }
+        Err(anyhow::anyhow!(
+            "InfluxDB at {} rejected {} of {} lines{}",
+            self.settings.url,
+            rejected.len(),
+            lines.len(),
+            match self.settings.accept_partial {
+                true => ", the others were written",
+                false => ", nothing was written",
+            }
+        ))
+    }
+
+    /// Creates the caches when an attempt is due. They need the table, so
+    /// creating them at start fails until a first write created it; they are
+    /// then tried again after the writes, less and less often.
+    async fn configure_caches_when_due(&self) {
+        if self.settings.last_caches.is_empty() && self.settings.distinct_caches.is_empty() {
+            return;
+        }
+        if !self.caches.lock().unwrap().take(Instant::now()) {
+            return;
+        }
+        let result = self.configure_caches().await;
+        let mut caches = self.caches.lock().unwrap();
+        let retried = caches.done(result.is_ok());
+        if let Err(e) = result {
+            match retried {
+                true => tracing::warn!("{}, retrying later", e),
+                false => tracing::error!("{}, giving up after {} attempts", e, caches.attempts),
+            }
+        }
+    }
+
+    /// Creates the configured caches, the ones already present are kept. Fails
+    /// when some could not be created, after trying all of them.
+    async fn configure_caches(&self) -> anyhow::Result<()> {
+        let db = self.settings.database.as_str();
+        let mut failed = 0;
+        for cache in &self.settings.last_caches {
+            let req = LastCacheRequest {
+                db,
+                table: cache.table.as_deref().unwrap_or(&self.settings.table),
+                name: cache.name.as_deref(),
+                key_columns: cache.key_columns.as_deref(),
+                value_columns: cache.value_columns.as_deref(),
+                count: cache.count,
+                ttl: cache.ttl_secs,
+            };
+            if !self
+                .configure_cache("/api/v3/configure/last_cache", &req, req.table)
+                .await
+            {
+                failed += 1;
+            }
+        }
+        for cache in &self.settings.distinct_caches {
+            let req = DistinctCacheRequest {
+                db,
+                table: cache.table.as_deref().unwrap_or(&self.settings.table),
+                name: cache.name.as_deref(),
+                columns: &cache.columns,
+                max_cardinality: cache.max_cardinality,
+                max_age: cache.max_age_secs,
+            };
+            if !self
+                .configure_cache("/api/v3/configure/distinct_cache", &req, req.table)
+                .await
+            {
+                failed += 1;
+            }
+        }
+        match failed {
+            0 => Ok(()),
+            _ => Err(anyhow::anyhow!(
+                "{} InfluxDB caches were not created",
+                failed
+            )),
         }
+    }
 
+    /// The sensor id and valid_from in seconds of the rows of the sensor info
+    /// table not removed, none when the table is missing.
+    async fn sensor_info_keys(&self, table: &str) -> anyhow::Result<HashSet<(String, i64)>> {
+        let resp = self
+            .post("/api/v3/query_sql")
+            .json(&serde_json::json!({
+                "db": self.settings.database,
+                "q": format!("SELECT * FROM \"{}\"", table.replace('"', "\"\"")),
+                "format": "json",
+            }))
+            .send()
+            .await?;
+        let status = resp.status();
+        let text = resp.text().await?;
+        if !status.is_success() {
+            if status == reqwest::StatusCode::NOT_FOUND || text.contains("not found") {
+                return Ok(HashSet::new());
+            }
+            return Err(anyhow::anyhow!(
+                "Error trying to read the sensor info from InfluxDB at {}: {} {}",
+                self.settings.url,
+                status,
+                text
+            ));
+        }
+        sensor_info_keys(&text)
+    }
+
+    /// Creates a cache, false when it is neither created nor present.
+    async fn configure_cache<T: Serialize>(&self, path: &str, req: &T, table: &str) -> bool {
+        match self.post(path).json(req).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                tracing::info!("created InfluxDB cache {} of table '{}'", path, table);
+                return true;
+            }
+            Ok(resp) if resp.status() == reqwest::StatusCode::CONFLICT => {
+                tracing::debug!("InfluxDB cache {} of table '{}' is present", path, table);
+                return true;
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                let text = resp.text().await.unwrap_or_default();
+                tracing::warn!(
+                    "failed to create InfluxDB cache {} of table '{}': {} {}",
+                    path,
+                    table,
+                    status,
+                    text
+                );
+            }
+            Err(e) => tracing::warn!(
+                "failed to create InfluxDB cache {} of table '{}': {}",
+                path,
+                table,
+                e
+            ),
+        }
+        false
+    }
+}
+
+/// The keys of the rows of a `query_sql` json response, see
+/// [InfluxDB3DataWriter::sensor_info_keys].
+fn sensor_info_keys(body: &str) -> anyhow::Result<HashSet<(String, i64)>> {
+    let rows: Vec<serde_json::Value> = serde_json::from_str(body)?;
+    let mut keys = HashSet::new();
+    for row in rows {
+        if row[REMOVED].as_bool() == Some(true) {
+            continue;
+        }
+        let (Some(sensor_id), Some(time)) = (row[SENSOR_ID].as_str(), row["time"].as_str()) else {
+            return Err(anyhow::anyhow!("unexpected sensor info row: {}", row));
+        };
+        let time = chrono::NaiveDateTime::parse_from_str(
+            time.trim_end_matches('Z'),
+            "%Y-%m-%dT%H:%M:%S%.f",
+        )
+        .map_err(|e| anyhow::anyhow!("invalid sensor info time {}: {}", time, e))?;
+        keys.insert((sensor_id.to_owned(), time.and_utc().timestamp()));
+    }
+    Ok(keys)
+}
+
+/// The line number, error and line of each line rejected in a partial write.
+fn rejected_lines(body: &str) -> Vec<(u64, String, String)> {
+    let Ok(body) = serde_json::from_str::<serde_json::Value>(body) else {
+        return vec![];
+    };
+    let Some(data) = body["data"].as_array() else {
+        return vec![];
+    };
+    data.iter()
+        .map(|line| {
+            (
+                line["line_number"].as_u64().unwrap_or_default(),
+                line["error_message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+                line["original_line"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned(),
+            )
+        })
+        .collect()
+}
+
+#[async_trait]
+impl DataWriter for InfluxDB3DataWriter {
+    fn name(&self) -> &'static str {
+        "influxdb3"
+    }
+
+    async fn start(&self) -> anyhow::Result<()> {
+        self.configure_caches_when_due().await;
         Ok(())
     }
 
-    async fn refresh_sensor_info(&self, _recs: &[super::SensorInfoRecord]) -> anyhow::Result<()> {
+    async fn write(&self, recs: &[super::Record]) -> anyhow::Result<()> {
+        let mut lines = vec![];
+
+        for rec in recs {
+            for row in self.mapper.rows(rec) {
+                let mut line = Line::new(&self.settings.table);
+                for (k, v) in &row.tags {
+                    line = line.tag(k, v);
+                }
+                for (k, v) in &row.fields {
+                    line = match v {
+                        FieldValue::F64(v) => line.field_f64(k, *v),
+                        FieldValue::Str(v) => line.field_str(k, v),
+                    };
+                }
+                for (k, v) in &row.values {
+                    line = line.field_f64(k, *v);
+                }
+                lines.extend(line.build(self.timestamp(row.timestamp as i64)));
+            }
+        }
+
+        self.write_lines(lines).await?;
+        self.configure_caches_when_due().await;
+
         Ok(())
     }
+
+    async fn refresh_sensor_info(&self, recs: &[super::SensorInfoRecord]) -> anyhow::Result<()> {
+        let table = &self.settings.sensor_info_table;
+        if table.is_empty() {
+            return Ok(());
+        }
+
+        // a row per period at its valid_from, the epoch when open, so that
+        // rewriting a period overwrites its row; the fields can't be cleared,
+        // so an open period has a zero valid_to
+        let mut lines = vec![];
+        let mut wanted = HashSet::new();
+        for rec in recs {
+            let from = rec
+                .valid_from
+                .unwrap_or(chrono::DateTime::UNIX_EPOCH)
+                .timestamp();
+            let line = Line::new(table)
+                .tag(SENSOR_ID, &rec.sensor_id)
+                .field_str(CHIP_ID, &rec.chip_id)
+                .field_str(SENSOR_TYPE, &rec.sensor_type)
+                .field_f64(LAT, rec.lat)
+                .field_f64(LON, rec.lon)
+                .field_str(CITY, &rec.city)
+                .field_str(INFO, &rec.info)
+                .field_i64(VALID_TO, rec.valid_to.map_or(0, |to| to.timestamp()))
+                .field_bool(REMOVED, false);
+            lines.extend(line.build(self.timestamp(from)));
+            wanted.insert((rec.sensor_id.clone(), from));
+        }
+        // and the rows can't be deleted, the periods gone are marked removed
+        let current = self.sensor_info_keys(table).await?;
+        let mut removed = 0;
+        for (sensor_id, from) in current.difference(&wanted) {
+            let line = Line::new(table)
+                .tag(SENSOR_ID, sensor_id)
+                .field_bool(REMOVED, true);
+            lines.extend(line.build(self.timestamp(*from)));
+            removed += 1;
+        }
+
+        self.write_lines(lines).await?;
+
+        tracing::info!(
+            "refreshed sensor info table '{}' with {} records, {} removed",
+            table,
+            recs.len(),
+            removed
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cache_retry() {
+        let now = Instant::now();
+        let mut retry = CacheRetry {
+            attempts: 0,
+            next: Some(now),
+        };
+        assert!(retry.take(now));
+        // not again until the delay is over, doubled after each attempt
+        assert!(!retry.take(now));
+        assert!(retry.done(false));
+        assert!(!retry.take(now + CACHE_RETRY_DELAY / 2));
+        assert!(retry.take(now + CACHE_RETRY_DELAY));
+        assert!(retry.done(false));
+        assert!(!retry.take(now + CACHE_RETRY_DELAY * 2));
+        assert!(retry.take(now + CACHE_RETRY_DELAY * 3));
+        assert!(!retry.done(true));
+        assert!(!retry.take(now + CACHE_RETRY_DELAY * 100));
+
+        let mut retry = CacheRetry {
+            attempts: CACHE_ATTEMPTS - 1,
+            next: Some(now),
+        };
+        assert!(retry.take(now));
+        assert!(!retry.done(false));
+        assert_eq!(retry.next, None);
+    }
+
+    #[test]
+    fn test_rejected_lines() {
+        let body = r#"{
+            "error": "partial write of line protocol occurred",
+            "data": [{
+                "original_line": "sensors,chip_id=1 P1=a 1",
+                "line_number": 2,
+                "error_message": "invalid field value"
+            }]
+        }"#;
+        assert_eq!(
+            rejected_lines(body),
+            vec![(
+                2,
+                "invalid field value".to_owned(),
+                "sensors,chip_id=1 P1=a 1".to_owned()
+            )]
+        );
+        assert!(rejected_lines("not found").is_empty());
+    }
+
+    #[test]
+    fn test_sensor_info_keys() {
+        let body = r#"[
+            {"sensor_id": "1", "time": "1970-01-01T00:00:00", "valid_to": 0},
+            {"sensor_id": "2", "time": "2024-01-01T00:00:00.000000000", "removed": false},
+            {"sensor_id": "3", "time": "2024-01-01T00:00:00", "removed": true}
+        ]"#;
+        assert_eq!(
+            sensor_info_keys(body).unwrap(),
+            HashSet::from([("1".to_owned(), 0), ("2".to_owned(), 1704067200)])
+        );
+        assert!(sensor_info_keys(r#"[{"sensor_id": "1"}]"#).is_err());
+    }
 }
@@ -0,0 +1,106 @@
+/// Builds a line of the InfluxDB line protocol, the tags can be added after the
+/// fields, they are written first.
+pub struct Line {
+    measurement: String,
+    tags: String,
+    fields: String,
+}
+
+impl Line {
+    pub fn new(measurement: &str) -> Self {
+        Line {
+            measurement: escape(measurement, &[',', ' ']),
+            tags: String::new(),
+            fields: String::new(),
+        }
+    }
+
+    /// Adds a tag, the empty values are not allowed by the protocol and skipped.
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        if !value.is_empty() {
+            self.tags.push(',');
+            self.tags.push_str(&escape(key, &[',', '=', ' ']));
+            self.tags.push('=');
+            self.tags.push_str(&escape(value, &[',', '=', ' ']));
+        }
+        self
+    }
+
+    /// Adds a float field, the values not finite are not allowed and skipped.
+    pub fn field_f64(self, key: &str, value: f64) -> Self {
+        match value.is_finite() {
+            true => self.field(key, format!("{:?}", value)),
+            false => self,
+        }
+    }
+
+    pub fn field_i64(self, key: &str, value: i64) -> Self {
+        self.field(key, format!("{}i", value))
+    }
+
+    pub fn field_bool(self, key: &str, value: bool) -> Self {
+        self.field(key, value.to_string())
+    }
+
+    pub fn field_str(self, key: &str, value: &str) -> Self {
+        self.field(key, format!("\"{}\"", escape(value, &['"'])))
+    }
+
+    fn field(mut self, key: &str, value: String) -> Self {
+        if !self.fields.is_empty() {
+            self.fields.push(',');
+        }
+        self.fields.push_str(&escape(key, &[',', '=', ' ']));
+        self.fields.push('=');
+        self.fields.push_str(&value);
+        self
+    }
+
+    /// The line, `None` without fields since a point needs at least one.
+    pub fn build(self, timestamp: i64) -> Option<String> {
+        if self.fields.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "{}{} {} {}",
+            self.measurement, self.tags, self.fields, timestamp
+        ))
+    }
+}
+
+/// Escapes the backslashes, so a trailing one does not escape the delimiter, and
+/// the given characters.
+fn escape(value: &str, chars: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '\\' || chars.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let line = Line::new("air quality")
+            .field_f64("P1", 10.0)
+            .tag("city", "San Giorgio, PD")
+            .tag("info", "")
+            .field_str("info", "a \"b\"")
+            .field_i64("valid_to", 1)
+            .field_f64("P2", f64::NAN)
+            .build(1700000000);
+        assert_eq!(
+            line.as_deref(),
+            Some(
+                "air\\ quality,city=San\\ Giorgio\\,\\ PD P1=10.0,info=\"a \\\"b\\\"\",valid_to=1i 1700000000"
+            )
+        );
+        assert_eq!(Line::new("m").tag("a", "b").build(1), None);
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::DataWriter;
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct WriterStatus {
+    pub name: String,
+    pub paused: bool,
+    pub writes: u64,
+    pub errors: u64,
+    pub skipped: u64,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<DateTime<Utc>>,
+    pub last_error_message: Option<String>,
+}
+
+pub fn as_data_writers(writers: &[Arc<ManagedWriter>]) -> Vec<Arc<dyn DataWriter>> {
+    writers
+        .iter()
+        .map(|w| w.clone() as Arc<dyn DataWriter>)
+        .collect()
+}
+
+/// Wraps a writer keeping track of its health and allowing it to be paused at runtime.
+/// Writes to a paused writer are dropped, the data is still in the csv archive.
+pub struct ManagedWriter {
+    inner: Arc<dyn DataWriter>,
+    paused: AtomicBool,
+    status: Mutex<WriterStatus>,
+}
+
+impl ManagedWriter {
+    pub fn new(inner: Arc<dyn DataWriter>) -> Self {
+        ManagedWriter {
+            status: Mutex::new(WriterStatus {
+                name: inner.name().to_owned(),
+                ..Default::default()
+            }),
+            inner,
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        tracing::info!(
+            "writer {} {}",
+            self.inner.name(),
+            if paused { "paused" } else { "resumed" }
+        );
+    }
+
+    pub fn status(&self) -> WriterStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        status.paused = self.paused.load(Ordering::Relaxed);
+        status
+    }
+
+    fn record<T>(&self, res: &anyhow::Result<T>) {
+        let mut status = self.status.lock().unwrap();
+        match res {
+            Ok(_) => {
+                status.writes += 1;
+                status.last_success = Some(Utc::now());
+            }
+            Err(e) => {
+                status.errors += 1;
+                status.last_error = Some(Utc::now());
+                status.last_error_message = Some(e.to_string());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DataWriter for ManagedWriter {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn start(&self) -> anyhow::Result<()> {
+        self.inner.start().await
+    }
+
+    async fn write(&self, recs: &[super::Record]) -> anyhow::Result<()> {
+        if self.paused.load(Ordering::Relaxed) {
+            self.status.lock().unwrap().skipped += 1;
+            tracing::debug!("writer {} is paused, skipping write", self.inner.name());
+            return Ok(());
+        }
+        let res = self.inner.write(recs).await;
+        self.record(&res);
+        res
+    }
+
+    async fn refresh_sensor_info(&self, recs: &[super::SensorInfoRecord]) -> anyhow::Result<()> {
+        if self.paused.load(Ordering::Relaxed) {
+            tracing::debug!(
+                "writer {} is paused, skipping sensor info refresh",
+                self.inner.name()
+            );
+            return Ok(());
+        }
+        let res = self.inner.refresh_sensor_info(recs).await;
+        self.record(&res);
+        res
+    }
+}
@@ -1,14 +1,20 @@
 mod import_csv;
 mod influxdb2;
 mod influxdb3;
+mod line_protocol;
+mod managed;
 mod questdb;
+mod questdb_sql;
+mod schema;
 mod sensor_data;
+mod tls;
 use async_trait::async_trait;
 
 use serde::{Deserialize, Serialize};
 pub use {
     import_csv::import_csv, influxdb2::InfluxDB2DataWriter, influxdb3::InfluxDB3DataWriter,
-    questdb::QuestDBDataWriter, sensor_data::*,
+    managed::{ManagedWriter, WriterStatus, as_data_writers},
+    questdb::QuestDBDataWriter, schema::{FieldValue, Placement, Row, RowMapper}, sensor_data::*,
 };
 
 pub const CHIP_ID: &str = "chip_id";
@@ -18,6 +24,14 @@ pub const LAT: &str = "lat";
 pub const LON: &str = "lon";
 pub const CITY: &str = "city";
 pub const INFO: &str = "info";
+pub const VALID_TO: &str = "valid_to";
+pub const REMOVED: &str = "removed";
+pub const MUNICIPALITY: &str = "municipality";
+pub const ISTAT_CODE: &str = "istat_code";
+pub const PROVINCE: &str = "province";
+pub const ELEVATION: &str = "elevation";
+pub const GEOHASH: &str = "geohash";
+pub const H3: &str = "h3";
 
 //const TIMESTAMP: &str = "timestamp";
 pub const P1: &str = "P1";
@@ -38,6 +52,26 @@ pub const BME280_HUMIDITY: &str = "BME280_humidity";
 pub const BME280_PRESSURE: &str = "BME280_pressure";
 
 pub const SIGNAL: &str = "signal";
+
+/// The value types sent by the firmware.
+pub const VALUE_TYPES: [&str; 16] = [
+    P1,
+    SDS_P1,
+    DUR_P1,
+    RATIO_P1,
+    P2,
+    SDS_P2,
+    DUR_P2,
+    RATIO_P2,
+    TEMPERATURE,
+    BMP_TEMPERATURE,
+    BME280_TEMPERATURE,
+    HUMIDITY,
+    BMP_PRESSURE,
+    BME280_HUMIDITY,
+    BME280_PRESSURE,
+    SIGNAL,
+];
 pub const TIMESTAMP: &str = "timestamp";
 
 pub const FIELD: &str = "field";
@@ -96,10 +130,13 @@ pub struct Record {
     pub lon: f64,
     pub city: String,
     pub info: String,
+    /// extra tags of the chip, e.g. the municipality resolved from its position
+    pub tags: Vec<(String, String)>,
     pub values: Vec<RecordValue>,
     pub timestamp: u128,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct SensorInfoRecord {
     pub sensor_id: String,
     pub sensor_type: String,
@@ -108,11 +145,19 @@ pub struct SensorInfoRecord {
     pub lon: f64,
     pub city: String,
     pub info: String,
+    pub valid_from: Option<chrono::DateTime<chrono::Utc>>,
+    pub valid_to: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 
 #[async_trait]
 pub trait DataWriter: Sync + Send {
+    /// backend name used in logs and metrics
+    fn name(&self) -> &'static str;
+    /// prepares the backend once at startup, e.g. creating the tables
+    async fn start(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
     async fn write(&self, recs: &[Record]) -> anyhow::Result<()>;
     async fn refresh_sensor_info(&self, recs: &[SensorInfoRecord]) -> anyhow::Result<()>;
 }
@@ -1,17 +1,97 @@
-use super::DataWriter;
+use super::questdb_sql::{SqlClient, SqlValue, quote_ident};
+use super::{DataWriter, FieldValue, Placement, Row, RowMapper, SensorInfoRecord, tls};
+use crate::config::{Layout, SqlProtocol};
 use anyhow::anyhow;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use questdb::ingress::{Buffer, Sender, TimestampNanos};
+use questdb::ingress::{Buffer, Sender, TimestampMicros, TimestampNanos};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{
+    CHIP_ID, CITY, FIELD, INFO, LAT, LON, SENSOR_ID, SENSOR_TYPE, TIMESTAMP, VALID_TO, VALUE,
+};
+/// How often and how many times the WAL of the staging table is checked before
+/// it is swapped in.
+const WAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const WAL_POLL_ATTEMPTS: usize = 120;
 
-use super::{CHIP_ID, CITY, FIELD, INFO, LAT, LON, SENSOR_ID, SENSOR_TYPE, TIMESTAMP, VALUE};
 pub struct QuestDBDataWriter {
     pub settings: crate::config::QuestDB,
+    mapper: RowMapper,
+    /// columns of the wide table known to exist
+    columns: Mutex<HashSet<String>>,
+}
+
+/// A versioned change of the main table, applied once and recorded in the
+/// migrations table.
+struct Migration {
+    version: i64,
+    description: &'static str,
+    statements: Vec<String>,
+}
+
+/// What a refresh did to the sensor info table. A rebuilt table was missing
+/// between the two renames of the swap.
+enum SensorInfoRefresh {
+    Unchanged,
+    Upserted(usize),
+    Rebuilt { removed: usize },
+}
+
+impl SensorInfoRefresh {
+    fn outcome(&self) -> &'static str {
+        match self {
+            SensorInfoRefresh::Unchanged => "unchanged",
+            SensorInfoRefresh::Upserted(_) => "upserted",
+            SensorInfoRefresh::Rebuilt { .. } => "rebuilt",
+        }
+    }
+}
+
+/// The records to upsert, new or changed periods, and the number of rows of the
+/// table without a record. The rows are keyed like the table dedup keys.
+fn diff_sensor_info<'a>(
+    current: &[SensorInfoRecord],
+    recs: &'a [SensorInfoRecord],
+) -> (Vec<&'a SensorInfoRecord>, usize) {
+    let key = |rec: &SensorInfoRecord| (rec.sensor_id.clone(), rec.valid_from);
+    let current: HashMap<_, &SensorInfoRecord> = current.iter().map(|r| (key(r), r)).collect();
+    let wanted: HashSet<_> = recs.iter().map(key).collect();
+    let upserts = recs
+        .iter()
+        .filter(|rec| current.get(&key(rec)) != Some(rec))
+        .collect();
+    let removed = current.keys().filter(|k| !wanted.contains(*k)).count();
+    (upserts, removed)
 }
 
 impl QuestDBDataWriter {
-    pub fn new(settings: crate::config::QuestDB) -> Self {
-        QuestDBDataWriter { settings }
+    /// Fails when pins are set without a CA to verify the ILP writes, which can't
+    /// use the pins.
+    pub fn new(settings: crate::config::QuestDB) -> anyhow::Result<Self> {
+        if settings.use_https
+            && !settings.tls.pins.is_empty()
+            && !(settings.tls.verify && settings.tls.ca_path.is_some())
+        {
+            return Err(anyhow!(
+                "the questdb tls pins need verify = true and a ca_path verifying the ILP writes"
+            ));
+        }
+        let mapper = RowMapper::new(
+            settings
+                .schema
+                .with_coordinates_as_fields("questdb", settings.coordinates_as_fields),
+        );
+        if settings.use_https {
+            tls::warn_settings("questdb", &settings.tls, true);
+        }
+        Ok(QuestDBDataWriter {
+            settings,
+            mapper,
+            columns: Mutex::new(HashSet::new()),
+        })
     }
 
     fn conn_string(&self) -> String {
@@ -19,69 +99,172 @@ impl QuestDBDataWriter {
             true => "https",
             false => "http",
         };
-        format!(
+        let mut conf = format!(
             "{}::addr={};username={};password={};",
-            schema, self.settings.addr, self.settings.username, self.settings.password
-        )
+            schema,
+            self.settings.addr,
+            self.settings.username,
+            self.settings.password.expose()
+        );
+        if self.settings.use_https {
+            conf.push_str(&tls::questdb_conf(&self.settings.tls));
+        }
+        conf
     }
 
-    fn rest_base_url(&self) -> String {
-        let schema = match self.settings.use_https {
-            true => "https",
-            false => "http",
-        };
-        format!("{}://{}", schema, self.settings.addr)
+    /// The columns of the main table besides the values of the wide layout
+    /// and the tags of the geo enrichment, which are added when first seen.
+    fn table_columns(&self) -> Vec<(String, &'static str)> {
+        let mut metadata = vec![CHIP_ID, CITY, LAT, LON, INFO];
+        if self.mapper.layout() == Layout::Narrow {
+            metadata.extend([SENSOR_ID, SENSOR_TYPE]);
+        }
+        let mut columns = vec![];
+        for name in metadata {
+            let ty = match self.mapper.placement(name) {
+                Placement::Tag => "SYMBOL",
+                Placement::Field if name == LAT || name == LON => "DOUBLE",
+                Placement::Field => "STRING",
+                Placement::Skipped => continue,
+            };
+            columns.push((self.mapper.name(name).to_owned(), ty));
+        }
+        if self.mapper.layout() == Layout::Narrow {
+            columns.push((self.mapper.name(FIELD).to_owned(), "SYMBOL"));
+            columns.push((self.mapper.name(VALUE).to_owned(), "DOUBLE"));
+        }
+        columns
     }
-}
 
-#[async_trait]
-impl DataWriter for QuestDBDataWriter {
-    async fn write(&self, recs: &[super::Record]) -> anyhow::Result<()> {
-        let mut sender = Sender::from_conf(self.conn_string())?;
+    /// The migrations of the main table, in order. New ones go at the end with
+    /// the next version, the applied ones must not change, so they don't depend
+    /// on the schema; the partitioning only applies to a new table. The columns
+    /// follow the schema, they are added by the settings statements.
+    fn migrations(&self) -> Vec<Migration> {
+        let table = quote_ident(&self.settings.table);
+        vec![Migration {
+            version: 1,
+            description: "create table",
+            statements: vec![format!(
+                "CREATE TABLE IF NOT EXISTS {} ({TIMESTAMP} TIMESTAMP) \
+                     TIMESTAMP({TIMESTAMP}) PARTITION BY {} WAL",
+                table, self.settings.partition_by
+            )],
+        }]
+    }
 
-        let mut buffer = Buffer::new();
+    /// The statements adding the columns of the schema and applying the dedup
+    /// and TTL settings, run on every start since the settings can change.
+    fn settings_statements(&self) -> Vec<String> {
+        let table = quote_ident(&self.settings.table);
+        let mut statements: Vec<String> = self
+            .table_columns()
+            .iter()
+            .map(|(name, ty)| {
+                format!(
+                    "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                    table,
+                    quote_ident(name),
+                    ty
+                )
+            })
+            .collect();
+        if self.settings.dedup {
+            let keys = match self.mapper.layout() {
+                Layout::Narrow => vec![SENSOR_ID, FIELD],
+                Layout::Wide => vec![CHIP_ID],
+            };
+            let keys: Vec<String> = keys
+                .into_iter()
+                .filter(|k| *k == FIELD || self.mapper.placement(k) != Placement::Skipped)
+                .map(|k| quote_ident(self.mapper.name(k)))
+                .collect();
+            statements.push(format!(
+                "ALTER TABLE {} DEDUP ENABLE UPSERT KEYS({TIMESTAMP}{})",
+                table,
+                keys.iter().map(|k| format!(", {}", k)).collect::<String>()
+            ));
+        } else {
+            statements.push(format!("ALTER TABLE {} DEDUP DISABLE", table));
+        }
+        if !self.settings.ttl.is_empty() {
+            statements.push(format!(
+                "ALTER TABLE {} SET TTL {}",
+                table, self.settings.ttl
+            ));
+        }
+        statements
+    }
 
-        let table = self.settings.table.as_str();
+    /// Applies the migrations of the main table not recorded yet and the settings.
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let sql = SqlClient::connect(&self.settings).await?;
+        let table = &self.settings.table;
+        let migrations_table = quote_ident(&self.settings.migrations_table);
 
-        for rec in recs {
-            let dt: DateTime<Utc> = DateTime::from_timestamp(rec.timestamp as i64, 0)
-                .ok_or(anyhow!("invalid timestamp: {}", rec.timestamp))?;
-            for d in &rec.values {
-                buffer
-                    .table(table)?
-                    .symbol(CHIP_ID, rec.chip_id.to_owned())?
-                    .symbol(CITY, rec.city.to_owned())?
-                    .symbol(LAT, rec.lat.to_string())?
-                    .symbol(LON, rec.lon.to_string())?
-                    .symbol(INFO, rec.info.to_owned())?
-                    .symbol(SENSOR_ID, d.sensor_id.to_owned())?
-                    .symbol(SENSOR_TYPE, d.sensor_type.to_owned())?
-                    .symbol(FIELD, d.field.as_str())?
-                    .column_f64(VALUE, d.value)?
-                    .at(TimestampNanos::from_datetime(dt)?)?;
+        sql.execute(
+            &format!(
+                "CREATE TABLE IF NOT EXISTS {} (\
+                 table_name SYMBOL, \
+                 version LONG, \
+                 description STRING, \
+                 applied_at TIMESTAMP\
+                 ) TIMESTAMP(applied_at)",
+                migrations_table
+            ),
+            &[],
+        )
+        .await?;
+        let current = sql
+            .query_long(
+                &format!(
+                    "SELECT max(version) FROM {} WHERE table_name = $1",
+                    migrations_table
+                ),
+                &[SqlValue::Str(table.clone())],
+            )
+            .await?
+            .unwrap_or(0);
+
+        for migration in self.migrations() {
+            if migration.version <= current {
+                continue;
+            }
+            for statement in &migration.statements {
+                sql.execute(statement, &[]).await?;
             }
+            sql.execute(
+                &format!("INSERT INTO {} VALUES($1, $2, $3, now())", migrations_table),
+                &[
+                    SqlValue::Str(table.clone()),
+                    SqlValue::Long(migration.version),
+                    SqlValue::Str(migration.description.to_owned()),
+                ],
+            )
+            .await?;
+            tracing::info!(
+                "applied migration {} '{}' to table '{}'",
+                migration.version,
+                migration.description,
+                table
+            );
         }
 
-        sender.flush(&mut buffer)?;
-
+        for statement in self.settings_statements() {
+            sql.execute(&statement, &[]).await?;
+        }
+        self.columns
+            .lock()
+            .unwrap()
+            .extend(self.table_columns().into_iter().map(|(name, _)| name));
         Ok(())
     }
 
-    async fn refresh_sensor_info(&self, recs: &[super::SensorInfoRecord]) -> anyhow::Result<()> {
-        let sensor_info_table = &self.settings.sensor_info_table;
-        if sensor_info_table.is_empty() {
-            return Ok(());
-        }
-
-        // Truncate the sensor_info table via QuestDB REST API
-        let base_url = self.rest_base_url();
-        let client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
-            .build()?;
-
-        // Create the table if it does not exist
+    async fn create_sensor_info_table(&self, sql: &SqlClient, table: &str) -> anyhow::Result<()> {
+        // Each period of a chip is a row at its valid_from (the epoch when open),
+        // rewriting a period upserts it so the history is kept instead of truncated
         let create_query = format!(
-            "CREATE TABLE IF NOT EXISTS '{}' (\
+            "CREATE TABLE IF NOT EXISTS {} (\
              {CHIP_ID} SYMBOL, \
              {SENSOR_ID} SYMBOL, \
              {SENSOR_TYPE} SYMBOL, \
@@ -89,73 +272,497 @@ impl DataWriter for QuestDBDataWriter {
              {LON} DOUBLE, \
              {CITY} STRING, \
              {INFO} STRING, \
+             {VALID_TO} TIMESTAMP, \
              {TIMESTAMP} TIMESTAMP\
-             ) TIMESTAMP({TIMESTAMP}) PARTITION BY DAY WAL",
-            sensor_info_table
+             ) TIMESTAMP({TIMESTAMP}) PARTITION BY DAY WAL \
+             DEDUP UPSERT KEYS({TIMESTAMP}, {SENSOR_ID})",
+            quote_ident(table)
         );
-        let resp = client
-            .get(format!("{}/exec", base_url))
-            .query(&[("query", &create_query)])
-            .send()
-            .await
-            .map_err(|e| anyhow!("failed to create table '{}': {}", sensor_info_table, e))?;
-
-        if !resp.status().is_success() {
-            let body = resp.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "failed to create table '{}': {}",
-                sensor_info_table,
-                body
-            ));
+        sql.execute(&create_query, &[]).await
+    }
+
+    async fn read_sensor_info(&self, sql: &SqlClient) -> anyhow::Result<Vec<SensorInfoRecord>> {
+        let rows = sql
+            .query_rows(&format!(
+                "SELECT {CHIP_ID}, {SENSOR_ID}, {SENSOR_TYPE}, {LAT}, {LON}, {CITY}, {INFO}, \
+                 {VALID_TO}, {TIMESTAMP} FROM {}",
+                quote_ident(&self.settings.sensor_info_table)
+            ))
+            .await?;
+        rows.into_iter()
+            .map(|row| match row.as_slice() {
+                [
+                    SqlValue::Str(chip_id),
+                    SqlValue::Str(sensor_id),
+                    SqlValue::Str(sensor_type),
+                    SqlValue::F64(lat),
+                    SqlValue::F64(lon),
+                    SqlValue::Str(city),
+                    SqlValue::Str(info),
+                    SqlValue::Timestamp(valid_to),
+                    SqlValue::Timestamp(from),
+                ] => Ok(SensorInfoRecord {
+                    sensor_id: sensor_id.clone(),
+                    sensor_type: sensor_type.clone(),
+                    chip_id: chip_id.clone(),
+                    lat: *lat,
+                    lon: *lon,
+                    city: city.clone(),
+                    info: info.clone(),
+                    valid_from: from.filter(|from| *from != DateTime::UNIX_EPOCH),
+                    valid_to: *valid_to,
+                }),
+                _ => Err(anyhow!("unexpected sensor info row: {:?}", row)),
+            })
+            .collect()
+    }
+
+    async fn write_sensor_info(
+        &self,
+        sql: &SqlClient,
+        table: &str,
+        recs: &[&SensorInfoRecord],
+    ) -> anyhow::Result<()> {
+        match self.settings.sql_protocol {
+            SqlProtocol::Rest => {
+                // Write all sensor info records via ILP
+                let mut sender = Sender::from_conf(self.conn_string())?;
+                let mut buffer = Buffer::new();
+
+                for rec in recs {
+                    buffer
+                        .table(table)?
+                        .symbol(CHIP_ID, &rec.chip_id)?
+                        .symbol(SENSOR_ID, &rec.sensor_id)?
+                        .symbol(SENSOR_TYPE, &rec.sensor_type)?
+                        .column_f64(LAT, rec.lat)?
+                        .column_f64(LON, rec.lon)?
+                        .column_str(CITY, &rec.city)?
+                        .column_str(INFO, &rec.info)?;
+                    if let Some(to) = rec.valid_to {
+                        buffer.column_ts(VALID_TO, TimestampMicros::from_datetime(to))?;
+                    }
+                    let from = rec.valid_from.unwrap_or(DateTime::UNIX_EPOCH);
+                    buffer.at(TimestampNanos::from_datetime(from)?)?;
+                }
+
+                sender.flush(&mut buffer)?;
+            }
+            SqlProtocol::Pg => {
+                let insert = format!(
+                    "INSERT INTO {} ({CHIP_ID}, {SENSOR_ID}, {SENSOR_TYPE}, {LAT}, {LON}, \
+                     {CITY}, {INFO}, {VALID_TO}, {TIMESTAMP}) \
+                     VALUES($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+                    quote_ident(table)
+                );
+                for rec in recs {
+                    let params = [
+                        SqlValue::Str(rec.chip_id.clone()),
+                        SqlValue::Str(rec.sensor_id.clone()),
+                        SqlValue::Str(rec.sensor_type.clone()),
+                        SqlValue::F64(rec.lat),
+                        SqlValue::F64(rec.lon),
+                        SqlValue::Str(rec.city.clone()),
+                        SqlValue::Str(rec.info.clone()),
+                        SqlValue::Timestamp(rec.valid_to),
+                        SqlValue::Timestamp(Some(rec.valid_from.unwrap_or(DateTime::UNIX_EPOCH))),
+                    ];
+                    sql.execute(&insert, &params).await?;
+                }
+            }
         }
+        Ok(())
+    }
 
-        // Truncate
-        let truncate_query = format!("TRUNCATE TABLE '{}'", sensor_info_table);
-        let resp = client
-            .get(format!("{}/exec", base_url))
-            .query(&[("query", &truncate_query)])
-            .send()
-            .await
-            .map_err(|e| anyhow!("failed to truncate table '{}': {}", sensor_info_table, e))?;
+    /// Waits until the server has applied the WAL writes of the table, the writes
+    /// return before they are visible.
+    async fn wait_applied(&self, sql: &SqlClient, table: &str) -> anyhow::Result<()> {
+        for _ in 0..WAL_POLL_ATTEMPTS {
+            let pending = sql
+                .query_long(
+                    "SELECT max(sequencerTxn - writerTxn) FROM wal_tables() WHERE name = $1",
+                    &[SqlValue::Str(table.to_owned())],
+                )
+                .await?;
+            if pending == Some(0) {
+                return Ok(());
+            }
+            tokio::time::sleep(WAL_POLL_INTERVAL).await;
+        }
+        Err(anyhow!(
+            "the writes of table '{}' are still not applied after {:?}",
+            table,
+            WAL_POLL_INTERVAL * WAL_POLL_ATTEMPTS as u32
+        ))
+    }
 
-        if !resp.status().is_success() {
-            let body = resp.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "failed to truncate table '{}': {}",
-                sensor_info_table,
-                body
-            ));
+    /// Brings the sensor info table in line with the records. New and changed
+    /// periods are upserted in place, so readers never see a partial table. Rows
+    /// can't be deleted, so when some must go the records are written to a staging
+    /// table that is swapped in once the server has applied them: the table is
+    /// renamed aside, the staging table takes its name and the old one is
+    /// dropped. Between the two renames the table is missing and the queries
+    /// joining it fail, these refreshes are counted as rebuilt. A failed or late
+    /// write leaves the table as it was and a failed swap renames it back.
+    async fn sync_sensor_info(
+        &self,
+        recs: &[SensorInfoRecord],
+    ) -> anyhow::Result<SensorInfoRefresh> {
+        let sensor_info_table = &self.settings.sensor_info_table;
+        let table = quote_ident(sensor_info_table);
+        let sql = SqlClient::connect(&self.settings).await?;
+
+        self.create_sensor_info_table(&sql, sensor_info_table)
+            .await?;
+
+        // tables created by earlier versions were truncated on each refresh
+        sql.execute(
+            &format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {VALID_TO} TIMESTAMP",
+                table
+            ),
+            &[],
+        )
+        .await?;
+        sql.execute(
+            &format!(
+                "ALTER TABLE {} DEDUP ENABLE UPSERT KEYS({TIMESTAMP}, {SENSOR_ID})",
+                table
+            ),
+            &[],
+        )
+        .await?;
+
+        let current = self.read_sensor_info(&sql).await?;
+        let (upserts, removed) = diff_sensor_info(&current, recs);
+
+        if removed == 0 {
+            if upserts.is_empty() {
+                return Ok(SensorInfoRefresh::Unchanged);
+            }
+            self.write_sensor_info(&sql, sensor_info_table, &upserts)
+                .await?;
+            return Ok(SensorInfoRefresh::Upserted(upserts.len()));
         }
 
-        if recs.is_empty() {
+        let staging_table = format!("{}_staging", sensor_info_table);
+        sql.execute(
+            &format!("DROP TABLE IF EXISTS {}", quote_ident(&staging_table)),
+            &[],
+        )
+        .await?;
+        self.create_sensor_info_table(&sql, &staging_table).await?;
+        self.write_sensor_info(&sql, &staging_table, &recs.iter().collect::<Vec<_>>())
+            .await?;
+        self.wait_applied(&sql, &staging_table).await?;
+
+        let old_table = quote_ident(&format!("{}_old", sensor_info_table));
+        sql.execute(&format!("DROP TABLE IF EXISTS {}", old_table), &[])
+            .await?;
+        sql.execute(&format!("RENAME TABLE {} TO {}", table, old_table), &[])
+            .await?;
+        let swap = format!("RENAME TABLE {} TO {}", quote_ident(&staging_table), table);
+        if let Err(e) = sql.execute(&swap, &[]).await {
+            sql.execute(&format!("RENAME TABLE {} TO {}", old_table, table), &[])
+                .await
+                .map_err(|rollback| {
+                    anyhow!(
+                        "{}, and renaming {} back failed: {}",
+                        e,
+                        old_table,
+                        rollback
+                    )
+                })?;
+            return Err(e);
+        }
+        if let Err(e) = sql.execute(&format!("DROP TABLE {}", old_table), &[]).await {
+            tracing::warn!("could not drop the replaced table {}: {:#}", old_table, e);
+        }
+        Ok(SensorInfoRefresh::Rebuilt { removed })
+    }
+
+    /// Adds the columns of the wide table not seen yet, so the values get a
+    /// DOUBLE column even when the server does not create columns on write.
+    async fn add_columns(&self, rows: &[Row]) -> anyhow::Result<()> {
+        let mut missing: Vec<(&str, &str)> = vec![];
+        {
+            let known = self.columns.lock().unwrap();
+            for row in rows {
+                let columns = row
+                    .tags
+                    .iter()
+                    .map(|(k, _)| (k.as_str(), "SYMBOL"))
+                    .chain(row.fields.iter().map(|(k, v)| match v {
+                        FieldValue::F64(_) => (k.as_str(), "DOUBLE"),
+                        FieldValue::Str(_) => (k.as_str(), "STRING"),
+                    }))
+                    .chain(row.values.iter().map(|(k, _)| (k.as_str(), "DOUBLE")));
+                for (name, ty) in columns {
+                    if !known.contains(name) && !missing.iter().any(|(n, _)| *n == name) {
+                        missing.push((name, ty));
+                    }
+                }
+            }
+        }
+        if missing.is_empty() {
             return Ok(());
         }
 
-        // Write all sensor info records via ILP
+        let sql = SqlClient::connect(&self.settings).await?;
+        let table = &self.settings.table;
+        for (name, ty) in missing {
+            sql.execute(
+                &format!(
+                    "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                    quote_ident(table),
+                    quote_ident(name),
+                    ty
+                ),
+                &[],
+            )
+            .await?;
+            tracing::debug!("column {} {} of table '{}' is present", name, ty, table);
+            self.columns.lock().unwrap().insert(name.to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DataWriter for QuestDBDataWriter {
+    fn name(&self) -> &'static str {
+        "questdb"
+    }
+
+    /// Migrates the main table, a failure is left to ILP, which creates the
+    /// table and its columns on write.
+    async fn start(&self) -> anyhow::Result<()> {
+        self.migrate().await
+    }
+
+    async fn write(&self, recs: &[super::Record]) -> anyhow::Result<()> {
         let mut sender = Sender::from_conf(self.conn_string())?;
+
         let mut buffer = Buffer::new();
 
-        for rec in recs {
-            buffer
-                .table(sensor_info_table.as_str())?
-                .symbol(CHIP_ID, &rec.chip_id)?
-                .symbol(SENSOR_ID, &rec.sensor_id)?
-                .symbol(SENSOR_TYPE, &rec.sensor_type)?
-                .column_f64(LAT, rec.lat)?
-                .column_f64(LON, rec.lon)?
-                .column_str(CITY, &rec.city)?
-                .column_str(INFO, &rec.info)?
-                .at(TimestampNanos::now())?;
+        let table = self.settings.table.as_str();
+
+        let rows: Vec<Row> = recs.iter().flat_map(|rec| self.mapper.rows(rec)).collect();
+        if self.mapper.layout() == Layout::Wide
+            && let Err(e) = self.add_columns(&rows).await
+        {
+            // the server may still create them on write
+            tracing::warn!("could not add the columns of table '{}': {:#}", table, e);
+        }
+
+        for row in &rows {
+            let dt: DateTime<Utc> = DateTime::from_timestamp(row.timestamp as i64, 0)
+                .ok_or(anyhow!("invalid timestamp: {}", row.timestamp))?;
+            let line = buffer.table(table)?;
+            for (k, v) in &row.tags {
+                line.symbol(k.as_str(), v.as_str())?;
+            }
+            // the symbols must come before the other columns
+            if self.mapper.layout() == Layout::Narrow {
+                for (name, value) in &row.values {
+                    line.symbol(self.mapper.name(FIELD), name.as_str())?
+                        .column_f64(self.mapper.name(VALUE), *value)?;
+                }
+            } else {
+                for (name, value) in &row.values {
+                    line.column_f64(name.as_str(), *value)?;
+                }
+            }
+            for (k, v) in &row.fields {
+                match v {
+                    FieldValue::F64(v) => line.column_f64(k.as_str(), *v)?,
+                    FieldValue::Str(v) => line.column_str(k.as_str(), v.as_str())?,
+                };
+            }
+            line.at(TimestampNanos::from_datetime(dt)?)?;
         }
 
         sender.flush(&mut buffer)?;
 
-        tracing::info!(
-            "refreshed sensor_info table '{}' with {} records",
-            sensor_info_table,
-            recs.len()
+        Ok(())
+    }
+
+    async fn refresh_sensor_info(&self, recs: &[super::SensorInfoRecord]) -> anyhow::Result<()> {
+        let sensor_info_table = &self.settings.sensor_info_table;
+        if sensor_info_table.is_empty() {
+            return Ok(());
+        }
+
+        match self.sync_sensor_info(recs).await {
+            Ok(refresh) => {
+                let (upserted, removed) = match refresh {
+                    SensorInfoRefresh::Unchanged => (0, 0),
+                    SensorInfoRefresh::Upserted(upserted) => (upserted, 0),
+                    SensorInfoRefresh::Rebuilt { removed, .. } => (recs.len(), removed),
+                };
+                crate::metrics::observe_sensor_info_refresh(
+                    self.name(),
+                    refresh.outcome(),
+                    upserted,
+                    removed,
+                );
+                match refresh {
+                    SensorInfoRefresh::Unchanged => tracing::debug!(
+                        "sensor_info table '{}' is up to date with {} records",
+                        sensor_info_table,
+                        recs.len()
+                    ),
+                    SensorInfoRefresh::Upserted(upserted) => tracing::info!(
+                        "refreshed sensor_info table '{}', {} of {} records upserted",
+                        sensor_info_table,
+                        upserted,
+                        recs.len()
+                    ),
+                    SensorInfoRefresh::Rebuilt { removed } => tracing::info!(
+                        "rebuilt sensor_info table '{}' with {} records, {} removed",
+                        sensor_info_table,
+                        recs.len(),
+                        removed
+                    ),
+                }
+                Ok(())
+            }
+            Err(e) => {
+                crate::metrics::observe_sensor_info_refresh(self.name(), "failed", 0, 0);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{BackendTls, QuestDB, Schema};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_migrations() {
+        let writer = QuestDBDataWriter::new(QuestDB {
+            table: "sensors".to_owned(),
+            ttl: "30 DAYS".to_owned(),
+            ..Default::default()
+        })
+        .unwrap();
+        let migrations = writer.migrations();
+        assert_eq!(migrations[0].version, 1);
+        assert_eq!(
+            migrations[0].statements[0],
+            "CREATE TABLE IF NOT EXISTS \"sensors\" (timestamp TIMESTAMP) \
+             TIMESTAMP(timestamp) PARTITION BY DAY WAL"
+        );
+        // the migrations don't follow the schema
+        let wide = QuestDBDataWriter::new(QuestDB {
+            table: "sensors".to_owned(),
+            schema: Schema {
+                layout: Layout::Wide,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        for (a, b) in wide.migrations().iter().zip(&migrations) {
+            assert_eq!(a.statements, b.statements);
+        }
+        let statements = writer.settings_statements();
+        assert_eq!(
+            statements[0],
+            "ALTER TABLE \"sensors\" ADD COLUMN IF NOT EXISTS \"chip_id\" SYMBOL"
         );
+        assert_eq!(
+            statements[statements.len() - 2..],
+            [
+                "ALTER TABLE \"sensors\" DEDUP ENABLE UPSERT KEYS(timestamp, \"sensor_id\", \"field\")",
+                "ALTER TABLE \"sensors\" SET TTL 30 DAYS",
+            ]
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn test_new_pins() {
+        let mut settings = QuestDB {
+            table: "sensors".to_owned(),
+            tls: BackendTls {
+                pins: vec!["ab".repeat(32)],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // the ILP writes would not be verified
+        assert!(QuestDBDataWriter::new(settings.clone()).is_err());
+        settings.tls.verify = true;
+        assert!(QuestDBDataWriter::new(settings.clone()).is_err());
+        settings.tls.ca_path = Some("/etc/ssl/questdb.pem".into());
+        assert!(QuestDBDataWriter::new(settings).is_ok());
+    }
+
+    #[test]
+    fn test_wide_migrations() {
+        let writer = QuestDBDataWriter::new(QuestDB {
+            table: "sensors".to_owned(),
+            schema: Schema {
+                layout: Layout::Wide,
+                fields: vec![LAT.to_owned(), LON.to_owned(), INFO.to_owned()],
+                rename: HashMap::from([(CHIP_ID.to_owned(), "chip".to_owned())]),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            writer.table_columns(),
+            vec![
+                ("chip".to_owned(), "SYMBOL"),
+                ("city".to_owned(), "SYMBOL"),
+                ("lat".to_owned(), "DOUBLE"),
+                ("lon".to_owned(), "DOUBLE"),
+                ("info".to_owned(), "STRING"),
+            ]
+        );
+        assert_eq!(
+            writer.settings_statements().last().unwrap(),
+            "ALTER TABLE \"sensors\" DEDUP ENABLE UPSERT KEYS(timestamp, \"chip\")"
+        );
+    }
+
+    #[test]
+    fn test_diff_sensor_info() {
+        let rec = |sensor_id: &str, city: &str| SensorInfoRecord {
+            sensor_id: sensor_id.to_owned(),
+            sensor_type: "SDS011".to_owned(),
+            chip_id: "esp8266-1".to_owned(),
+            lat: 45.6,
+            lon: 11.7,
+            city: city.to_owned(),
+            info: "".to_owned(),
+            valid_from: None,
+            valid_to: None,
+        };
+        let current = vec![rec("1", "Cittadella"), rec("2", "Cittadella")];
+
+        let (upserts, removed) = diff_sensor_info(&current, &current);
+        assert!(upserts.is_empty());
+        assert_eq!(removed, 0);
+
+        let recs = vec![
+            rec("1", "Padova"),
+            rec("2", "Cittadella"),
+            rec("3", "Padova"),
+        ];
+        let (upserts, removed) = diff_sensor_info(&current, &recs);
+        assert_eq!(upserts, vec![&recs[0], &recs[2]]);
+        assert_eq!(removed, 0);
+
+        let mut moved = rec("2", "Padova");
+        moved.valid_from = DateTime::from_timestamp(1, 0);
+        let recs = vec![rec("1", "Cittadella"), moved];
+        let (upserts, removed) = diff_sensor_info(&current, &recs);
+        assert_eq!(upserts, vec![&recs[1]]);
+        assert_eq!(removed, 1);
     }
 }
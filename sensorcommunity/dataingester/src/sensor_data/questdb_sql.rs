@@ -0,0 +1,370 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use tokio_postgres::Socket;
+use tokio_postgres::tls::MakeTlsConnect;
+use tokio_postgres::types::{ToSql, Type};
+
+use crate::config::{QuestDB, SqlProtocol};
+
+const PG_PORT: u16 = 8812;
+const PG_DATABASE: &str = "qdb";
+
+/// A statement parameter, `$1` is the first one, or a value of a returned row
+/// where a null string is empty.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Str(String),
+    Long(i64),
+    F64(f64),
+    Timestamp(Option<DateTime<Utc>>),
+}
+
+/// Runs the schema and metadata statements, over the REST `/exec` endpoint with
+/// the parameters escaped into the statement or over the PostgreSQL wire
+/// protocol with the parameters bound.
+pub enum SqlClient {
+    Rest {
+        client: reqwest::Client,
+        base_url: String,
+    },
+    Pg(tokio_postgres::Client),
+}
+
+impl SqlClient {
+    pub async fn connect(settings: &QuestDB) -> anyhow::Result<Self> {
+        match settings.sql_protocol {
+            SqlProtocol::Rest => {
+                let scheme = match settings.use_https {
+                    true => "https",
+                    false => "http",
+                };
+                let client = super::tls::http_client(&settings.tls)?.build()?;
+                Ok(SqlClient::Rest {
+                    client,
+                    base_url: format!("{}://{}", scheme, settings.addr),
+                })
+            }
+            SqlProtocol::Pg => {
+                let (host, port) = pg_host_port(settings)?;
+                let mut config = tokio_postgres::Config::new();
+                config
+                    .host(&host)
+                    .port(port)
+                    .user(&settings.username)
+                    .password(settings.password.expose())
+                    .dbname(PG_DATABASE);
+                let connected = match settings.use_https {
+                    true => {
+                        config.ssl_mode(tokio_postgres::config::SslMode::Require);
+                        connect_pg(config, super::tls::PgTls::new(&settings.tls)?).await
+                    }
+                    false => connect_pg(config, tokio_postgres::NoTls).await,
+                };
+                let client = connected
+                    .map_err(|e| anyhow!("failed to connect to {}:{}: {}", host, port, e))?;
+                Ok(SqlClient::Pg(client))
+            }
+        }
+    }
+
+    /// Runs a statement, DDL statements take no parameters.
+    pub async fn execute(&self, statement: &str, params: &[SqlValue]) -> anyhow::Result<()> {
+        match self {
+            SqlClient::Rest { client, base_url } => {
+                rest_exec(client, base_url, &inline_params(statement, params)).await?;
+            }
+            SqlClient::Pg(client) => {
+                let result = if params.is_empty() {
+                    client.batch_execute(statement).await
+                } else {
+                    client
+                        .execute(
+                            statement,
+                            &pg_params(params)
+                                .iter()
+                                .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+                                .collect::<Vec<_>>(),
+                        )
+                        .await
+                        .map(|_| ())
+                };
+                result.map_err(|e| anyhow!("failed to run '{}': {}", statement, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a query returning a single LONG, `None` when the value is null.
+    pub async fn query_long(
+        &self,
+        statement: &str,
+        params: &[SqlValue],
+    ) -> anyhow::Result<Option<i64>> {
+        match self {
+            SqlClient::Rest { client, base_url } => {
+                let resp = rest_exec(client, base_url, &inline_params(statement, params)).await?;
+                Ok(resp["dataset"][0][0].as_i64())
+            }
+            SqlClient::Pg(client) => {
+                let params = pg_params(params);
+                let row = client
+                    .query_one(
+                        statement,
+                        &params
+                            .iter()
+                            .map(|p| p.as_ref() as &(dyn ToSql + Sync))
+                            .collect::<Vec<_>>(),
+                    )
+                    .await
+                    .map_err(|e| anyhow!("failed to run '{}': {}", statement, e))?;
+                Ok(row.try_get::<_, Option<i64>>(0)?)
+            }
+        }
+    }
+
+    /// Runs a query and returns all the rows.
+    pub async fn query_rows(&self, statement: &str) -> anyhow::Result<Vec<Vec<SqlValue>>> {
+        match self {
+            SqlClient::Rest { client, base_url } => {
+                let resp = rest_exec(client, base_url, statement).await?;
+                let types: Vec<&str> = resp["columns"]
+                    .as_array()
+                    .map(|columns| {
+                        columns
+                            .iter()
+                            .map(|c| c["type"].as_str().unwrap_or_default())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let rows = resp["dataset"].as_array().cloned().unwrap_or_default();
+                rows.iter()
+                    .map(|row| {
+                        types
+                            .iter()
+                            .enumerate()
+                            .map(|(i, ty)| json_value(ty, &row[i]))
+                            .collect::<anyhow::Result<Vec<_>>>()
+                    })
+                    .collect()
+            }
+            SqlClient::Pg(client) => {
+                let rows = client
+                    .query(statement, &[])
+                    .await
+                    .map_err(|e| anyhow!("failed to run '{}': {}", statement, e))?;
+                rows.iter()
+                    .map(|row| {
+                        (0..row.len())
+                            .map(|i| pg_value(row, i))
+                            .collect::<anyhow::Result<Vec<_>>>()
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Quotes a table or column name.
+pub fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn pg_host_port(settings: &QuestDB) -> anyhow::Result<(String, u16)> {
+    let addr = match settings.pg_addr.is_empty() {
+        true => settings.addr.as_str(),
+        false => settings.pg_addr.as_str(),
+    };
+    let (host, port) = match addr.rsplit_once(':') {
+        Some((host, port)) if !settings.pg_addr.is_empty() => (
+            host,
+            port.parse()
+                .map_err(|_| anyhow!("invalid pg address: {}", addr))?,
+        ),
+        Some((host, _)) => (host, PG_PORT),
+        None => (addr, PG_PORT),
+    };
+    Ok((host.to_owned(), port))
+}
+
+fn pg_params(params: &[SqlValue]) -> Vec<Box<dyn ToSql + Sync + Send>> {
+    params
+        .iter()
+        .map(|p| -> Box<dyn ToSql + Sync + Send> {
+            match p {
+                SqlValue::Str(v) => Box::new(v.clone()),
+                SqlValue::Long(v) => Box::new(*v),
+                SqlValue::F64(v) => Box::new(*v),
+                SqlValue::Timestamp(v) => Box::new(v.map(|v| v.naive_utc())),
+            }
+        })
+        .collect()
+}
+
+fn json_value(ty: &str, value: &serde_json::Value) -> anyhow::Result<SqlValue> {
+    Ok(match ty {
+        "DOUBLE" | "FLOAT" => SqlValue::F64(value.as_f64().unwrap_or(f64::NAN)),
+        "LONG" | "INT" | "SHORT" | "BYTE" => SqlValue::Long(value.as_i64().unwrap_or_default()),
+        "TIMESTAMP" | "DATE" => SqlValue::Timestamp(match value.as_str() {
+            Some(v) => Some(
+                DateTime::parse_from_rfc3339(v)
+                    .map_err(|e| anyhow!("invalid timestamp {}: {}", v, e))?
+                    .to_utc(),
+            ),
+            None => None,
+        }),
+        _ => SqlValue::Str(value.as_str().unwrap_or_default().to_owned()),
+    })
+}
+
+fn pg_value(row: &tokio_postgres::Row, i: usize) -> anyhow::Result<SqlValue> {
+    let ty = row.columns()[i].type_();
+    Ok(if *ty == Type::FLOAT8 {
+        SqlValue::F64(row.try_get::<_, Option<f64>>(i)?.unwrap_or(f64::NAN))
+    } else if *ty == Type::INT8 {
+        SqlValue::Long(row.try_get::<_, Option<i64>>(i)?.unwrap_or_default())
+    } else if *ty == Type::TIMESTAMP {
+        SqlValue::Timestamp(
+            row.try_get::<_, Option<chrono::NaiveDateTime>>(i)?
+                .map(|v| v.and_utc()),
+        )
+    } else if *ty == Type::TIMESTAMPTZ {
+        SqlValue::Timestamp(row.try_get::<_, Option<DateTime<Utc>>>(i)?)
+    } else {
+        SqlValue::Str(row.try_get::<_, Option<String>>(i)?.unwrap_or_default())
+    })
+}
+
+/// Connects and drives the connection in a task.
+async fn connect_pg<T>(
+    config: tokio_postgres::Config,
+    tls: T,
+) -> Result<tokio_postgres::Client, tokio_postgres::Error>
+where
+    T: MakeTlsConnect<Socket>,
+    T::Stream: Send + 'static,
+{
+    let (client, connection) = config.connect(tls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::warn!("questdb pg connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+fn literal(value: &SqlValue) -> String {
+    match value {
+        SqlValue::Str(v) => format!("'{}'", v.replace('\'', "''")),
+        SqlValue::Long(v) => v.to_string(),
+        SqlValue::F64(v) => v.to_string(),
+        SqlValue::Timestamp(Some(v)) => {
+            format!("'{}'", v.format("%Y-%m-%dT%H:%M:%S%.6fZ"))
+        }
+        SqlValue::Timestamp(None) => "NULL".to_owned(),
+    }
+}
+
+/// Replaces the `$n` placeholders with the escaped parameters in a single pass,
+/// so that the text of a parameter is never taken for a placeholder. The quoted
+/// literals and identifiers are copied as they are.
+fn inline_params(statement: &str, params: &[SqlValue]) -> String {
+    let mut inlined = String::with_capacity(statement.len());
+    let mut rest = statement;
+    while let Some(pos) = rest.find(['$', '\'', '"']) {
+        inlined.push_str(&rest[..pos]);
+        let quote = rest[pos..].chars().next().unwrap();
+        rest = &rest[pos + 1..];
+        if quote != '$' {
+            // a doubled quote inside the span ends and reopens it
+            let end = rest.find(quote).map_or(rest.len(), |end| end + 1);
+            inlined.push(quote);
+            inlined.push_str(&rest[..end]);
+            rest = &rest[end..];
+            continue;
+        }
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        match rest[..digits].parse::<usize>() {
+            Ok(n) if n >= 1 && n <= params.len() => inlined.push_str(&literal(&params[n - 1])),
+            _ => {
+                inlined.push('$');
+                inlined.push_str(&rest[..digits]);
+            }
+        }
+        rest = &rest[digits..];
+    }
+    inlined.push_str(rest);
+    inlined
+}
+
+async fn rest_exec(
+    client: &reqwest::Client,
+    base_url: &str,
+    query: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let resp = client
+        .get(format!("{}/exec", base_url))
+        .query(&[("query", query)])
+        .send()
+        .await
+        .map_err(|e| anyhow!("failed to run '{}': {}", query, e))?;
+
+    if !resp.status().is_success() {
+        let body = resp.text().await.unwrap_or_default();
+        return Err(anyhow!("failed to run '{}': {}", query, body));
+    }
+    resp.json()
+        .await
+        .map_err(|e| anyhow!("invalid response to '{}': {}", query, e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_inline_params() {
+        let params: Vec<SqlValue> = (1..=10).map(SqlValue::Long).collect();
+        assert_eq!(inline_params("$1 $10 $11", &params), "1 10 $11");
+        assert_eq!(
+            inline_params(
+                "$1, $2",
+                &[SqlValue::Str("$2".to_owned()), SqlValue::Long(2)]
+            ),
+            "'$2', 2"
+        );
+        assert_eq!(
+            inline_params(
+                "SELECT '$1', \"$1\", 'it''s $1' FROM t WHERE a = $1",
+                &[SqlValue::Long(1)]
+            ),
+            "SELECT '$1', \"$1\", 'it''s $1' FROM t WHERE a = 1"
+        );
+        assert_eq!(
+            inline_params(
+                "INSERT INTO t VALUES($1, $2)",
+                &[
+                    SqlValue::Str("it's".to_owned()),
+                    SqlValue::Timestamp(DateTime::from_timestamp(0, 0)),
+                ]
+            ),
+            "INSERT INTO t VALUES('it''s', '1970-01-01T00:00:00.000000Z')"
+        );
+        assert_eq!(quote_ident("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn test_pg_host_port() {
+        let mut settings = QuestDB {
+            addr: "questdb:9000".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            pg_host_port(&settings).unwrap(),
+            ("questdb".to_owned(), PG_PORT)
+        );
+        settings.pg_addr = "pg:5432".to_owned();
+        assert_eq!(pg_host_port(&settings).unwrap(), ("pg".to_owned(), 5432));
+    }
+}
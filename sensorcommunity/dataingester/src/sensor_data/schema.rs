@@ -0,0 +1,270 @@
+use crate::config::{Layout, Schema};
+
+use super::{CHIP_ID, CITY, INFO, LAT, LON, Record, SENSOR_ID, SENSOR_TYPE};
+
+/// A metadata value written as a field, the coordinates stay numeric.
+pub enum FieldValue {
+    F64(f64),
+    Str(String),
+}
+
+/// How a metadata is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    Tag,
+    Field,
+    Skipped,
+}
+
+/// A point as a writer sends it, with the names already renamed.
+pub struct Row {
+    pub tags: Vec<(String, String)>,
+    /// metadata written as fields
+    pub fields: Vec<(String, FieldValue)>,
+    /// the measured values, a single one in the narrow layout
+    pub values: Vec<(String, f64)>,
+    pub timestamp: u128,
+}
+
+/// Resolves the names written by a backend and splits each record into rows.
+pub struct RowMapper {
+    schema: Schema,
+}
+
+impl RowMapper {
+    pub fn new(schema: Schema) -> Self {
+        RowMapper { schema }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.schema.layout
+    }
+
+    /// The written name of a metadata or value.
+    pub fn name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.schema
+            .rename
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or(name)
+    }
+
+    pub fn placement(&self, name: &str) -> Placement {
+        if self.schema.fields.iter().any(|f| f == name) {
+            return Placement::Field;
+        }
+        match &self.schema.tags {
+            Some(tags) if !tags.iter().any(|t| t == name) => Placement::Skipped,
+            _ => Placement::Tag,
+        }
+    }
+
+    fn add(&self, row: &mut Row, name: &str, value: &str, numeric: Option<f64>) {
+        self.add_as(row, name, self.name(name).to_owned(), value, numeric);
+    }
+
+    /// Adds a metadata placed as `name` under another written name.
+    fn add_as(
+        &self,
+        row: &mut Row,
+        name: &str,
+        written: String,
+        value: &str,
+        numeric: Option<f64>,
+    ) {
+        match self.placement(name) {
+            Placement::Field => {
+                let value = match numeric {
+                    Some(v) => FieldValue::F64(v),
+                    None => FieldValue::Str(value.to_owned()),
+                };
+                row.fields.push((written, value));
+            }
+            Placement::Tag => row.tags.push((written, value.to_owned())),
+            Placement::Skipped => {}
+        }
+    }
+
+    fn chip_row(&self, rec: &Record) -> Row {
+        let mut row = Row {
+            tags: vec![],
+            fields: vec![],
+            values: vec![],
+            timestamp: rec.timestamp,
+        };
+        self.add(&mut row, CHIP_ID, &rec.chip_id, None);
+        self.add(&mut row, CITY, &rec.city, None);
+        self.add(&mut row, LAT, &rec.lat.to_string(), Some(rec.lat));
+        self.add(&mut row, LON, &rec.lon.to_string(), Some(rec.lon));
+        self.add(&mut row, INFO, &rec.info, None);
+        row
+    }
+
+    /// Narrow: a row per value, tagged with its sensor. Wide: a row per record with
+    /// all the values and the id of each sensor as `sensor_id_<sensor type>`, placed
+    /// like `sensor_id`. The values of different sensors with the same name are
+    /// written as `<name>_<sensor type>`, e.g. `temperature_DHT22` and
+    /// `temperature_BME280`.
+    pub fn rows(&self, rec: &Record) -> Vec<Row> {
+        let extra_tags = |row: &mut Row| {
+            for (k, v) in &rec.tags {
+                self.add(row, k, v, None);
+            }
+        };
+        match self.schema.layout {
+            Layout::Narrow => rec
+                .values
+                .iter()
+                .map(|d| {
+                    let mut row = self.chip_row(rec);
+                    self.add(&mut row, SENSOR_ID, &d.sensor_id, None);
+                    self.add(&mut row, SENSOR_TYPE, &d.sensor_type, None);
+                    extra_tags(&mut row);
+                    row.values.push((self.name(&d.field).to_owned(), d.value));
+                    row
+                })
+                .collect(),
+            Layout::Wide => {
+                if rec.values.is_empty() {
+                    return vec![];
+                }
+                let mut row = self.chip_row(rec);
+                extra_tags(&mut row);
+                let mut sensors: Vec<(&str, &str)> = vec![];
+                for d in &rec.values {
+                    if !sensors.contains(&(&d.sensor_type, &d.sensor_id)) {
+                        sensors.push((&d.sensor_type, &d.sensor_id));
+                    }
+                }
+                for (sensor_type, sensor_id) in &sensors {
+                    let written = format!("{}_{}", self.name(SENSOR_ID), sensor_type);
+                    self.add_as(&mut row, SENSOR_ID, written, sensor_id, None);
+                }
+                for d in &rec.values {
+                    let name = self.name(&d.field);
+                    let shared = rec
+                        .values
+                        .iter()
+                        .any(|o| o.sensor_type != d.sensor_type && self.name(&o.field) == name);
+                    let name = match shared {
+                        true => format!("{}_{}", name, d.sensor_type),
+                        false => name.to_owned(),
+                    };
+                    if row.values.iter().any(|(n, _)| *n == name) {
+                        tracing::warn!(
+                            "sensor {} of chip {} sent more than one {} value, keeping the first",
+                            d.sensor_id,
+                            rec.chip_id,
+                            name
+                        );
+                        continue;
+                    }
+                    row.values.push((name, d.value));
+                }
+                vec![row]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sensor_data::RecordValue;
+    use std::collections::HashMap;
+
+    fn record() -> Record {
+        let value = |sensor_id: &str, field: &str, value| RecordValue {
+            sensor_id: sensor_id.to_owned(),
+            sensor_type: match sensor_id {
+                "1" => "SDS011".to_owned(),
+                _ => "PMS7003".to_owned(),
+            },
+            field: field.to_owned(),
+            value,
+        };
+        Record {
+            chip_id: "esp8266-1".to_owned(),
+            lat: 45.6,
+            lon: 11.7,
+            city: "Cittadella".to_owned(),
+            info: "".to_owned(),
+            tags: vec![("geohash5".to_owned(), "u207s".to_owned())],
+            values: vec![
+                value("1", "P1", 10.0),
+                value("1", "P2", 5.0),
+                value("2", "P1", 11.0),
+            ],
+            timestamp: 1,
+        }
+    }
+
+    #[test]
+    fn test_narrow_rows() {
+        let rows = RowMapper::new(Schema::default()).rows(&record());
+        assert_eq!(rows.len(), 3);
+        let tags: Vec<&str> = rows[0].tags.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(
+            tags,
+            vec![
+                "chip_id",
+                "city",
+                "lat",
+                "lon",
+                "info",
+                "sensor_id",
+                "sensor_type",
+                "geohash5"
+            ]
+        );
+        assert!(rows[0].fields.is_empty());
+        assert_eq!(rows[1].values, vec![("P2".to_owned(), 5.0)]);
+    }
+
+    #[test]
+    fn test_wide_rows_with_mapping() {
+        let schema = Schema {
+            layout: Layout::Wide,
+            tags: Some(vec![CHIP_ID.to_owned(), "geohash5".to_owned()]),
+            fields: vec![LAT.to_owned(), LON.to_owned()],
+            rename: HashMap::from([
+                (CHIP_ID.to_owned(), "chip".to_owned()),
+                ("P1".to_owned(), "pm10".to_owned()),
+            ]),
+        };
+        let rows = RowMapper::new(schema).rows(&record());
+        assert_eq!(rows.len(), 1);
+        let tags: Vec<&str> = rows[0].tags.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(tags, vec!["chip", "geohash5"]);
+        assert!(
+            matches!(rows[0].fields[0], (ref n, FieldValue::F64(v)) if n == "lat" && v == 45.6)
+        );
+        assert_eq!(
+            rows[0].values,
+            vec![
+                ("pm10_SDS011".to_owned(), 10.0),
+                ("P2".to_owned(), 5.0),
+                ("pm10_PMS7003".to_owned(), 11.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_wide_rows_sensor_ids() {
+        let schema = Schema {
+            layout: Layout::Wide,
+            ..Default::default()
+        };
+        let rows = RowMapper::new(schema).rows(&record());
+        let tags: Vec<(&str, &str)> = rows[0]
+            .tags
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .filter(|(k, _)| k.starts_with(SENSOR_ID))
+            .collect();
+        assert_eq!(
+            tags,
+            vec![("sensor_id_SDS011", "1"), ("sensor_id_PMS7003", "2")]
+        );
+    }
+}
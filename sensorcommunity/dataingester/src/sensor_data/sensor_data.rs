@@ -1,8 +1,16 @@
-use std::{collections::HashMap, fs::OpenOptions, io::Seek, sync::Arc};
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Seek,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
 
-use crate::cache::Cache;
-use anyhow::{Result, anyhow};
-use chrono::Utc;
+use crate::registry::Registry;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tracing::Instrument;
 use serde::{Deserialize, Serialize};
 
 use super::{
@@ -13,91 +21,129 @@ use super::{
 // "Time", durP1;ratioP1;P1;durP2;ratioP2;P2;SDS_P1;SDS_P2;Temp;Humidity;BMP_temperature;BMP_pressure;BME280_temperature;BME280_humidity;BME280_pressure;Samples;Min_cycle;Max_cycle;Signal\n"
 // chip_id;lat;lon;timestamp;P1;durP1;ratioP1;P2;durP2;ratioP2;temperature;humidity;pressure;signal
 
-/*
-#[derive(InfluxDbWriteable)]
-pub struct InfluxDataRecord<'a> {
-    chip_id: &'a str,
-    lat: f64,
-    lon: f64,
-    time: DateTime<Utc>,
-
-    city: String,
-    description: String,
-}
-*/
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Payload {
     software_version: String,
     sensordatavalues: Vec<SensorValue>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Payload {
+    /// the distinct value types sent, in the order they first appear
+    pub fn value_types(&self) -> Vec<&str> {
+        let mut types: Vec<&str> = Vec::new();
+        for v in &self.sensordatavalues {
+            if !types.contains(&v.value_type.as_str()) {
+                types.push(&v.value_type);
+            }
+        }
+        types
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SensorValue {
     value_type: String,
     value: String,
 }
 
-pub fn get_sensor_id(
-    sensor_cache: &Cache<crate::SensorInfo>,
+/// What happened to a payload passed to [write].
+#[derive(Debug)]
+pub enum WriteOutcome {
+    Written,
+    /// the chip is disabled, the payload is dropped
+    Disabled,
+    /// the chip is in the chips file but none of its periods covers the
+    /// timestamp, the payload is dropped
+    NoPeriod,
+    /// the chip is not in the chips file, the payload is given back to be held
+    UnknownChip(Payload),
+}
+
+/// The writers and the lookups a payload is written with.
+pub struct WriteContext<'a> {
+    pub writers: &'a [Arc<dyn crate::sensor_data::DataWriter>],
+    pub measure_name_to_field: &'a HashMap<String, String>,
+    pub measure_name_to_sensor_type: &'a HashMap<String, String>,
+    pub registry: &'a Registry,
+}
+
+/// Returns the path of today's csv archive file for a chip, creating the day folder if needed.
+pub fn archive_file_path(sensor_data_dir: &Path, chip_id: &str) -> std::io::Result<PathBuf> {
+    archive_file_path_at(sensor_data_dir, chip_id, Utc::now())
+}
+
+/// Returns the path of the csv archive file for a chip on the day of `at`.
+pub fn archive_file_path_at(
+    sensor_data_dir: &Path,
     chip_id: &str,
-    sensor_type: &str,
-) -> Result<String, anyhow::Error> {
-    let cache_id = format!("{}:{}", chip_id, sensor_type);
-    match sensor_cache.read() {
-        Ok(cache) => {
-            if let Some(info) = cache.get(&cache_id) {
-                Ok(info.sensor_id.to_owned())
-            } else {
-                Err(anyhow!("missing sensory id for key: {}", cache_id))
-            }
-        }
-        Err(e) => Err(anyhow!("{}", e)),
+    at: DateTime<Utc>,
+) -> std::io::Result<PathBuf> {
+    let formatted_day = format!("{}", at.format("%Y-%m-%d"));
+
+    let root_folder = sensor_data_dir.join(&formatted_day);
+    let file_name = format!("{}_chip_{}.csv", &formatted_day, chip_id);
+
+    if let Err(e) = std::fs::create_dir_all(&root_folder) {
+        tracing::error!(
+            "Error creating sensor data folder at: {}, {}",
+            root_folder.as_os_str().to_string_lossy(),
+            e
+        );
+        return Err(e);
     }
+
+    Ok(root_folder.join(file_name))
 }
 
 pub async fn write(
-    writers: &[Arc<dyn crate::sensor_data::DataWriter>],
+    ctx: &WriteContext<'_>,
     // influxdb_settings: &crate::config::InfluxDB,
     // influxdb3_settings: &crate::config::InfluxDB3,
     file_path: &std::path::PathBuf,
-    measure_name_to_field: &HashMap<String, String>,
-    measure_name_to_sensor_type: &HashMap<String, String>,
-    chip_cache: Cache<crate::ChipInfo>,
-    sensor_cache: Cache<crate::SensorInfo>,
     chip_id: &str,
+    timestamp: i64,
     payload: Payload,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<WriteOutcome, Box<dyn std::error::Error>> {
+    let WriteContext {
+        writers,
+        measure_name_to_field,
+        measure_name_to_sensor_type,
+        registry,
+    } = *ctx;
     // let mut wtr = csv::Writer::from_path(file_path)?;
 
-    let timestamp = Utc::now().timestamp();
-
     // used to write the csv file, we can remove it later if we want to only write to the databases
     let mut d = crate::sensor_data::DataRecord::default();
 
-    match chip_cache.read() {
-        Ok(cache) => {
-            if let Some(info) = cache.get(chip_id) {
-                d.city = info.city.to_owned();
-                d.info = info.info.to_owned();
-                d.lat = info.lat;
-                d.lon = info.lon;
-            } else {
-                tracing::error!(
-                    "skipping missing chip id: {}. If you want to record its data add it to the chip file.",
-                    chip_id,
-                );
-                return Ok(());
+    let tags = match registry.chip_at(chip_id, timestamp) {
+        Some(info) => {
+            if !info.enabled {
+                tracing::debug!("skipping disabled chip id: {}", chip_id);
+                return Ok(WriteOutcome::Disabled);
             }
+            let tags = registry.geo_tags(&info);
+            d.city = info.city;
+            d.info = info.info;
+            d.lat = info.lat;
+            d.lon = info.lon;
+            tags
         }
-        _ => {
-            tracing::error!(
-                "skipping chip id: {}. Error trying to acquire cache lock.",
+        None if registry.contains_chip(chip_id) => {
+            tracing::warn!(
+                "no period of chip id {} covers {}, skipping its data",
                 chip_id,
+                DateTime::from_timestamp(timestamp, 0).unwrap_or_default(),
             );
-            return Ok(());
+            return Ok(WriteOutcome::NoPeriod);
         }
-    }
+        None => {
+            tracing::warn!(
+                "missing chip id: {}. If you want to record its data add it to the chip file.",
+                chip_id,
+            );
+            return Ok(WriteOutcome::UnknownChip(payload));
+        }
+    };
 
     d.timestamp = timestamp;
     d.chip_id = chip_id;
@@ -110,6 +156,7 @@ pub async fn write(
         lon: d.lon,
         city: d.city.clone(),
         info: d.info.clone(),
+        tags,
         values: vec![],
     };
 
@@ -206,6 +253,10 @@ pub async fn write(
         let sensor_type = match measure_name_to_sensor_type.get(&data_row.value_type) {
             Some(s) => s,
             None => {
+                crate::metrics::observe_unknown_value_type(
+                    &data_row.value_type,
+                    measure_name_to_field,
+                );
                 tracing::debug!(
                     "Missing sensor type for chip id {} with value type {}, skipping value",
                     chip_id,
@@ -215,7 +266,7 @@ pub async fn write(
             }
         };
 
-        let sensor_id = match get_sensor_id(&sensor_cache, chip_id, &sensor_type) {
+        let sensor_id = match registry.sensor_id(chip_id, &sensor_type) {
             Ok(id) => id,
             Err(e) => {
                 tracing::error!(
@@ -230,6 +281,8 @@ pub async fn write(
 
         let v = data_row.value.parse::<f64>().unwrap_or_default() as f64;
 
+        crate::metrics::observe_value(chip_id, &sensor_id, field_name, v);
+
         rec.values.push(crate::sensor_data::RecordValue {
             sensor_id: sensor_id.clone(),
             sensor_type: sensor_type.to_owned(),
@@ -246,92 +299,19 @@ pub async fn write(
         );
     }
 
-    let recs = &vec![rec];
+    let recs = &[rec];
     for w in writers {
-        if let Err(e) = w.write(&recs).await {
+        let start = Instant::now();
+        let res = w
+            .write(recs)
+            .instrument(tracing::info_span!("writer.write", backend = w.name()))
+            .await;
+        crate::metrics::observe_write(w.name(), start.elapsed(), res.is_ok());
+        if let Err(e) = res {
             tracing::error!("Error trying to write record: {}", e);
         }
     }
-
-    /*
-        let use_influxdb_3 = influxdb_settings.url.len() == 0;
-
-        if use_influxdb_3 {
-            let mut write_queries = Vec::<influxdb::WriteQuery>::new();
-
-            let now = chrono::Utc::now().timestamp() as u128;
-
-            for rc in rec.values {
-                let mut wq = influxdb::Timestamp::Seconds(now).into_query(&influxdb3_settings.table);
-
-                wq = wq
-                    .add_tag(CHIP_ID, rec.chip_id)
-                    .add_tag(CITY, rec.city)
-                    .add_tag(LAT, rec.lat)
-                    .add_tag(LON, rec.lon)
-                    .add_tag(INFO, rec.info)
-                    .add_tag(SENSOR_ID, rc.sensor_id)
-                    .add_tag(SENSOR_TYPE, rc.sensor_type.to_owned());
-
-                wq = wq.add_field(rc.field.as_str(), rc.value);
-                write_queries.push(wq);
-            }
-
-            let mut client =
-                influxdb::Client::new(&influxdb3_settings.url, &influxdb3_settings.database);
-            if influxdb3_settings.token.len() > 0 {
-                client = client.with_token(&influxdb3_settings.token);
-            }
-
-            if let Err(e) = client.query(&write_queries).await {
-                tracing::error!(
-                    "Error trying to write to InfluxDB at {}: {}",
-                    &influxdb3_settings.url,
-                    e
-                );
-            }
-        } else {
-            let mut points = vec![];
-            let req_builder = reqwest::Client::builder()
-                .redirect(reqwest::redirect::Policy::none())
-                .danger_accept_invalid_certs(true);
-            let builder = influxdb2::ClientBuilder::with_builder(
-                req_builder,
-                &influxdb_settings.url,
-                &influxdb_settings.org,
-                &influxdb_settings.token,
-            );
-            let client = builder.build()?;
-
-            for rc in rec.values {
-                let mut dp = influxdb2::models::DataPoint::builder(&influxdb_settings.measurement);
-
-                dp = dp
-                    .tag(CHIP_ID, rec.chip_id)
-                    .tag(CITY, rec.city)
-                    .tag(LAT, rec.lat.to_string())
-                    .tag(LON, rec.lon.to_string())
-                    .tag(INFO, rec.info)
-                    .tag(SENSOR_ID, rc.sensor_id)
-                    .tag(SENSOR_TYPE, rc.sensor_type);
-
-                dp = dp.field(rc.field.as_str(), rc.value);
-                points.push(dp.build()?);
-            }
-
-            if let Err(e) = client
-                .write(&influxdb_settings.bucket, futures::stream::iter(points))
-                .await
-            {
-                tracing::error!(
-                    "Error trying to write to InfluxDB at {}: {}",
-                    &influxdb_settings.url,
-                    e
-                );
-            }
-        }
-    */
-    Ok(())
+    Ok(WriteOutcome::Written)
 }
 
 pub fn write_csv(
@@ -0,0 +1,300 @@
+use std::{
+    fs,
+    future::Future,
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll},
+    time::SystemTime,
+};
+
+use anyhow::{Context, anyhow};
+use rustls_021 as rustls;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_postgres::Socket;
+use tokio_postgres::tls::{ChannelBinding, MakeTlsConnect, TlsConnect};
+
+use crate::config::BackendTls;
+
+/// A client builder verifying the backend certificate as configured. With pins
+/// only the pinned certificates are accepted, whatever their issuer and name.
+pub fn http_client(tls: &BackendTls) -> anyhow::Result<reqwest::ClientBuilder> {
+    let mut builder = reqwest::Client::builder();
+    if !tls.pins.is_empty() {
+        return Ok(builder.use_preconfigured_tls(client_config(tls)?));
+    }
+    if !tls.verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    } else if let Some(ca_path) = &tls.ca_path {
+        builder = builder.tls_built_in_root_certs(false);
+        for cert in reqwest::Certificate::from_pem_bundle(&read(ca_path)?)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(cert_path) = &tls.client_cert_path {
+        let mut pem = read(cert_path)?;
+        if let Some(key_path) = &tls.client_key_path {
+            pem.extend(read(key_path)?);
+        }
+        builder = builder.identity(reqwest::Identity::from_pem(&pem)?);
+    }
+    Ok(builder)
+}
+
+/// The settings appended to the QuestDB ILP configuration string, the ILP client
+/// supports neither client certificates nor pins, with pins the certificate is
+/// verified with the CA instead.
+pub fn questdb_conf(tls: &BackendTls) -> String {
+    let mut conf = String::new();
+    if !tls.verify && tls.pins.is_empty() {
+        conf.push_str("tls_verify=unsafe_off;");
+    } else if let Some(ca_path) = &tls.ca_path {
+        conf.push_str(&format!("tls_roots={};", ca_path.display()));
+    }
+    conf
+}
+
+/// Connects the QuestDB PostgreSQL wire client with TLS, verifying the server
+/// like [http_client].
+#[derive(Clone)]
+pub struct PgTls(tokio_rustls::TlsConnector);
+
+impl PgTls {
+    pub fn new(tls: &BackendTls) -> anyhow::Result<Self> {
+        Ok(PgTls(Arc::new(client_config(tls)?).into()))
+    }
+}
+
+impl MakeTlsConnect<Socket> for PgTls {
+    type Stream = PgTlsStream;
+    type TlsConnect = PgTlsConnect;
+    type Error = io::Error;
+
+    fn make_tls_connect(&mut self, domain: &str) -> io::Result<PgTlsConnect> {
+        let domain = rustls::ServerName::try_from(domain)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(PgTlsConnect(self.0.clone(), domain))
+    }
+}
+
+pub struct PgTlsConnect(tokio_rustls::TlsConnector, rustls::ServerName);
+
+impl TlsConnect<Socket> for PgTlsConnect {
+    type Stream = PgTlsStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<PgTlsStream>> + Send>>;
+
+    fn connect(self, stream: Socket) -> Self::Future {
+        let PgTlsConnect(connector, domain) = self;
+        Box::pin(async move { Ok(PgTlsStream(connector.connect(domain, stream).await?)) })
+    }
+}
+
+pub struct PgTlsStream(tokio_rustls::client::TlsStream<Socket>);
+
+impl tokio_postgres::tls::TlsStream for PgTlsStream {
+    fn channel_binding(&self) -> ChannelBinding {
+        ChannelBinding::none()
+    }
+}
+
+impl AsyncRead for PgTlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PgTlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Warns about the settings that weaken or are ignored on a backend connection.
+pub fn warn_settings(backend: &str, tls: &BackendTls, ilp: bool) {
+    if !tls.verify && tls.pins.is_empty() {
+        tracing::warn!("the TLS certificate of {} is not verified", backend);
+    }
+    if ilp && (!tls.pins.is_empty() || tls.client_cert_path.is_some()) {
+        tracing::warn!(
+            "the {} ILP writes use neither the TLS pins nor the client certificate",
+            backend
+        );
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+/// Parses a SHA-256 fingerprint, in hex with or without colons.
+fn parse_pin(pin: &str) -> anyhow::Result<[u8; 32]> {
+    let hex: String = pin.chars().filter(|c| *c != ':').collect();
+    let mut fingerprint = [0u8; 32];
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(anyhow!("invalid SHA-256 fingerprint: {}", pin));
+    }
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| anyhow!("invalid SHA-256 fingerprint: {}", pin))?;
+    }
+    Ok(fingerprint)
+}
+
+struct PinVerifier {
+    pins: Vec<[u8; 32]>,
+}
+
+impl rustls::client::ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let fingerprint: [u8; 32] = Sha256::digest(&end_entity.0).into();
+        match self.pins.contains(&fingerprint) {
+            true => Ok(rustls::client::ServerCertVerified::assertion()),
+            false => Err(rustls::Error::General(
+                "the server certificate is not pinned".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Accepts any server certificate, when the verification is off.
+struct NoVerifier;
+
+impl rustls::client::ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// The rustls configuration of the settings, the pins first, then the CA file or
+/// the bundled roots.
+fn client_config(tls: &BackendTls) -> anyhow::Result<rustls::ClientConfig> {
+    let verifier: Arc<dyn rustls::client::ServerCertVerifier> = if !tls.pins.is_empty() {
+        let pins = tls
+            .pins
+            .iter()
+            .map(|p| parse_pin(p))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Arc::new(PinVerifier { pins })
+    } else if !tls.verify {
+        Arc::new(NoVerifier)
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        match &tls.ca_path {
+            Some(ca_path) => {
+                for cert in rustls_pemfile::certs(&mut read(ca_path)?.as_slice())? {
+                    roots.add(&rustls::Certificate(cert))?;
+                }
+            }
+            None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            })),
+        }
+        Arc::new(rustls::client::WebPkiVerifier::new(roots, None))
+    };
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+    let config = match &tls.client_cert_path {
+        Some(cert_path) => {
+            let certs = rustls_pemfile::certs(&mut read(cert_path)?.as_slice())?
+                .into_iter()
+                .map(rustls::Certificate)
+                .collect();
+            let key_path = tls.client_key_path.as_deref().unwrap_or(cert_path);
+            let key = rustls_pemfile::read_all(&mut read(key_path)?.as_slice())?
+                .into_iter()
+                .find_map(|item| match item {
+                    rustls_pemfile::Item::PKCS8Key(key)
+                    | rustls_pemfile::Item::RSAKey(key)
+                    | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                    _ => None,
+                })
+                .ok_or(anyhow!("no private key in {}", key_path.display()))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    Ok(config)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_pin() {
+        let hex = "ab".repeat(32);
+        assert_eq!(parse_pin(&hex).unwrap(), [0xab; 32]);
+        let colons = vec!["AB"; 32].join(":");
+        assert_eq!(parse_pin(&colons).unwrap(), [0xab; 32]);
+        assert!(parse_pin("abcd").is_err());
+        assert!(parse_pin(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_http_client_identity() {
+        // the client certificate is read whether the server is verified or not
+        for verify in [false, true] {
+            let tls = BackendTls {
+                verify,
+                client_cert_path: Some("/nonexistent/client.pem".into()),
+                ..Default::default()
+            };
+            assert!(http_client(&tls).is_err());
+        }
+    }
+
+    #[test]
+    fn test_questdb_conf() {
+        let mut tls = BackendTls::default();
+        assert_eq!(questdb_conf(&tls), "tls_verify=unsafe_off;");
+        tls.verify = true;
+        tls.ca_path = Some("/etc/ssl/questdb.pem".into());
+        assert_eq!(questdb_conf(&tls), "tls_roots=/etc/ssl/questdb.pem;");
+        // the pins are never left unverified
+        let pinned = BackendTls {
+            pins: vec!["ab".repeat(32)],
+            ..Default::default()
+        };
+        assert_eq!(questdb_conf(&pinned), "");
+    }
+}
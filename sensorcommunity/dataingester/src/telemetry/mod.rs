@@ -0,0 +1,96 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
+use opentelemetry_otlp::{LogExporter, MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource, logs::SdkLoggerProvider, metrics::SdkMeterProvider, trace::SdkTracerProvider,
+};
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+// the exporter http client logs through tracing as well, keep it out of the exported data
+const EXCLUDED_TARGETS: &str = "hyper=off,hyper_util=off,h2=off,reqwest=off,opentelemetry=off,opentelemetry_sdk=off,opentelemetry_otlp=off";
+
+/// Keeps the OTLP providers alive and flushes them on shutdown.
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    logger_provider: SdkLoggerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl std::fmt::Debug for Telemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Telemetry")
+    }
+}
+
+impl Telemetry {
+    pub fn shutdown(&self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            eprintln!("error shutting down the OTLP tracer provider: {}", e);
+        }
+        if let Err(e) = self.logger_provider.shutdown() {
+            eprintln!("error shutting down the OTLP logger provider: {}", e);
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            eprintln!("error shutting down the OTLP meter provider: {}", e);
+        }
+    }
+}
+
+/// Builds the OTLP/HTTP exporters for traces, logs and metrics sent to `endpoint`
+/// (e.g. `http://localhost:4318`) and the tracing layer feeding them.
+pub fn otlp_layer(
+    endpoint: &str,
+    service_name: &str,
+    level: &str,
+) -> anyhow::Result<(Box<dyn Layer<Registry> + Send + Sync>, Telemetry)> {
+    let endpoint = endpoint.trim_end_matches('/');
+    let resource = Resource::builder()
+        .with_service_name(service_name.to_owned())
+        .build();
+
+    let span_exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint))
+        .build()?;
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_batch_exporter(span_exporter)
+        .with_resource(resource.clone())
+        .build();
+
+    let log_exporter = LogExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/logs", endpoint))
+        .build()?;
+    let logger_provider = SdkLoggerProvider::builder()
+        .with_batch_exporter(log_exporter)
+        .with_resource(resource.clone())
+        .build();
+
+    let metric_exporter = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/metrics", endpoint))
+        .build()?;
+    let meter_provider = SdkMeterProvider::builder()
+        .with_periodic_exporter(metric_exporter)
+        .with_resource(resource)
+        .build();
+    opentelemetry::global::set_meter_provider(meter_provider.clone());
+
+    let filter = || EnvFilter::try_new(format!("{},{}", level.to_lowercase(), EXCLUDED_TARGETS));
+
+    let tracer = tracer_provider.tracer("dataingester");
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter()?)
+        .and_then(OpenTelemetryTracingBridge::new(&logger_provider).with_filter(filter()?))
+        .boxed();
+
+    Ok((
+        layer,
+        Telemetry {
+            tracer_provider,
+            logger_provider,
+            meter_provider,
+        },
+    ))
+}